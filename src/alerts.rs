@@ -0,0 +1,579 @@
+use crate::config::{Alerts, Webhook, WebhookFormat};
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use crossbeam_channel::Sender;
+use log::{error, info, warn};
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use serde_json::Value;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::{Duration, Instant};
+use tiny_http::{Response, Server};
+
+/// time to wait for a webhook to respond
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// number of times delivering a notification to a webhook is attempted
+const MAX_DELIVERY_ATTEMPTS: u32 = 3;
+/// time between attempts to deliver a notification, doubled after every attempt
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const GENERIC_TEMPLATE: &str = r#"{"rule": "{{rule}}", "key": "{{key}}", "status": "{{status}}", "title": "{{title}}", "message": "{{message}}", "time": "{{time}}"}"#;
+const SLACK_TEMPLATE: &str = r#"{"text": "*{{title}}*\n{{message}}"}"#;
+const DISCORD_TEMPLATE: &str = r#"{"content": "**{{title}}**\n{{message}}"}"#;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlertRule {
+    SendFailures,
+    QueueLength,
+    PayerBalanceLow,
+    RpcUnreachable,
+    /// sent by `crank alert test`
+    Test,
+}
+
+/// an alert which started firing or was resolved
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub rule: AlertRule,
+    /// identifies the alert, the rule along with the market or payer it is about
+    pub key: String,
+    pub resolved: bool,
+    pub message: String,
+    /// rfc3339 timestamp of when the notification was raised
+    pub time: String,
+}
+
+/// a firing alert, which is only notified again after repeat_interval
+struct Firing {
+    last_sent: Instant,
+}
+
+/// evaluates the alert rules on the outcomes of the crank loop, notifying the
+/// webhooks once when an alert starts firing, again every repeat_interval while
+/// it keeps firing, and once more when it is resolved
+pub struct AlertManager {
+    config: Alerts,
+    notifications: Sender<(Vec<Webhook>, Notification)>,
+    send_failures: u64,
+    /// number of checks in a row each market's event queue was above max_queue_len
+    queue_len_streaks: HashMap<Pubkey, u64>,
+    rpc_failures: u64,
+    firing: HashMap<String, Firing>,
+}
+
+impl AlertRule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertRule::SendFailures => "send_failures",
+            AlertRule::QueueLength => "queue_length",
+            AlertRule::PayerBalanceLow => "payer_balance_low",
+            AlertRule::RpcUnreachable => "rpc_unreachable",
+            AlertRule::Test => "test",
+        }
+    }
+}
+
+impl Notification {
+    pub fn new(rule: AlertRule, key: String, resolved: bool, message: String) -> Self {
+        Self {
+            rule,
+            key,
+            resolved,
+            message,
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        }
+    }
+    /// a notification used to check that webhooks and their templates work
+    pub fn test() -> Self {
+        Self::new(
+            AlertRule::Test,
+            AlertRule::Test.as_str().to_string(),
+            false,
+            "test alert sent by crank alert test".to_string(),
+        )
+    }
+    pub fn status(&self) -> &'static str {
+        if self.resolved {
+            "resolved"
+        } else {
+            "firing"
+        }
+    }
+    pub fn title(&self) -> String {
+        format!("serum crank alert {}: {}", self.status(), self.key)
+    }
+    /// returns the json payload of the notification for the webhook, the fields
+    /// are json escaped so they can be placed within the strings of a template
+    pub fn render(&self, webhook: &Webhook) -> Result<String> {
+        let template = match (webhook.template.as_ref(), webhook.format) {
+            (Some(template), _) => template.as_str(),
+            (None, WebhookFormat::Generic) => GENERIC_TEMPLATE,
+            (None, WebhookFormat::Slack) => SLACK_TEMPLATE,
+            (None, WebhookFormat::Discord) => DISCORD_TEMPLATE,
+        };
+        let mut payload = template.to_string();
+        for (name, value) in [
+            ("title", self.title()),
+            ("message", self.message.clone()),
+            ("rule", self.rule.as_str().to_string()),
+            ("key", self.key.clone()),
+            ("status", self.status().to_string()),
+            ("time", self.time.clone()),
+        ]
+        .iter()
+        {
+            payload = payload.replace(&format!("{{{{{}}}}}", name), &json_escape(value));
+        }
+        serde_json::from_str::<Value>(&payload)
+            .map_err(|err| anyhow!("payload is not valid json: {}", err))?;
+        Ok(payload)
+    }
+}
+
+impl AlertManager {
+    /// starts the thread delivering the notifications to the webhooks, so slow
+    /// webhooks never hold up the crank loop
+    pub fn new(config: Alerts) -> Self {
+        let (notifications, receiver) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            let client = match webhook_client() {
+                Ok(client) => client,
+                Err(err) => {
+                    error!(
+                        "failed to create webhook client, alerts are disabled: {:#?}",
+                        err
+                    );
+                    return;
+                }
+            };
+            for (webhooks, notification) in receiver.iter() {
+                for webhook in webhooks.iter() {
+                    if let Err(err) = deliver(&client, webhook, &notification) {
+                        error!(
+                            "failed to deliver alert {} to webhook {}: {:#?}",
+                            notification.key,
+                            webhook.label(),
+                            err
+                        );
+                    }
+                }
+            }
+        });
+        Self::with_sender(config, notifications)
+    }
+    /// queues the notifications on the channel, for the thread delivering them
+    fn with_sender(config: Alerts, notifications: Sender<(Vec<Webhook>, Notification)>) -> Self {
+        Self {
+            config,
+            notifications,
+            send_failures: 0,
+            queue_len_streaks: HashMap::new(),
+            rpc_failures: 0,
+            firing: HashMap::new(),
+        }
+    }
+    pub fn set_config(&mut self, config: Alerts) {
+        self.config = config;
+    }
+    /// records the outcome of sending a crank transaction, with the error when it failed
+    pub fn record_send(&mut self, err: Option<String>) {
+        match err {
+            Some(err) => {
+                self.send_failures += 1;
+                let message = format!(
+                    "{} crank transactions in a row failed to send, the last with: {}",
+                    self.send_failures, err
+                );
+                let firing = self.config.max_send_failures > 0
+                    && self.send_failures >= self.config.max_send_failures;
+                self.update(AlertRule::SendFailures, String::new(), firing, message);
+            }
+            None => {
+                self.send_failures = 0;
+                let message = "crank transactions are sending again".to_string();
+                self.update(AlertRule::SendFailures, String::new(), false, message);
+            }
+        }
+    }
+    /// records the length of the market's event queue when it was checked
+    pub fn record_queue_len(&mut self, market: &Pubkey, event_q_len: usize) {
+        let max_queue_len = self.config.max_queue_len;
+        let streak = self.queue_len_streaks.entry(*market).or_insert(0);
+        if max_queue_len > 0 && event_q_len as u64 > max_queue_len {
+            *streak += 1;
+        } else {
+            *streak = 0;
+        }
+        let firing = *streak > 0 && *streak >= self.config.queue_len_iterations;
+        let message = if firing {
+            format!(
+                "event queue of market {} held more than {} events for {} checks in a row, {} events now",
+                market, max_queue_len, streak, event_q_len
+            )
+        } else {
+            format!(
+                "event queue of market {} is down to {} events",
+                market, event_q_len
+            )
+        };
+        self.update(AlertRule::QueueLength, market.to_string(), firing, message);
+    }
+    /// records the balance of the payer in lamports
+    pub fn record_payer_balance(&mut self, payer: &Pubkey, balance: u64, warning_balance: u64) {
+        let firing = self.config.payer_balance_low && balance < warning_balance;
+        let message = if firing {
+            format!(
+                "payer {} balance of {} lamports is below the warning balance of {} lamports",
+                payer, balance, warning_balance
+            )
+        } else {
+            format!("payer {} balance is back up to {} lamports", payer, balance)
+        };
+        self.update(
+            AlertRule::PayerBalanceLow,
+            payer.to_string(),
+            firing,
+            message,
+        );
+    }
+    /// records the outcome of fetching the payer balances from the rpc node,
+    /// with the error when it failed, done once per crank loop iteration
+    pub fn record_rpc(&mut self, err: Option<String>) {
+        match err {
+            Some(err) => {
+                self.rpc_failures += 1;
+                let message = format!(
+                    "rpc node unreachable for {} crank loop iterations in a row, the last request failed with: {}",
+                    self.rpc_failures, err
+                );
+                let firing = self.config.rpc_unreachable_iterations > 0
+                    && self.rpc_failures >= self.config.rpc_unreachable_iterations;
+                self.update(AlertRule::RpcUnreachable, String::new(), firing, message);
+            }
+            None => {
+                self.rpc_failures = 0;
+                let message = "rpc node is reachable again".to_string();
+                self.update(AlertRule::RpcUnreachable, String::new(), false, message);
+            }
+        }
+    }
+    /// notifies the webhooks when the alert starts firing, is still firing after
+    /// repeat_interval, or is resolved. the subject, such as a market, is added to
+    /// the key of the rule so the alerts of each subject are tracked separately
+    fn update(&mut self, rule: AlertRule, subject: String, firing: bool, message: String) {
+        let key = if subject.is_empty() {
+            rule.as_str().to_string()
+        } else {
+            format!("{} {}", rule.as_str(), subject)
+        };
+        let repeat_interval = Duration::from_secs(self.config.repeat_interval);
+        let notify = match (firing, self.firing.get_mut(&key)) {
+            (true, None) => {
+                self.firing.insert(
+                    key.clone(),
+                    Firing {
+                        last_sent: Instant::now(),
+                    },
+                );
+                true
+            }
+            (true, Some(alert)) => {
+                let repeat =
+                    repeat_interval.as_secs() > 0 && alert.last_sent.elapsed() >= repeat_interval;
+                if repeat {
+                    alert.last_sent = Instant::now();
+                }
+                repeat
+            }
+            (false, Some(_)) => {
+                self.firing.remove(&key);
+                true
+            }
+            (false, None) => false,
+        };
+        if !notify {
+            return;
+        }
+        let notification = Notification::new(rule, key, !firing, message);
+        if firing {
+            warn!(
+                "alert {} firing: {}",
+                notification.key, notification.message
+            );
+        } else {
+            info!(
+                "alert {} resolved: {}",
+                notification.key, notification.message
+            );
+        }
+        if self.config.webhooks.is_empty() {
+            return;
+        }
+        if self
+            .notifications
+            .send((self.config.webhooks.clone(), notification))
+            .is_err()
+        {
+            error!("failed to queue alert notification, the webhook thread stopped");
+        }
+    }
+}
+
+pub fn webhook_client() -> Result<Client> {
+    Ok(Client::builder().timeout(WEBHOOK_TIMEOUT).build()?)
+}
+
+/// posts the notification to the webhook, retrying failed attempts
+pub fn deliver(client: &Client, webhook: &Webhook, notification: &Notification) -> Result<()> {
+    let payload = notification.render(webhook)?;
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let err = match client
+            .post(&webhook.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(payload.clone())
+            .send()
+        {
+            Ok(res) if res.status().is_success() => return Ok(()),
+            Ok(res) => anyhow!("webhook responded with status {}", res.status()),
+            Err(err) => anyhow!("failed to post to webhook: {}", err),
+        };
+        if attempt >= MAX_DELIVERY_ATTEMPTS {
+            return Err(err);
+        }
+        warn!(
+            "attempt {} of {} to deliver alert {} to webhook {} failed: {:#?}",
+            attempt,
+            MAX_DELIVERY_ATTEMPTS,
+            notification.key,
+            webhook.label(),
+            err
+        );
+        std::thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// runs a webhook receiver printing the body of every request it receives,
+/// used to check the alert payloads locally
+pub fn receive(listen_addr: &str) -> Result<()> {
+    let server = Server::http(listen_addr).map_err(|err| {
+        anyhow!(
+            "failed to start webhook receiver on {}: {:#?}",
+            listen_addr,
+            err
+        )
+    })?;
+    println!("receiving webhooks on http://{}", listen_addr);
+    for mut request in server.incoming_requests() {
+        let mut body = String::new();
+        if let Err(err) = request.as_reader().read_to_string(&mut body) {
+            error!("failed to read webhook request {:#?}", err);
+        }
+        println!("{} {}", request.method(), request.url());
+        println!("{}", body);
+        if let Err(err) = request.respond(Response::from_string("ok")) {
+            error!("failed to respond to webhook request {:#?}", err);
+        }
+    }
+    Ok(())
+}
+
+/// escapes the value so it can be placed within a json string
+fn json_escape(value: &str) -> String {
+    let quoted = Value::String(value.to_string()).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::Receiver;
+
+    fn webhook(format: WebhookFormat, template: Option<&str>) -> Webhook {
+        Webhook {
+            url: "http://127.0.0.1:1".to_string(),
+            name: None,
+            format,
+            template: template.map(str::to_string),
+        }
+    }
+
+    /// an alert manager queueing its notifications on the returned channel
+    fn manager() -> (AlertManager, Receiver<(Vec<Webhook>, Notification)>) {
+        let config = Alerts {
+            webhooks: vec![webhook(WebhookFormat::Generic, None)],
+            max_send_failures: 2,
+            max_queue_len: 10,
+            queue_len_iterations: 2,
+            repeat_interval: 60,
+            ..Alerts::default()
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        (AlertManager::with_sender(config, sender), receiver)
+    }
+
+    /// returns the key and whether it was resolved of each queued notification
+    fn sent(receiver: &Receiver<(Vec<Webhook>, Notification)>) -> Vec<(String, bool)> {
+        receiver
+            .try_iter()
+            .map(|(_, notification)| (notification.key, notification.resolved))
+            .collect()
+    }
+
+    #[test]
+    fn alerts_are_sent_once_when_firing_and_once_when_resolved() {
+        let (mut manager, receiver) = manager();
+        manager.record_send(Some("blockhash not found".to_string()));
+        assert!(sent(&receiver).is_empty());
+        manager.record_send(Some("blockhash not found".to_string()));
+        assert_eq!(sent(&receiver), vec![("send_failures".to_string(), false)]);
+        // still firing within repeat_interval
+        manager.record_send(Some("blockhash not found".to_string()));
+        manager.record_send(Some("blockhash not found".to_string()));
+        assert!(sent(&receiver).is_empty());
+        manager.record_send(None);
+        assert_eq!(sent(&receiver), vec![("send_failures".to_string(), true)]);
+        manager.record_send(None);
+        assert!(sent(&receiver).is_empty());
+    }
+
+    #[test]
+    fn firing_alerts_are_repeated_after_repeat_interval() {
+        let (mut manager, receiver) = manager();
+        let payer = Pubkey::new_unique();
+        let key = format!("payer_balance_low {}", payer);
+        manager.record_payer_balance(&payer, 1, 1_000);
+        assert_eq!(sent(&receiver), vec![(key.clone(), false)]);
+        // the alert was last sent longer than repeat_interval ago
+        let last_sent = Instant::now().checked_sub(Duration::from_secs(61)).unwrap();
+        manager.firing.get_mut(&key).unwrap().last_sent = last_sent;
+        manager.record_payer_balance(&payer, 1, 1_000);
+        assert_eq!(sent(&receiver), vec![(key.clone(), false)]);
+        manager.record_payer_balance(&payer, 1, 1_000);
+        assert!(sent(&receiver).is_empty());
+        // never repeated when repeat_interval is 0
+        manager.config.repeat_interval = 0;
+        manager.firing.get_mut(&key).unwrap().last_sent = last_sent;
+        manager.record_payer_balance(&payer, 1, 1_000);
+        assert!(sent(&receiver).is_empty());
+        manager.record_payer_balance(&payer, 1_000, 1_000);
+        assert_eq!(sent(&receiver), vec![(key, true)]);
+    }
+
+    #[test]
+    fn queue_length_alerts_are_tracked_per_market() {
+        let (mut manager, receiver) = manager();
+        let market = Pubkey::new_unique();
+        let other_market = Pubkey::new_unique();
+        manager.record_queue_len(&market, 11);
+        manager.record_queue_len(&other_market, 11);
+        assert!(sent(&receiver).is_empty());
+        manager.record_queue_len(&market, 11);
+        manager.record_queue_len(&other_market, 3);
+        manager.record_queue_len(&other_market, 11);
+        let key = format!("queue_length {}", market);
+        assert_eq!(sent(&receiver), vec![(key.clone(), false)]);
+        manager.record_queue_len(&market, 10);
+        assert_eq!(sent(&receiver), vec![(key, true)]);
+    }
+
+    #[test]
+    fn nothing_is_queued_without_webhooks() {
+        let (mut manager, receiver) = manager();
+        manager.set_config(Alerts {
+            webhooks: vec![],
+            rpc_unreachable_iterations: 1,
+            ..Alerts::default()
+        });
+        manager.record_rpc(Some("connection refused".to_string()));
+        assert!(manager.firing.contains_key("rpc_unreachable"));
+        assert!(sent(&receiver).is_empty());
+    }
+
+    #[test]
+    fn notifications_render_every_format() {
+        let notification = Notification::new(
+            AlertRule::QueueLength,
+            "queue_length market".to_string(),
+            false,
+            "message with \"quotes\"\nand a new line".to_string(),
+        );
+        let generic: Value = serde_json::from_str(
+            &notification
+                .render(&webhook(WebhookFormat::Generic, None))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(generic["rule"], "queue_length");
+        assert_eq!(generic["key"], "queue_length market");
+        assert_eq!(generic["status"], "firing");
+        assert_eq!(generic["message"], notification.message.as_str());
+        assert_eq!(generic["time"], notification.time.as_str());
+        let slack: Value = serde_json::from_str(
+            &notification
+                .render(&webhook(WebhookFormat::Slack, None))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            slack["text"],
+            format!("*{}*\n{}", notification.title(), notification.message).as_str()
+        );
+        let discord: Value = serde_json::from_str(
+            &notification
+                .render(&webhook(WebhookFormat::Discord, None))
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(discord["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("**serum crank alert firing: queue_length market**"));
+        let custom = webhook(
+            WebhookFormat::Slack,
+            Some(r#"{"alert": "{{key}} {{status}}"}"#),
+        );
+        assert_eq!(
+            notification.render(&custom).unwrap(),
+            r#"{"alert": "queue_length market firing"}"#
+        );
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        let invalid = webhook(WebhookFormat::Generic, Some(r#"{"text": {{message}}}"#));
+        let err = Notification::test().render(&invalid).unwrap_err();
+        assert!(err.to_string().contains("payload is not valid json"));
+    }
+
+    #[test]
+    fn notifications_are_delivered_with_retries() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr());
+        let (bodies, received) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            // the first attempt fails, the second succeeds
+            for (attempt, mut request) in server.incoming_requests().enumerate() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                bodies.send(body).unwrap();
+                let status = if attempt == 0 { 500 } else { 200 };
+                let _ = request.respond(Response::from_string("ok").with_status_code(status));
+            }
+        });
+        let webhook = Webhook {
+            url,
+            ..webhook(WebhookFormat::Generic, None)
+        };
+        let notification = Notification::test();
+        deliver(&webhook_client().unwrap(), &webhook, &notification).unwrap();
+        let bodies: Vec<String> = received.try_iter().collect();
+        let payload = notification.render(&webhook).unwrap();
+        assert_eq!(bodies, vec![payload.clone(), payload]);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::config::PayerBalance;
+use crate::metrics::Metrics;
+use log::{error, info, warn};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// window over which the spend rate of a payer is measured
+const SPEND_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+/// min time spend must be measured over before estimating the runway
+const MIN_SPEND_WINDOW: Duration = Duration::from_secs(60);
+const SECONDS_PER_DAY: f64 = 86_400.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BalanceLevel {
+    Ok,
+    /// the balance is below the warning threshold
+    Warning,
+    /// the balance is below the critical threshold, the payer is skipped
+    Critical,
+}
+
+/// tracks the balance of each payer and the fees it spends, logging when a
+/// balance crosses the warning and critical thresholds and estimating the
+/// number of days until the payer runs dry
+pub struct BalanceMonitor {
+    config: PayerBalance,
+    metrics: Arc<Metrics>,
+    started: Instant,
+    payers: HashMap<Pubkey, PayerState>,
+}
+
+struct PayerState {
+    /// fees spent within the spend window, oldest first
+    spend: VecDeque<(Instant, u64)>,
+    level: BalanceLevel,
+}
+
+impl BalanceMonitor {
+    pub fn new(config: PayerBalance, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config,
+            metrics,
+            started: Instant::now(),
+            payers: HashMap::new(),
+        }
+    }
+    /// replaces the thresholds, which are applied on the next balance update
+    pub fn set_config(&mut self, config: PayerBalance) {
+        self.config = config;
+    }
+    /// records fees paid by a crank transaction
+    pub fn record_spend(&mut self, payer: &Pubkey, lamports: u64) {
+        self.metrics
+            .payer_lamports_spent
+            .with_label_values(&[&payer.to_string()])
+            .inc_by(lamports);
+        self.payers
+            .entry(*payer)
+            .or_insert_with(PayerState::default)
+            .spend
+            .push_back((Instant::now(), lamports));
+    }
+    /// updates the balance of the payer, logging when it crosses a threshold
+    pub fn update(&mut self, payer: &Pubkey, balance: u64) {
+        let label = payer.to_string();
+        self.metrics
+            .payer_balance
+            .with_label_values(&[&label])
+            .set(balance as i64);
+        let config = &self.config;
+        let state = self
+            .payers
+            .entry(*payer)
+            .or_insert_with(PayerState::default);
+        let runway_days = state.runway_days(self.started, balance);
+        if let Some(runway_days) = runway_days {
+            self.metrics
+                .payer_runway_days
+                .with_label_values(&[&label])
+                .set(runway_days);
+        }
+        let runway = runway_days
+            .map(|days| format!("{:.1} days", days))
+            .unwrap_or_else(|| "unknown".to_string());
+        let level = if balance < config.critical_balance {
+            BalanceLevel::Critical
+        } else if balance < config.warning_balance {
+            BalanceLevel::Warning
+        } else {
+            BalanceLevel::Ok
+        };
+        if level == state.level {
+            return;
+        }
+        match level {
+            BalanceLevel::Critical => error!(
+                "payer {} balance {} is below the critical threshold of {} lamports, skipping the payer",
+                payer, balance, config.critical_balance
+            ),
+            BalanceLevel::Warning => warn!(
+                "payer {} balance {} is below the warning threshold of {} lamports, estimated runway {}",
+                payer, balance, config.warning_balance, runway
+            ),
+            BalanceLevel::Ok => info!(
+                "payer {} balance {} lamports is above the warning threshold, estimated runway {}",
+                payer, balance, runway
+            ),
+        }
+        if state.level == BalanceLevel::Critical {
+            info!("payer {} is used for crank transactions again", payer);
+        }
+        state.level = level;
+    }
+    /// a payer is skipped while its balance is critical
+    pub fn is_paused(&self, payer: &Pubkey) -> bool {
+        self.payers
+            .get(payer)
+            .map(|state| state.level == BalanceLevel::Critical)
+            .unwrap_or(false)
+    }
+}
+
+impl Default for PayerState {
+    fn default() -> Self {
+        Self {
+            spend: VecDeque::new(),
+            level: BalanceLevel::Ok,
+        }
+    }
+}
+
+impl PayerState {
+    /// estimates the number of days the balance lasts at the recent spend rate
+    fn runway_days(&mut self, started: Instant, balance: u64) -> Option<f64> {
+        while let Some((spent_at, _)) = self.spend.front() {
+            if spent_at.elapsed() <= SPEND_WINDOW {
+                break;
+            }
+            self.spend.pop_front();
+        }
+        let window = started.elapsed().min(SPEND_WINDOW);
+        let spent: u64 = self.spend.iter().map(|(_, lamports)| lamports).sum();
+        if window < MIN_SPEND_WINDOW || spent == 0 {
+            return None;
+        }
+        let spent_per_day = spent as f64 / window.as_secs_f64() * SECONDS_PER_DAY;
+        Some(balance as f64 / spent_per_day)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> BalanceMonitor {
+        BalanceMonitor::new(
+            PayerBalance {
+                warning_balance: 1_000,
+                critical_balance: 100,
+            },
+            Arc::new(Metrics::new().unwrap()),
+        )
+    }
+
+    #[test]
+    fn payers_are_paused_while_critical() {
+        let mut monitor = monitor();
+        let payer = Pubkey::new_unique();
+        assert!(!monitor.is_paused(&payer));
+        monitor.update(&payer, 99);
+        assert!(monitor.is_paused(&payer));
+        assert_eq!(monitor.payers[&payer].level, BalanceLevel::Critical);
+        monitor.update(&payer, 500);
+        assert!(!monitor.is_paused(&payer));
+        assert_eq!(monitor.payers[&payer].level, BalanceLevel::Warning);
+        monitor.update(&payer, 1_000);
+        assert_eq!(monitor.payers[&payer].level, BalanceLevel::Ok);
+        assert!(!monitor.is_paused(&Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn thresholds_apply_on_the_next_update() {
+        let mut monitor = monitor();
+        let payer = Pubkey::new_unique();
+        monitor.update(&payer, 500);
+        assert!(!monitor.is_paused(&payer));
+        monitor.set_config(PayerBalance {
+            warning_balance: 10_000,
+            critical_balance: 1_000,
+        });
+        assert!(!monitor.is_paused(&payer));
+        monitor.update(&payer, 500);
+        assert!(monitor.is_paused(&payer));
+    }
+
+    #[test]
+    fn runway_is_estimated_from_the_spend_rate() {
+        let mut state = PayerState::default();
+        let started = Instant::now().checked_sub(MIN_SPEND_WINDOW * 2).unwrap();
+        assert_eq!(state.runway_days(started, 1_000), None);
+        state.spend.push_back((Instant::now(), 500));
+        // 500 lamports over two minutes is 360,000 lamports a day
+        let runway_days = state.runway_days(started, 3_600_000).unwrap();
+        assert!((runway_days - 10.0).abs() < 0.1);
+        // spend is only measured once the crank has been running for a while
+        assert_eq!(state.runway_days(Instant::now(), 3_600_000), None);
+    }
+}
@@ -0,0 +1,388 @@
+use crate::config::{ParsedMarketKeys, PriorityFee};
+use crate::rpc::RpcPool;
+use anyhow::Result;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use solana_client::rpc_request::RpcRequest;
+use solana_sdk::instruction::Instruction;
+use std::sync::Arc;
+
+/// the compute budget native program
+pub mod program {
+    solana_sdk::declare_id!("ComputeBudget111111111111111111111111111111");
+}
+
+/// max compute units a single transaction may request
+pub const MAX_COMPUTE_UNIT_LIMIT: u32 = 1_400_000;
+/// compute units allotted to each instruction when no limit is requested
+const DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT: u64 = 200_000;
+/// max number of accounts accepted by getRecentPrioritizationFees
+const MAX_PRIORITIZATION_FEE_ACCOUNTS: usize = 128;
+
+/// instruction tag of ComputeBudgetInstruction::SetComputeUnitLimit
+const SET_COMPUTE_UNIT_LIMIT: u8 = 2;
+/// instruction tag of ComputeBudgetInstruction::SetComputeUnitPrice
+const SET_COMPUTE_UNIT_PRICE: u8 = 3;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecentPrioritizationFee {
+    prioritization_fee: u64,
+}
+
+/// builds the compute budget instructions prepended to crank transactions
+pub struct ComputeBudget {
+    rpc: Arc<RpcPool>,
+    config: PriorityFee,
+}
+
+impl ComputeBudget {
+    pub fn new(rpc: Arc<RpcPool>, config: PriorityFee) -> Self {
+        Self { rpc, config }
+    }
+    /// returns placeholder instructions of the same size as those returned by
+    /// `instructions`, used by the transaction packer to measure transactions
+    pub fn placeholder_instructions(&self, markets: &[ParsedMarketKeys]) -> Vec<Instruction> {
+        let mut ixs = Vec::with_capacity(2);
+        if self.config.compute_unit_limit.is_some()
+            || markets
+                .iter()
+                .any(|market| market.compute_unit_limit.is_some())
+        {
+            ixs.push(set_compute_unit_limit(0));
+        }
+        if self.config.dynamic
+            || self.config.compute_unit_price.is_some()
+            || markets
+                .iter()
+                .any(|market| market.compute_unit_price.is_some())
+        {
+            ixs.push(set_compute_unit_price(0));
+        }
+        ixs
+    }
+    /// returns the compute budget instructions for a transaction cranking the given markets.
+    /// the compute unit limit is the sum of the limit of each market, counting markets
+    /// without a limit at the runtime default, while the price is the highest price of
+    /// any market, or the recent prioritization fee when dynamic
+    pub fn instructions(&self, markets: &[&ParsedMarketKeys]) -> Vec<Instruction> {
+        let mut ixs = Vec::with_capacity(2);
+        let limits: Vec<Option<u32>> = markets
+            .iter()
+            .map(|market| market.compute_unit_limit.or(self.config.compute_unit_limit))
+            .collect();
+        if limits.iter().any(Option::is_some) {
+            // the requested limit replaces the default of every instruction in the transaction
+            let limit = limits
+                .iter()
+                .map(|limit| limit.unwrap_or(DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT as u32))
+                .fold(0_u32, |total, limit| total.saturating_add(limit))
+                .min(MAX_COMPUTE_UNIT_LIMIT);
+            ixs.push(set_compute_unit_limit(limit));
+        }
+        let static_price = markets
+            .iter()
+            .filter_map(|market| market.compute_unit_price.or(self.config.compute_unit_price))
+            .max();
+        let price = if self.config.dynamic {
+            match self.recent_prioritization_fee(markets) {
+                Ok(fee) => {
+                    let price = fee
+                        .max(static_price.unwrap_or(0))
+                        .min(self.config.max_compute_unit_price);
+                    info!(
+                        "using dynamic compute unit price {} (recent fee {}, ceiling {})",
+                        price, fee, self.config.max_compute_unit_price
+                    );
+                    Some(price)
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to retrieve recent prioritization fees, falling back to static price: {:#?}",
+                        err
+                    );
+                    // keep the instruction so the transaction matches the packed size
+                    Some(
+                        static_price
+                            .unwrap_or(0)
+                            .min(self.config.max_compute_unit_price),
+                    )
+                }
+            }
+        } else {
+            static_price
+        };
+        if let Some(price) = price {
+            ixs.push(set_compute_unit_price(price));
+        }
+        ixs
+    }
+    /// returns the configured percentile of the prioritization fees recently
+    /// paid by transactions write locking the event queues of the markets
+    fn recent_prioritization_fee(&self, markets: &[&ParsedMarketKeys]) -> Result<u64> {
+        let accounts: Vec<String> = markets
+            .iter()
+            .map(|market| market.keys.event_q.to_string())
+            .take(MAX_PRIORITIZATION_FEE_ACCOUNTS)
+            .collect();
+        let mut fees: Vec<u64> = self
+            .rpc
+            .read(|client| {
+                client.send::<Vec<RecentPrioritizationFee>>(
+                    RpcRequest::Custom {
+                        method: "getRecentPrioritizationFees",
+                    },
+                    json!([accounts]),
+                )
+            })?
+            .into_iter()
+            .map(|fee| fee.prioritization_fee)
+            .collect();
+        if fees.is_empty() {
+            return Ok(0);
+        }
+        fees.sort_unstable();
+        let percentile = self.config.dynamic_percentile.min(100) as usize;
+        let idx = (fees.len() - 1) * percentile / 100;
+        Ok(fees[idx])
+    }
+}
+
+/// returns a ComputeBudgetInstruction::SetComputeUnitLimit instruction
+pub fn set_compute_unit_limit(units: u32) -> Instruction {
+    let mut data = Vec::with_capacity(5);
+    data.push(SET_COMPUTE_UNIT_LIMIT);
+    data.extend_from_slice(&units.to_le_bytes());
+    Instruction {
+        program_id: program::id(),
+        accounts: vec![],
+        data,
+    }
+}
+
+/// returns a ComputeBudgetInstruction::SetComputeUnitPrice instruction
+pub fn set_compute_unit_price(micro_lamports: u64) -> Instruction {
+    let mut data = Vec::with_capacity(9);
+    data.push(SET_COMPUTE_UNIT_PRICE);
+    data.extend_from_slice(&micro_lamports.to_le_bytes());
+    Instruction {
+        program_id: program::id(),
+        accounts: vec![],
+        data,
+    }
+}
+
+/// returns the prioritization fee in lamports paid by a transaction made up of the
+/// instructions, based on the compute budget instructions it contains
+pub fn prioritization_fee(instructions: &[Instruction]) -> u64 {
+    let mut limit: Option<u64> = None;
+    let mut price: Option<u64> = None;
+    let mut num_other_ixs = 0_u64;
+    for ix in instructions {
+        if ix.program_id != program::id() {
+            num_other_ixs += 1;
+            continue;
+        }
+        match ix.data.split_first() {
+            Some((&SET_COMPUTE_UNIT_LIMIT, units)) if units.len() == 4 => {
+                let mut bytes = [0_u8; 4];
+                bytes.copy_from_slice(units);
+                limit = Some(u32::from_le_bytes(bytes) as u64);
+            }
+            Some((&SET_COMPUTE_UNIT_PRICE, micro_lamports)) if micro_lamports.len() == 8 => {
+                let mut bytes = [0_u8; 8];
+                bytes.copy_from_slice(micro_lamports);
+                price = Some(u64::from_le_bytes(bytes));
+            }
+            _ => {}
+        }
+    }
+    let price = match price {
+        Some(price) => price,
+        None => return 0,
+    };
+    let limit = limit.unwrap_or_else(|| {
+        (num_other_ixs * DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT).min(MAX_COMPUTE_UNIT_LIMIT as u64)
+    });
+    // prices are in micro-lamports, rounding up to the nearest lamport
+    ((price as u128 * limit as u128 + 999_999) / 1_000_000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RpcEndpoint, RpcFailover, RpcRole};
+    use crate::metrics::Metrics;
+    use crate::rpc::mock::mock_endpoint;
+    use serde_json::Value;
+    use solana_sdk::pubkey::Pubkey;
+
+    /// nothing listens on this port, so requests fail right away
+    const UNREACHABLE_URL: &str = "http://127.0.0.1:1";
+
+    /// starts an rpc endpoint answering getRecentPrioritizationFees with the fees
+    fn fees_endpoint(fees: Vec<u64>) -> String {
+        mock_endpoint(move |_, _| {
+            let fees: Vec<Value> = fees
+                .iter()
+                .enumerate()
+                .map(|(slot, fee)| json!({"slot": slot, "prioritizationFee": fee}))
+                .collect();
+            json!(fees)
+        })
+    }
+
+    fn compute_budget(url: &str, config: PriorityFee) -> ComputeBudget {
+        let rpc = RpcPool::new(
+            vec![RpcEndpoint {
+                url: url.to_string(),
+                name: None,
+                role: RpcRole::Both,
+            }],
+            RpcFailover::default(),
+            Arc::new(Metrics::new().unwrap()),
+        );
+        ComputeBudget::new(Arc::new(rpc), config)
+    }
+
+    fn market(
+        compute_unit_limit: Option<u32>,
+        compute_unit_price: Option<u64>,
+    ) -> ParsedMarketKeys {
+        let mut market = ParsedMarketKeys {
+            compute_unit_limit,
+            compute_unit_price,
+            ..ParsedMarketKeys::default()
+        };
+        market.keys.event_q = Pubkey::new_unique();
+        market
+    }
+
+    /// returns the compute unit limit and price set by the instructions
+    fn decode(ixs: &[Instruction]) -> (Option<u32>, Option<u64>) {
+        let (mut limit, mut price) = (None, None);
+        for ix in ixs {
+            assert_eq!(ix.program_id, program::id());
+            assert!(ix.accounts.is_empty());
+            match ix.data[0] {
+                SET_COMPUTE_UNIT_LIMIT => {
+                    let mut bytes = [0_u8; 4];
+                    bytes.copy_from_slice(&ix.data[1..]);
+                    limit = Some(u32::from_le_bytes(bytes));
+                }
+                SET_COMPUTE_UNIT_PRICE => {
+                    let mut bytes = [0_u8; 8];
+                    bytes.copy_from_slice(&ix.data[1..]);
+                    price = Some(u64::from_le_bytes(bytes));
+                }
+                tag => panic!("unexpected instruction {}", tag),
+            }
+        }
+        (limit, price)
+    }
+
+    #[test]
+    fn instructions_are_encoded() {
+        let ix = set_compute_unit_limit(300_000);
+        assert_eq!(ix.program_id, program::id());
+        assert_eq!(ix.data, vec![2, 0xe0, 0x93, 0x04, 0x00]);
+        let ix = set_compute_unit_price(1_000_001);
+        assert_eq!(ix.data, vec![3, 0x41, 0x42, 0x0f, 0x00, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn limits_are_summed_and_capped() {
+        let budget = compute_budget(UNREACHABLE_URL, PriorityFee::default());
+        let (a, b) = (market(Some(50_000), None), market(Some(70_000), None));
+        assert_eq!(
+            decode(&budget.instructions(&[&a, &b])),
+            (Some(120_000), None)
+        );
+        // markets without a limit are counted at the runtime default
+        let c = market(None, None);
+        assert_eq!(
+            decode(&budget.instructions(&[&a, &c])),
+            (Some(250_000), None)
+        );
+        assert_eq!(decode(&budget.instructions(&[&c])), (None, None));
+        let large: Vec<ParsedMarketKeys> = (0..3).map(|_| market(Some(600_000), None)).collect();
+        let large: Vec<&ParsedMarketKeys> = large.iter().collect();
+        assert_eq!(
+            decode(&budget.instructions(&large)),
+            (Some(MAX_COMPUTE_UNIT_LIMIT), None)
+        );
+        // the configured limit applies to markets without an override
+        let config = PriorityFee {
+            compute_unit_limit: Some(10_000),
+            ..PriorityFee::default()
+        };
+        let budget = compute_budget(UNREACHABLE_URL, config);
+        assert_eq!(
+            decode(&budget.instructions(&[&a, &c])),
+            (Some(60_000), None)
+        );
+    }
+
+    #[test]
+    fn highest_price_is_used() {
+        let config = PriorityFee {
+            compute_unit_price: Some(5),
+            ..PriorityFee::default()
+        };
+        let budget = compute_budget(UNREACHABLE_URL, config);
+        let (a, b) = (market(None, Some(20)), market(None, None));
+        assert_eq!(decode(&budget.instructions(&[&a, &b])), (None, Some(20)));
+        assert_eq!(decode(&budget.instructions(&[&b])), (None, Some(5)));
+        let budget = compute_budget(UNREACHABLE_URL, PriorityFee::default());
+        assert_eq!(decode(&budget.instructions(&[&b])), (None, None));
+    }
+
+    #[test]
+    fn dynamic_price_is_bounded() {
+        let config = PriorityFee {
+            compute_unit_price: Some(10),
+            dynamic: true,
+            dynamic_percentile: 50,
+            max_compute_unit_price: 1_000,
+            ..PriorityFee::default()
+        };
+        let a = market(None, None);
+        // the median recent fee
+        let budget = compute_budget(&fees_endpoint(vec![300, 100, 200]), config.clone());
+        assert_eq!(decode(&budget.instructions(&[&a])), (None, Some(200)));
+        // the static price is the minimum
+        let budget = compute_budget(&fees_endpoint(vec![1, 2, 3]), config.clone());
+        assert_eq!(decode(&budget.instructions(&[&a])), (None, Some(10)));
+        // the ceiling is the maximum
+        let budget = compute_budget(&fees_endpoint(vec![5_000, 6_000]), config.clone());
+        assert_eq!(decode(&budget.instructions(&[&a])), (None, Some(1_000)));
+        // the static price is used when the fees can't be retrieved
+        let budget = compute_budget(UNREACHABLE_URL, config);
+        assert_eq!(decode(&budget.instructions(&[&a])), (None, Some(10)));
+    }
+
+    #[test]
+    fn placeholders_match_the_instructions() {
+        let config = PriorityFee {
+            compute_unit_price: Some(10),
+            ..PriorityFee::default()
+        };
+        let budget = compute_budget(UNREACHABLE_URL, config);
+        let markets = vec![market(Some(50_000), None), market(None, None)];
+        let placeholders = budget.placeholder_instructions(&markets);
+        let ixs = budget.instructions(&markets.iter().collect::<Vec<_>>());
+        assert_eq!(placeholders.len(), ixs.len());
+        for (placeholder, ix) in placeholders.iter().zip(ixs.iter()) {
+            assert_eq!(placeholder.data.len(), ix.data.len());
+            assert_eq!(placeholder.data[0], ix.data[0]);
+        }
+    }
+
+    #[test]
+    fn prioritization_fee_rounds_up() {
+        let ixs = vec![set_compute_unit_limit(300_000), set_compute_unit_price(5)];
+        assert_eq!(prioritization_fee(&ixs), 2);
+        assert_eq!(prioritization_fee(&[set_compute_unit_limit(300_000)]), 0);
+    }
+}
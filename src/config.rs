@@ -1,24 +1,248 @@
+use crate::alerts;
+use crate::compute_budget::MAX_COMPUTE_UNIT_LIMIT;
 use crate::crank::{get_keys_for_market, MarketPubkeys};
-use anyhow::Result;
+use crate::keys::KeyLoader;
+use crate::logfile::LogFile;
+use crate::logging::JsonLogger;
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 use simplelog::*;
 use solana_client::rpc_client::RpcClient;
 use solana_sdk::pubkey::Pubkey;
-use solana_sdk::signature::{read_keypair_file, Keypair};
-use std::fs::File;
-use std::sync::Arc;
+use solana_sdk::signature::{Keypair, Signer};
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
 use std::{fs, str::FromStr};
+use url::Url;
+
+/// max number of unique account keys a transaction can reference
+const MAX_TRANSACTION_ACCOUNTS: usize = 256;
+/// replaces secrets in printed configurations
+const REDACTED: &str = "REDACTED";
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Configuration {
     pub http_rpc_url: String,
     pub ws_rpc_url: String,
-    pub key_path: String,
+    /// additional rpc endpoints, http_rpc_url is always
+    /// the first endpoint and serves both reads and sends
+    #[serde(default)]
+    pub rpc_endpoints: Vec<RpcEndpoint>,
+    #[serde(default)]
+    pub rpc_failover: RpcFailover,
+    /// key source, or list of key sources, of the keypairs used to pay for crank
+    /// transactions. a key source is a keypair or keystore file, a directory of
+    /// them, or `env:<VAR>`. the payers are rotated per transaction
+    pub key_path: KeyPath,
+    /// file the logs are written to along with stdout, only logged to stdout when empty
+    #[serde(default)]
     pub log_file: String,
     pub debug_log: bool,
+    /// format of log records, json records carry fields such as the market,
+    /// iteration and signature so they can be filtered without parsing messages
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub log_rotation: LogRotation,
+    /// address the embedded http server listens on, serving prometheus metrics
+    /// at /metrics and the /healthz and /readyz probes. disabled when not set
+    #[serde(default)]
+    pub http_listen_addr: Option<String>,
+    #[serde(default)]
+    pub health_check: HealthCheck,
+    #[serde(default)]
+    pub payer_balance: PayerBalance,
+    #[serde(default)]
+    pub cost_accounting: CostAccounting,
+    #[serde(default)]
+    pub history: History,
+    #[serde(default)]
+    pub alerts: Alerts,
     pub crank: Crank,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RpcEndpoint {
+    pub url: String,
+    /// name of the endpoint used in logs and metrics, defaults to the
+    /// host of the url so that api keys in the path aren't exposed
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub role: RpcRole,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcRole {
+    /// only used for reading accounts and transaction statuses
+    Read,
+    /// only used for sending transactions
+    Send,
+    Both,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KeyPath {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogRotation {
+    /// rotate log_file once it grows to this many bytes, never rotated by size when 0
+    #[serde(default = "default_log_max_size")]
+    pub max_size: u64,
+    /// rotate log_file after it has been written to for this many hours,
+    /// never rotated by age when 0
+    #[serde(default)]
+    pub max_age_hours: u64,
+    /// number of rotated log files kept
+    #[serde(default = "default_log_retain")]
+    pub retain: usize,
+    /// gzip compress rotated log files
+    #[serde(default)]
+    pub compress: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RpcFailover {
+    /// time in seconds between slot checks of the rpc endpoints
+    #[serde(default = "default_health_check_interval")]
+    pub health_check_interval: u64,
+    /// an endpoint is unhealthy when the fraction of its recent
+    /// requests which failed is above this, between 0 and 1
+    #[serde(default = "default_max_error_rate")]
+    pub max_error_rate: f64,
+    /// an endpoint is unhealthy when its slot is more than this
+    /// many slots behind the highest slot of all endpoints
+    #[serde(default = "default_max_slot_lag")]
+    pub max_slot_lag: u64,
+    /// when true, send transactions to every healthy send endpoint
+    /// instead of only the first one
+    #[serde(default)]
+    pub fan_out_sends: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PayerBalance {
+    /// log a warning when the payer balance in lamports falls below this
+    #[serde(default = "default_warning_balance")]
+    pub warning_balance: u64,
+    /// stop using a payer when its balance in lamports falls below this. sending
+    /// crank transactions is paused, while still monitoring the queues, when
+    /// every payer is below this
+    #[serde(default = "default_critical_balance")]
+    pub critical_balance: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthCheck {
+    /// the service is unhealthy when no crank loop iteration has completed
+    /// within this multiple of the largest max_wait_for_events_delay of any market,
+    /// plus the time spent waiting for crank transactions to confirm
+    pub max_iteration_delay_multiple: u64,
+    /// the service is unhealthy when the total balance of the payers in lamports is below this
+    pub min_payer_balance: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CostAccounting {
+    /// file the running totals of the fees paid per market and per payer are
+    /// kept in across restarts, costs aren't tracked when empty, as they are by default
+    #[serde(default)]
+    pub ledger_file: String,
+    /// how the fee of a transaction cranking several markets is split between them
+    #[serde(default)]
+    pub apportion: Apportion,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alerts {
+    /// webhooks every alert and recovery notice is delivered to
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    /// alert when this many crank transactions in a row fail to send, disabled when 0
+    #[serde(default = "default_max_send_failures")]
+    pub max_send_failures: u64,
+    /// alert when the event queue of a market holds more than this many events
+    /// for queue_len_iterations checks in a row, disabled when 0
+    #[serde(default)]
+    pub max_queue_len: u64,
+    #[serde(default = "default_queue_len_iterations")]
+    pub queue_len_iterations: u64,
+    /// alert when a payer balance is below payer_balance.warning_balance
+    #[serde(default = "default_alert_payer_balance_low")]
+    pub payer_balance_low: bool,
+    /// alert when the payer balances can't be fetched from the rpc node
+    /// for this many crank loop iterations in a row, disabled when 0
+    #[serde(default = "default_rpc_unreachable_iterations")]
+    pub rpc_unreachable_iterations: u64,
+    /// time in seconds after which an alert which is still firing is sent
+    /// again, only sent once when 0
+    #[serde(default = "default_alert_repeat_interval")]
+    pub repeat_interval: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// name of the webhook used in logs, defaults to the host of the url
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// json payload sent instead of the payload of format, in which
+    /// `{{title}}`, `{{message}}`, `{{rule}}`, `{{key}}`, `{{status}}`
+    /// and `{{time}}` are replaced by the json escaped alert fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// a json object of every alert field
+    Generic,
+    /// a slack incoming webhook message
+    Slack,
+    /// a discord webhook message
+    Discord,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    /// sqlite database recording every crank loop iteration, along with the queue
+    /// state of each market's last confirmed crank which is restored on restart.
+    /// no history is kept when empty, as it is by default
+    #[serde(default)]
+    pub database: String,
+    /// number of days crank loop iterations are kept, kept forever when 0
+    #[serde(default = "default_history_retention_days")]
+    pub retention_days: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Apportion {
+    /// evenly per crank instruction of each market
+    Instructions,
+    /// by the compute units consumed by each market's crank instructions,
+    /// split per instruction when they aren't in the transaction logs
+    ComputeUnits,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Crank {
     pub markets: Vec<Market>,
@@ -26,7 +250,81 @@ pub struct Crank {
     pub max_wait_for_events_delay: u64,
     pub num_accounts: usize,
     pub events_per_worker: usize,
-    pub max_markets_per_tx: usize,
+    /// optional upper bound on the number of markets cranked in a single
+    /// transaction, transactions are otherwise packed by size and account count
+    #[serde(default)]
+    pub max_markets_per_tx: Option<usize>,
+    /// max number of unique account keys in a single transaction
+    #[serde(default = "default_max_accounts_per_tx")]
+    pub max_accounts_per_tx: usize,
+    /// when true, subscribe to each market's event queue over ws_rpc_url
+    /// and crank as soon as events arrive, polling stays as a fallback
+    #[serde(default)]
+    pub use_ws_subscriptions: bool,
+    /// time in milliseconds to wait for further event queue notifications
+    /// after the first one, so a burst of fills produces one crank run
+    #[serde(default = "default_ws_debounce_ms")]
+    pub ws_debounce_ms: u64,
+    /// time in milliseconds between signature status checks of sent transactions
+    #[serde(default = "default_confirm_poll_interval_ms")]
+    pub confirm_poll_interval_ms: u64,
+    /// time in milliseconds after which an unconfirmed transaction is re-broadcast
+    #[serde(default = "default_resend_interval_ms")]
+    pub resend_interval_ms: u64,
+    /// compute budget and priority fee settings for crank transactions
+    #[serde(default)]
+    pub priority_fee: PriorityFee,
+    /// when set, also crank the markets discovered from the dex program
+    #[serde(default)]
+    pub discovery: Option<Discovery>,
+    /// minimum time in seconds between confirmed cranks of a market,
+    /// no minimum is enforced when 0
+    #[serde(default)]
+    pub min_crank_interval: u64,
+    /// number of worker threads fetching and processing market queues,
+    /// bounding the number of concurrent rpc requests
+    #[serde(default = "default_worker_threads")]
+    pub worker_threads: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Discovery {
+    /// time in seconds between refreshes of the discovered markets
+    pub refresh_interval: u64,
+    /// when not empty, only crank markets whose coin or pc mint is in this list
+    #[serde(default)]
+    pub include_mints: Vec<String>,
+    /// never crank markets whose coin or pc mint is in this list
+    #[serde(default)]
+    pub exclude_mints: Vec<String>,
+    /// coin wallet used for discovered markets, defaults to the first payer
+    #[serde(default)]
+    pub coin_wallet: Option<String>,
+    /// pc wallet used for discovered markets, defaults to the first payer
+    #[serde(default)]
+    pub pc_wallet: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PriorityFee {
+    /// compute units requested for each market's consume events instruction,
+    /// the limit of a transaction is the sum of the markets it cranks
+    #[serde(default)]
+    pub compute_unit_limit: Option<u32>,
+    /// price in micro-lamports per compute unit. used as is when dynamic
+    /// is false, and as the minimum price when dynamic is true
+    #[serde(default)]
+    pub compute_unit_price: Option<u64>,
+    /// when true, derive the price from the fees recently paid
+    /// for transactions write locking the markets' event queues
+    #[serde(default)]
+    pub dynamic: bool,
+    /// percentile of the recent prioritization fees to pay in dynamic mode
+    #[serde(default = "default_dynamic_percentile")]
+    pub dynamic_percentile: u8,
+    /// hard ceiling for the dynamic price in micro-lamports per compute unit
+    #[serde(default = "default_max_compute_unit_price")]
+    pub max_compute_unit_price: u64,
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
@@ -35,6 +333,25 @@ pub struct Market {
     pub market_account: String,
     pub coin_wallet: String,
     pub pc_wallet: String,
+    /// overrides priority_fee.compute_unit_limit for this market
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compute_unit_limit: Option<u32>,
+    /// overrides priority_fee.compute_unit_price for this market
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compute_unit_price: Option<u64>,
+    /// overrides crank.num_accounts for this market
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_accounts: Option<usize>,
+    /// overrides crank.events_per_worker for this market
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub events_per_worker: Option<usize>,
+    /// overrides crank.max_wait_for_events_delay, the time in seconds
+    /// between checks of this market's queues
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_wait_for_events_delay: Option<u64>,
+    /// overrides crank.min_crank_interval for this market
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_crank_interval: Option<u64>,
 }
 
 #[derive(Clone, Default, Debug, PartialEq)]
@@ -42,122 +359,389 @@ pub struct ParsedMarketKeys {
     pub keys: MarketPubkeys,
     pub coin_wallet: Pubkey,
     pub pc_wallet: Pubkey,
+    pub compute_unit_limit: Option<u32>,
+    pub compute_unit_price: Option<u64>,
+    pub num_accounts: Option<usize>,
+    pub events_per_worker: Option<usize>,
+    pub max_wait_for_events_delay: Option<u64>,
+    pub min_crank_interval: Option<u64>,
 }
 
 impl Crank {
     pub fn market_keys(
         &self,
-        rpc: &Arc<RpcClient>,
+        rpc: &RpcClient,
         program_id: Pubkey,
     ) -> Result<Vec<ParsedMarketKeys>> {
         let mut markets = vec![];
         for market in self.markets.iter() {
-            let market_keys = get_keys_for_market(
-                &rpc,
-                &program_id,
-                &Pubkey::from_str(market.market_account.as_str()).unwrap(),
-            )?;
-            markets.push(ParsedMarketKeys {
-                keys: market_keys,
-                coin_wallet: Pubkey::from_str(market.coin_wallet.as_str()).unwrap(),
-                pc_wallet: Pubkey::from_str(market.pc_wallet.as_str()).unwrap(),
-            })
+            markets.push(market.parsed_keys(rpc, program_id)?);
         }
         Ok(markets)
     }
+    /// adds every problem preventing the crank settings from being used to problems
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        check(problems, parse_pubkey("dex_program", &self.dex_program));
+        if let Some(discovery) = self.discovery.as_ref() {
+            discovery.validate(problems);
+        } else if self.markets.is_empty() {
+            problems.push("no markets configured and discovery is disabled".to_string());
+        }
+        let mut market_accounts = HashSet::with_capacity(self.markets.len());
+        for market in self.markets.iter() {
+            market.validate(problems);
+            if !market_accounts.insert(market.market_account.as_str()) {
+                problems.push(format!(
+                    "market {} is configured more than once",
+                    market.market_account
+                ));
+            }
+        }
+        if self.max_wait_for_events_delay == 0 {
+            problems.push("max_wait_for_events_delay must be greater than 0".to_string());
+        }
+        check(problems, validate_num_accounts(self.num_accounts));
+        check(problems, validate_events_per_worker(self.events_per_worker));
+        if self.worker_threads == 0 {
+            problems.push("worker_threads must be greater than 0".to_string());
+        }
+        if self.max_markets_per_tx == Some(0) {
+            problems.push("max_markets_per_tx must be greater than 0".to_string());
+        }
+        if self.max_accounts_per_tx == 0 || self.max_accounts_per_tx > MAX_TRANSACTION_ACCOUNTS {
+            problems.push(format!(
+                "max_accounts_per_tx must be between 1 and {}",
+                MAX_TRANSACTION_ACCOUNTS
+            ));
+        }
+        if self.confirm_poll_interval_ms == 0 {
+            problems.push("confirm_poll_interval_ms must be greater than 0".to_string());
+        }
+        if self.resend_interval_ms == 0 {
+            problems.push("resend_interval_ms must be greater than 0".to_string());
+        }
+        self.priority_fee.validate(problems);
+    }
+}
+
+impl ParsedMarketKeys {
+    /// max number of open orders accounts passed to consume events for this market
+    pub fn num_accounts(&self, crank: &Crank) -> usize {
+        self.num_accounts.unwrap_or(crank.num_accounts)
+    }
+    /// max number of events consumed by a single crank of this market
+    pub fn events_per_worker(&self, crank: &Crank) -> usize {
+        self.events_per_worker.unwrap_or(crank.events_per_worker)
+    }
+    /// time between checks of this market's queues
+    pub fn poll_interval(&self, crank: &Crank) -> Duration {
+        Duration::from_secs(
+            self.max_wait_for_events_delay
+                .unwrap_or(crank.max_wait_for_events_delay),
+        )
+    }
+    /// minimum time between confirmed cranks of this market
+    pub fn min_crank_interval(&self, crank: &Crank) -> Duration {
+        Duration::from_secs(self.min_crank_interval.unwrap_or(crank.min_crank_interval))
+    }
+}
+
+impl RpcEndpoint {
+    /// returns the name of the endpoint, or the host of its url
+    pub fn label(&self) -> String {
+        if let Some(name) = self.name.as_ref() {
+            return name.clone();
+        }
+        match Url::parse(&self.url) {
+            Ok(url) => url.host_str().unwrap_or_default().to_string(),
+            Err(_) => redact_url(&self.url),
+        }
+    }
+}
+
+impl Webhook {
+    /// returns the name of the webhook, or the host of its url
+    pub fn label(&self) -> String {
+        if let Some(name) = self.name.as_ref() {
+            return name.clone();
+        }
+        match Url::parse(&self.url) {
+            Ok(url) => url.host_str().unwrap_or_default().to_string(),
+            Err(_) => REDACTED.to_string(),
+        }
+    }
+}
+
+impl Alerts {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        for webhook in self.webhooks.iter() {
+            if let Err(err) = Url::parse(&webhook.url) {
+                problems.push(format!(
+                    "invalid alert webhook {}: {}",
+                    webhook.label(),
+                    err
+                ));
+            }
+            if let Err(err) = alerts::Notification::test().render(webhook) {
+                problems.push(format!(
+                    "invalid template of alert webhook {}: {}",
+                    webhook.label(),
+                    err
+                ));
+            }
+        }
+        if self.max_queue_len > 0 && self.queue_len_iterations == 0 {
+            problems.push("alerts queue_len_iterations must be greater than 0".to_string());
+        }
+    }
+}
+
+impl RpcFailover {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.health_check_interval == 0 {
+            problems.push("rpc_failover health_check_interval must be greater than 0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.max_error_rate) {
+            problems.push("rpc_failover max_error_rate must be between 0 and 1".to_string());
+        }
+    }
+}
+
+impl Discovery {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.refresh_interval == 0 {
+            problems.push("discovery refresh_interval must be greater than 0".to_string());
+        }
+        for mint in self.include_mints.iter().chain(self.exclude_mints.iter()) {
+            check(problems, parse_pubkey("discovery mint", mint));
+        }
+        for wallet in self.coin_wallet.iter().chain(self.pc_wallet.iter()) {
+            check(problems, parse_pubkey("discovery wallet", wallet));
+        }
+    }
+}
+
+impl PriorityFee {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        check(
+            problems,
+            validate_compute_unit_limit(self.compute_unit_limit),
+        );
+        if self.dynamic_percentile > 100 {
+            problems.push("priority_fee dynamic_percentile must be at most 100".to_string());
+        }
+    }
+}
+
+impl Market {
+    /// resolves the market's keys, returning an error if any of the configured pubkeys are invalid
+    pub fn parsed_keys(&self, rpc: &RpcClient, program_id: Pubkey) -> Result<ParsedMarketKeys> {
+        let market_account = parse_pubkey("market_account", &self.market_account)?;
+        let coin_wallet = parse_pubkey("coin_wallet", &self.coin_wallet)?;
+        let pc_wallet = parse_pubkey("pc_wallet", &self.pc_wallet)?;
+        // keep the rpc error as the source, so the request can fail over to another endpoint
+        let market_keys = get_keys_for_market(rpc, &program_id, &market_account)
+            .with_context(|| format!("failed to load market {}", self.name))?;
+        Ok(ParsedMarketKeys {
+            keys: market_keys,
+            coin_wallet,
+            pc_wallet,
+            compute_unit_limit: self.compute_unit_limit,
+            compute_unit_price: self.compute_unit_price,
+            num_accounts: self.num_accounts,
+            events_per_worker: self.events_per_worker,
+            max_wait_for_events_delay: self.max_wait_for_events_delay,
+            min_crank_interval: self.min_crank_interval,
+        })
+    }
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        let results = vec![
+            parse_pubkey("market_account", &self.market_account).map(|_| ()),
+            parse_pubkey("coin_wallet", &self.coin_wallet).map(|_| ()),
+            parse_pubkey("pc_wallet", &self.pc_wallet).map(|_| ()),
+            self.num_accounts.map_or(Ok(()), validate_num_accounts),
+            self.events_per_worker
+                .map_or(Ok(()), validate_events_per_worker),
+            validate_compute_unit_limit(self.compute_unit_limit),
+        ];
+        for err in results.into_iter().filter_map(Result::err) {
+            problems.push(format!("invalid market {}: {}", self.name, err));
+        }
+    }
 }
 
 impl Configuration {
-    pub fn new(path: &str, as_json: bool) -> Result<()> {
+    pub fn new(path: &str) -> Result<()> {
         let config = Configuration::default();
-        config.save(path, as_json)
+        config.save(path)
     }
-    pub fn save(&self, path: &str, as_json: bool) -> Result<()> {
-        let data = if as_json {
-            serde_json::to_string_pretty(&self)?
-        } else {
-            serde_yaml::to_string(&self)?
-        };
-        fs::write(path, data).expect("failed to write to file");
+    /// saves the configuration as json when the path has a .json extension, and as yaml otherwise
+    pub fn save(&self, path: &str) -> Result<()> {
+        let data = self.render(is_json(path))?;
+        fs::write(path, data).map_err(|err| anyhow!("failed to write {}: {}", path, err))?;
         Ok(())
     }
-    pub fn load(path: &str, from_json: bool) -> Result<Configuration> {
-        let data = fs::read(path).expect("failed to read file");
-        let config: Configuration = if from_json {
+    /// loads the configuration as json when the path has a .json extension, and as yaml otherwise
+    pub fn load(path: &str) -> Result<Configuration> {
+        let data = fs::read(path).map_err(|err| anyhow!("failed to read {}: {}", path, err))?;
+        let config: Configuration = if is_json(path) {
             serde_json::from_slice(data.as_slice())?
         } else {
             serde_yaml::from_slice(data.as_slice())?
         };
         Ok(config)
     }
-    pub fn payer(&self) -> Keypair {
-        read_keypair_file(self.key_path.clone()).expect("failed to read keypair file")
+    /// checks that the configuration can be used to turn the crank
+    pub fn validate(&self) -> Result<()> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!("invalid configuration: {}", problems.join(", ")))
     }
-    /// if file_log is true, log to both file and stdout
-    /// otherwise just log to stdout
-    pub fn init_log(&self, file_log: bool) -> Result<()> {
-        if !file_log {
-            if self.debug_log {
-                TermLogger::init(
-                    LevelFilter::Debug,
-                    ConfigBuilder::new()
-                        .set_location_level(LevelFilter::Debug)
-                        .build(),
-                    TerminalMode::Mixed,
-                    ColorChoice::Auto,
-                )?;
-                return Ok(());
-            } else {
-                TermLogger::init(
-                    LevelFilter::Info,
-                    ConfigBuilder::new()
-                        .set_location_level(LevelFilter::Error)
-                        .build(),
-                    TerminalMode::Mixed,
-                    ColorChoice::Auto,
-                )?;
-                return Ok(());
+    /// returns every problem with the configuration which can be found without
+    /// querying the rpc node
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for endpoint in self.rpc_endpoints().iter() {
+            if let Err(err) = Url::parse(&endpoint.url) {
+                problems.push(format!(
+                    "invalid rpc endpoint {}: {}",
+                    endpoint.label(),
+                    err
+                ));
             }
         }
-        if self.debug_log {
-            CombinedLogger::init(vec![
-                TermLogger::new(
-                    LevelFilter::Debug,
-                    ConfigBuilder::new()
-                        .set_location_level(LevelFilter::Debug)
-                        .build(),
-                    TerminalMode::Mixed,
-                    ColorChoice::Auto,
-                ),
-                WriteLogger::new(
-                    LevelFilter::Debug,
-                    ConfigBuilder::new()
-                        .set_location_level(LevelFilter::Debug)
-                        .build(),
-                    File::create(self.log_file.as_str()).unwrap(),
-                ),
-            ])?;
+        if self.crank.use_ws_subscriptions {
+            if let Err(err) = Url::parse(&self.ws_rpc_url) {
+                problems.push(format!(
+                    "invalid ws_rpc_url {}: {}",
+                    redact_url(&self.ws_rpc_url),
+                    err
+                ));
+            }
+        }
+        self.rpc_failover.validate(&mut problems);
+        if self.payer_balance.critical_balance > self.payer_balance.warning_balance {
+            problems.push(
+                "payer_balance critical_balance must not be greater than warning_balance"
+                    .to_string(),
+            );
+        }
+        if self.health_check.max_iteration_delay_multiple == 0 {
+            problems.push(
+                "health_check max_iteration_delay_multiple must be greater than 0".to_string(),
+            );
+        }
+        self.alerts.validate(&mut problems);
+        self.crank.validate(&mut problems);
+        problems
+    }
+    /// returns a copy of the configuration safe to print, with the credentials, path
+    /// and query of the rpc and webhook urls, which often contain api keys, redacted
+    pub fn redacted(&self) -> Configuration {
+        let mut config = self.clone();
+        config.http_rpc_url = redact_url(&config.http_rpc_url);
+        config.ws_rpc_url = redact_url(&config.ws_rpc_url);
+        for endpoint in config.rpc_endpoints.iter_mut() {
+            endpoint.url = redact_url(&endpoint.url);
+        }
+        for webhook in config.alerts.webhooks.iter_mut() {
+            webhook.url = redact_url(&webhook.url);
+        }
+        config
+    }
+    /// serializes the configuration as json or yaml
+    pub fn render(&self, as_json: bool) -> Result<String> {
+        if as_json {
+            Ok(serde_json::to_string_pretty(&self)?)
         } else {
-            CombinedLogger::init(vec![
-                TermLogger::new(
-                    LevelFilter::Info,
-                    ConfigBuilder::new()
-                        .set_location_level(LevelFilter::Error)
-                        .build(),
-                    TerminalMode::Mixed,
-                    ColorChoice::Auto,
-                ),
+            Ok(serde_yaml::to_string(&self)?)
+        }
+    }
+    /// returns http_rpc_url followed by the additional rpc endpoints
+    pub fn rpc_endpoints(&self) -> Vec<RpcEndpoint> {
+        let mut endpoints = Vec::with_capacity(self.rpc_endpoints.len() + 1);
+        endpoints.push(RpcEndpoint {
+            url: self.http_rpc_url.clone(),
+            name: None,
+            role: RpcRole::Both,
+        });
+        endpoints.extend(self.rpc_endpoints.iter().cloned());
+        endpoints
+    }
+    /// reads the payer keypairs, in the order they are configured. keypair
+    /// files in a directory are read in the order of their file names
+    pub fn payers(&self) -> Result<Vec<Keypair>> {
+        let sources = match &self.key_path {
+            KeyPath::Single(source) => vec![source.clone()],
+            KeyPath::Multiple(sources) => sources.clone(),
+        };
+        let mut loader = KeyLoader::default();
+        let mut payers: Vec<Keypair> = Vec::with_capacity(sources.len());
+        for source in sources.iter() {
+            for payer in loader.load(source)? {
+                if payers.iter().any(|known| known.pubkey() == payer.pubkey()) {
+                    continue;
+                }
+                payers.push(payer);
+            }
+        }
+        if payers.is_empty() {
+            return Err(anyhow!("key_path does not contain any keypair files"));
+        }
+        Ok(payers)
+    }
+    /// if file_log is true, log to both log_file and stdout, otherwise just
+    /// log to stdout. returns the log file, so it can be reopened on SIGUSR1
+    pub fn init_log(&self, file_log: bool) -> Result<Option<LogFile>> {
+        let level = if self.debug_log {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        };
+        let log_file = if file_log {
+            let log_file = LogFile::open(self.log_file.as_str(), self.log_rotation.clone())
+                .map_err(|err| anyhow!("failed to open log file {}: {}", self.log_file, err))?;
+            Some(log_file)
+        } else {
+            None
+        };
+        if self.log_format == LogFormat::Json {
+            let mut outputs: Vec<Box<dyn Write + Send>> = vec![Box::new(io::stdout())];
+            if let Some(log_file) = log_file.as_ref() {
+                outputs.push(Box::new(log_file.clone()));
+            }
+            JsonLogger::init(level, outputs)?;
+            return Ok(log_file);
+        }
+        let location_level = if self.debug_log {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Error
+        };
+        let term_logger = TermLogger::new(
+            level,
+            ConfigBuilder::new()
+                .set_location_level(location_level)
+                .build(),
+            TerminalMode::Mixed,
+            ColorChoice::Auto,
+        );
+        match log_file.as_ref() {
+            Some(log_file) => CombinedLogger::init(vec![
+                term_logger,
                 WriteLogger::new(
-                    LevelFilter::Info,
+                    level,
                     ConfigBuilder::new()
-                        .set_location_level(LevelFilter::Error)
+                        .set_location_level(location_level)
                         .build(),
-                    File::create(self.log_file.as_str()).unwrap(),
+                    log_file.clone(),
                 ),
-            ])?;
+            ])?,
+            None => CombinedLogger::init(vec![term_logger])?,
         }
-
-        Ok(())
+        Ok(log_file)
     }
 }
 
@@ -166,9 +750,19 @@ impl Default for Configuration {
         Self {
             http_rpc_url: "https://api.devnet.solana.com".to_string(),
             ws_rpc_url: "ws://api.devnet.solana.com".to_string(),
-            key_path: "~/.config/solana/id.json".to_string(),
-            log_file: "cranker.log".to_string(),
+            rpc_endpoints: vec![],
+            rpc_failover: RpcFailover::default(),
+            key_path: KeyPath::Single("~/.config/solana/id.json".to_string()),
+            log_file: String::new(),
             debug_log: false,
+            log_format: LogFormat::default(),
+            log_rotation: LogRotation::default(),
+            http_listen_addr: None,
+            health_check: HealthCheck::default(),
+            payer_balance: PayerBalance::default(),
+            cost_accounting: CostAccounting::default(),
+            history: History::default(),
+            alerts: Alerts::default(),
             crank: Crank::default(),
         }
     }
@@ -183,11 +777,358 @@ impl Default for Crank {
                 market_account: "somekey".to_string(),
                 coin_wallet: "somewallet".to_string(),
                 pc_wallet: "some_pc_wallet".to_string(),
+                ..Market::default()
             }],
             max_wait_for_events_delay: 60,
             num_accounts: 32,
             events_per_worker: 5,
-            max_markets_per_tx: 6,
+            max_markets_per_tx: None,
+            max_accounts_per_tx: default_max_accounts_per_tx(),
+            use_ws_subscriptions: false,
+            ws_debounce_ms: default_ws_debounce_ms(),
+            confirm_poll_interval_ms: default_confirm_poll_interval_ms(),
+            resend_interval_ms: default_resend_interval_ms(),
+            priority_fee: PriorityFee::default(),
+            discovery: None,
+            min_crank_interval: 0,
+            worker_threads: default_worker_threads(),
+        }
+    }
+}
+
+impl Default for PayerBalance {
+    fn default() -> Self {
+        Self {
+            warning_balance: default_warning_balance(),
+            critical_balance: default_critical_balance(),
+        }
+    }
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            max_iteration_delay_multiple: 5,
+            min_payer_balance: 0,
+        }
+    }
+}
+
+impl Default for CostAccounting {
+    fn default() -> Self {
+        Self {
+            ledger_file: String::new(),
+            apportion: Apportion::default(),
+        }
+    }
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Self {
+            webhooks: vec![],
+            max_send_failures: default_max_send_failures(),
+            max_queue_len: 0,
+            queue_len_iterations: default_queue_len_iterations(),
+            payer_balance_low: default_alert_payer_balance_low(),
+            rpc_unreachable_iterations: default_rpc_unreachable_iterations(),
+            repeat_interval: default_alert_repeat_interval(),
+        }
+    }
+}
+
+impl Default for WebhookFormat {
+    fn default() -> Self {
+        WebhookFormat::Generic
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            database: String::new(),
+            retention_days: default_history_retention_days(),
+        }
+    }
+}
+
+impl Default for Apportion {
+    fn default() -> Self {
+        Apportion::ComputeUnits
+    }
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_size: default_log_max_size(),
+            max_age_hours: 0,
+            retain: default_log_retain(),
+            compress: false,
+        }
+    }
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl Default for RpcRole {
+    fn default() -> Self {
+        RpcRole::Both
+    }
+}
+
+impl Default for RpcFailover {
+    fn default() -> Self {
+        Self {
+            health_check_interval: default_health_check_interval(),
+            max_error_rate: default_max_error_rate(),
+            max_slot_lag: default_max_slot_lag(),
+            fan_out_sends: false,
+        }
+    }
+}
+
+impl Default for PriorityFee {
+    fn default() -> Self {
+        Self {
+            compute_unit_limit: None,
+            compute_unit_price: None,
+            dynamic: false,
+            dynamic_percentile: default_dynamic_percentile(),
+            max_compute_unit_price: default_max_compute_unit_price(),
+        }
+    }
+}
+
+fn default_ws_debounce_ms() -> u64 {
+    500
+}
+
+fn default_max_accounts_per_tx() -> usize {
+    64
+}
+
+fn default_confirm_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_resend_interval_ms() -> u64 {
+    2000
+}
+
+fn default_dynamic_percentile() -> u8 {
+    75
+}
+
+fn default_max_compute_unit_price() -> u64 {
+    100_000
+}
+
+fn default_worker_threads() -> usize {
+    16
+}
+
+fn default_warning_balance() -> u64 {
+    // 1 SOL
+    1_000_000_000
+}
+
+fn default_critical_balance() -> u64 {
+    // 0.01 SOL
+    10_000_000
+}
+
+fn default_max_send_failures() -> u64 {
+    3
+}
+
+fn default_queue_len_iterations() -> u64 {
+    5
+}
+
+fn default_alert_payer_balance_low() -> bool {
+    true
+}
+
+fn default_rpc_unreachable_iterations() -> u64 {
+    3
+}
+
+fn default_alert_repeat_interval() -> u64 {
+    // 1 hour
+    3600
+}
+
+fn default_history_retention_days() -> u64 {
+    30
+}
+
+fn default_log_max_size() -> u64 {
+    // 100 MiB
+    100 * 1024 * 1024
+}
+
+fn default_log_retain() -> usize {
+    5
+}
+
+fn default_health_check_interval() -> u64 {
+    10
+}
+
+fn default_max_error_rate() -> f64 {
+    0.5
+}
+
+fn default_max_slot_lag() -> u64 {
+    50
+}
+
+/// keeps the scheme, host and port of the url, redacting the rest
+fn redact_url(url: &str) -> String {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return REDACTED.to_string(),
+    };
+    if parsed.username().is_empty()
+        && parsed.password().is_none()
+        && matches!(parsed.path(), "" | "/")
+        && parsed.query().is_none()
+    {
+        return url.to_string();
+    }
+    let port = parsed
+        .port()
+        .map(|port| format!(":{}", port))
+        .unwrap_or_default();
+    format!(
+        "{}://{}{}/{}",
+        parsed.scheme(),
+        parsed.host_str().unwrap_or_default(),
+        port,
+        REDACTED
+    )
+}
+
+/// returns true when the path has a .json extension
+fn is_json(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("json"))
+}
+
+/// adds the error of the check, if it failed, to problems
+fn check<T>(problems: &mut Vec<String>, res: Result<T>) {
+    if let Err(err) = res {
+        problems.push(err.to_string());
+    }
+}
+
+fn parse_pubkey(field: &str, value: &str) -> Result<Pubkey> {
+    Pubkey::from_str(value).map_err(|err| anyhow!("invalid {} {}: {}", field, value, err))
+}
+
+fn validate_num_accounts(num_accounts: usize) -> Result<()> {
+    if num_accounts == 0 {
+        return Err(anyhow!("num_accounts must be greater than 0"));
+    }
+    Ok(())
+}
+
+fn validate_events_per_worker(events_per_worker: usize) -> Result<()> {
+    if events_per_worker == 0 || events_per_worker > u16::MAX as usize {
+        return Err(anyhow!(
+            "events_per_worker must be between 1 and {}",
+            u16::MAX
+        ));
+    }
+    Ok(())
+}
+
+fn validate_compute_unit_limit(compute_unit_limit: Option<u32>) -> Result<()> {
+    if compute_unit_limit.map_or(false, |limit| limit == 0 || limit > MAX_COMPUTE_UNIT_LIMIT) {
+        return Err(anyhow!(
+            "compute_unit_limit must be between 1 and {}",
+            MAX_COMPUTE_UNIT_LIMIT
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the default configuration with valid market pubkeys
+    fn valid_config() -> Configuration {
+        let mut config = Configuration::default();
+        for market in config.crank.markets.iter_mut() {
+            market.market_account = Pubkey::new_unique().to_string();
+            market.coin_wallet = Pubkey::new_unique().to_string();
+            market.pc_wallet = Pubkey::new_unique().to_string();
+        }
+        config
+    }
+
+    #[test]
+    fn config_format_follows_the_extension() {
+        assert!(is_json("config.json"));
+        assert!(is_json("/etc/crank/config.JSON"));
+        assert!(!is_json("config.yaml"));
+        assert!(!is_json("config.yml"));
+        assert!(!is_json("json"));
+    }
+
+    #[test]
+    fn configurations_round_trip_as_json_and_yaml() {
+        let config = valid_config();
+        for name in ["config.json", "config.yaml"].iter() {
+            let path = std::env::temp_dir()
+                .join(format!("crank-config-test-{}-{}", std::process::id(), name))
+                .to_string_lossy()
+                .into_owned();
+            config.save(&path).unwrap();
+            let data = fs::read_to_string(&path).unwrap();
+            let loaded = Configuration::load(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(data.starts_with('{'), is_json(&path));
+            assert_eq!(loaded.unwrap(), config);
+        }
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        assert!(valid_config().problems().is_empty());
+        assert!(valid_config().validate().is_ok());
+        let mut config = Configuration {
+            http_rpc_url: "https://rpc.example.com:port/?api-key=secret".to_string(),
+            ..Configuration::default()
+        };
+        config.crank.max_wait_for_events_delay = 0;
+        config.crank.markets.push(config.crank.markets[0].clone());
+        let problems = config.problems();
+        for problem in [
+            "invalid rpc endpoint REDACTED",
+            "invalid market TULIP-USDC: invalid market_account somekey",
+            "invalid market TULIP-USDC: invalid coin_wallet somewallet",
+            "market somekey is configured more than once",
+            "max_wait_for_events_delay must be greater than 0",
+        ]
+        .iter()
+        {
+            assert!(
+                problems.iter().any(|found| found.starts_with(problem)),
+                "{} not in {:?}",
+                problem,
+                problems
+            );
         }
+        assert!(config.validate().is_err());
+        // urls often contain api keys, so invalid urls are redacted as well
+        assert!(problems.iter().all(|problem| !problem.contains("secret")));
     }
 }
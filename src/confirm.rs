@@ -0,0 +1,449 @@
+use crate::rpc::RpcPool;
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{select, Receiver};
+use log::{error, info, warn};
+use solana_client::rpc_config::RpcSendTransactionConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_sdk::transaction::{Transaction, TransactionError};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// max number of signatures accepted by a single getSignatureStatuses request
+const MAX_SIGNATURE_STATUSES: usize = 256;
+/// give up on a transaction after this long, regardless of its blockhash
+pub const MAX_CONFIRMATION_TIME: Duration = Duration::from_secs(120);
+
+/// final outcome of a sent crank transaction
+#[derive(Clone, Debug, PartialEq)]
+pub enum CrankOutcome {
+    /// the transaction was confirmed in the given slot
+    Confirmed(u64),
+    /// the transaction landed but failed with the given error
+    Failed(TransactionError),
+    /// the blockhash of the transaction expired before it landed
+    Expired,
+}
+
+/// the status of the signature of a pending transaction
+#[derive(Clone, Debug, PartialEq)]
+enum SignatureStatus {
+    /// the cluster has no status for the transaction
+    Unknown,
+    /// the transaction landed but hasn't reached confirmed commitment yet
+    Processed,
+    Resolved(CrankOutcome),
+}
+
+/// a crank transaction which has been sent but not yet confirmed
+#[derive(Clone, Debug)]
+pub struct PendingTransaction {
+    pub signature: Signature,
+    pub transaction: Transaction,
+    pub markets: Vec<Pubkey>,
+    /// fee in lamports paid by the transaction if it lands
+    pub fee: u64,
+    last_sent: Instant,
+}
+
+/// the outcome of each crank transaction which was resolved, the rest are left
+/// unresolved when waiting for them was interrupted by the exit signal
+#[derive(Debug)]
+pub struct Confirmations {
+    pub outcomes: Vec<(PendingTransaction, CrankOutcome)>,
+    pub interrupted: bool,
+}
+
+/// polls the status of sent crank transactions, re-broadcasting
+/// them while their blockhash is valid until they land or expire
+pub struct ConfirmationTracker {
+    rpc: Arc<RpcPool>,
+    poll_interval: Duration,
+    resend_interval: Duration,
+}
+
+impl PendingTransaction {
+    pub fn new(
+        signature: Signature,
+        transaction: Transaction,
+        markets: Vec<Pubkey>,
+        fee: u64,
+    ) -> Self {
+        Self {
+            signature,
+            transaction,
+            markets,
+            fee,
+            last_sent: Instant::now(),
+        }
+    }
+    /// the payer of the transaction fee, which is always the first account
+    pub fn fee_payer(&self) -> &Pubkey {
+        &self.transaction.message.account_keys[0]
+    }
+}
+
+impl ConfirmationTracker {
+    pub fn new(rpc: Arc<RpcPool>, poll_interval: Duration, resend_interval: Duration) -> Self {
+        Self {
+            rpc,
+            poll_interval,
+            resend_interval,
+        }
+    }
+    /// blocks until every pending transaction has either been confirmed,
+    /// failed, or expired, returning the outcome of each transaction.
+    /// stops waiting as soon as the exit signal is received
+    pub fn confirm(
+        &self,
+        mut pending: Vec<PendingTransaction>,
+        exit_chan: &Receiver<bool>,
+    ) -> Confirmations {
+        let started = Instant::now();
+        let mut outcomes = Vec::with_capacity(pending.len());
+        while !pending.is_empty() {
+            select! {
+                recv(exit_chan) -> _msg => {
+                    warn!(
+                        "caught exit signal, not waiting for {} crank transactions to confirm",
+                        pending.len()
+                    );
+                    return Confirmations {
+                        outcomes,
+                        interrupted: true,
+                    };
+                },
+                default(self.poll_interval) => {}
+            }
+            let statuses = match self.signature_statuses(&pending) {
+                Ok(statuses) => statuses,
+                Err(err) => {
+                    error!("failed to retrieve signature statuses {:#?}", err);
+                    // without statuses, transactions which landed can't be told apart
+                    // from those which didn't, so they are only given up on after
+                    // MAX_CONFIRMATION_TIME
+                    if started.elapsed() < MAX_CONFIRMATION_TIME {
+                        continue;
+                    }
+                    vec![SignatureStatus::Unknown; pending.len()]
+                }
+            };
+            // cache blockhash validity so each blockhash is checked at most once per poll
+            let mut valid_blockhashes: HashMap<Hash, bool> = HashMap::new();
+            let mut still_pending = Vec::with_capacity(pending.len());
+            for (mut pending_tx, status) in pending.into_iter().zip(statuses.into_iter()) {
+                if let SignatureStatus::Resolved(outcome) = status {
+                    outcomes.push((pending_tx, outcome));
+                    continue;
+                }
+                if started.elapsed() >= MAX_CONFIRMATION_TIME {
+                    warn!(
+                        "transaction {} not confirmed after {:?}, giving up",
+                        pending_tx.signature, MAX_CONFIRMATION_TIME
+                    );
+                    outcomes.push((pending_tx, CrankOutcome::Expired));
+                    continue;
+                }
+                // a transaction which landed is about to confirm, even once its blockhash
+                // expires, and doesn't need to be re-broadcast
+                if status == SignatureStatus::Processed {
+                    still_pending.push(pending_tx);
+                    continue;
+                }
+                let blockhash = pending_tx.transaction.message.recent_blockhash;
+                let blockhash_valid = *valid_blockhashes
+                    .entry(blockhash)
+                    .or_insert_with(|| self.is_blockhash_valid(&blockhash));
+                if !blockhash_valid {
+                    outcomes.push((pending_tx, CrankOutcome::Expired));
+                    continue;
+                }
+                if pending_tx.last_sent.elapsed() >= self.resend_interval {
+                    info!("re-broadcasting crank transaction {}", pending_tx.signature);
+                    let res = self.rpc.send_transaction(
+                        &pending_tx.transaction,
+                        RpcSendTransactionConfig {
+                            skip_preflight: true,
+                            ..RpcSendTransactionConfig::default()
+                        },
+                    );
+                    if res.is_err() {
+                        warn!(
+                            "failed to re-broadcast crank transaction {}: {:#?}",
+                            pending_tx.signature,
+                            res.err()
+                        );
+                    }
+                    pending_tx.last_sent = Instant::now();
+                }
+                still_pending.push(pending_tx);
+            }
+            pending = still_pending;
+        }
+        Confirmations {
+            outcomes,
+            interrupted: false,
+        }
+    }
+    /// returns the status of each pending transaction, which is resolved once
+    /// the transaction failed or reached confirmed commitment
+    fn signature_statuses(&self, pending: &[PendingTransaction]) -> Result<Vec<SignatureStatus>> {
+        let signatures: Vec<Signature> = pending.iter().map(|tx| tx.signature).collect();
+        let mut outcomes = Vec::with_capacity(signatures.len());
+        for chunk in signatures.chunks(MAX_SIGNATURE_STATUSES) {
+            let statuses = self
+                .rpc
+                .read(|client| client.get_signature_statuses(chunk))?
+                .value;
+            // a short answer would shift the statuses onto the wrong transactions
+            if statuses.len() != chunk.len() {
+                return Err(anyhow!(
+                    "requested {} signature statuses, received {}",
+                    chunk.len(),
+                    statuses.len()
+                ));
+            }
+            for status in statuses {
+                let status = match status {
+                    Some(status) => match status.err {
+                        Some(err) => SignatureStatus::Resolved(CrankOutcome::Failed(err)),
+                        None if status.satisfies_commitment(CommitmentConfig::confirmed()) => {
+                            SignatureStatus::Resolved(CrankOutcome::Confirmed(status.slot))
+                        }
+                        None => SignatureStatus::Processed,
+                    },
+                    None => SignatureStatus::Unknown,
+                };
+                outcomes.push(status);
+            }
+        }
+        Ok(outcomes)
+    }
+    /// a blockhash is valid as long as the cluster still returns its fee calculator,
+    /// rpc errors are treated as valid so that transient failures don't expire transactions
+    fn is_blockhash_valid(&self, blockhash: &Hash) -> bool {
+        match self
+            .rpc
+            .read(|client| client.get_fee_calculator_for_blockhash(blockhash))
+        {
+            Ok(fee_calculator) => fee_calculator.is_some(),
+            Err(err) => {
+                warn!("failed to check blockhash validity {:#?}", err);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RpcEndpoint, RpcFailover, RpcRole};
+    use crate::metrics::Metrics;
+    use crate::rpc::mock::mock_endpoint;
+    use serde_json::{json, Value};
+    use solana_sdk::signature::{Keypair, Signer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// the answers of the mock rpc endpoint
+    #[derive(Default)]
+    struct MockCluster {
+        /// status of every signature, by the number of getSignatureStatuses requests seen
+        status: Option<Box<dyn Fn(usize) -> Value + Send + Sync>>,
+        blockhash_valid: bool,
+        /// answer getSignatureStatuses with one status less than requested
+        short_statuses: bool,
+        status_requests: AtomicUsize,
+        sends: AtomicUsize,
+    }
+
+    impl MockCluster {
+        fn answer(&self, method: &str, params: &Value) -> Value {
+            match method {
+                "getSignatureStatuses" => {
+                    let request = self.status_requests.fetch_add(1, Ordering::SeqCst);
+                    let status = self
+                        .status
+                        .as_ref()
+                        .map_or(Value::Null, |status| status(request));
+                    let mut statuses = vec![status; params[0].as_array().unwrap().len()];
+                    if self.short_statuses {
+                        statuses.pop();
+                    }
+                    json!({"context": {"slot": 1}, "value": statuses})
+                }
+                "getFeeCalculatorForBlockhash" => {
+                    let value = if self.blockhash_valid {
+                        json!({"feeCalculator": {"lamportsPerSignature": 5000}})
+                    } else {
+                        Value::Null
+                    };
+                    json!({"context": {"slot": 1}, "value": value})
+                }
+                "sendTransaction" => {
+                    self.sends.fetch_add(1, Ordering::SeqCst);
+                    // the signature follows the one byte signature count
+                    let tx = base64::decode(params[0].as_str().unwrap()).unwrap();
+                    json!(bs58::encode(&tx[1..65]).into_string())
+                }
+                method => panic!("unexpected request {}", method),
+            }
+        }
+    }
+
+    fn tracker(cluster: &Arc<MockCluster>, resend_interval: Duration) -> ConfirmationTracker {
+        let cluster = Arc::clone(cluster);
+        let rpc = RpcPool::new(
+            vec![RpcEndpoint {
+                url: mock_endpoint(move |method, params| cluster.answer(method, params)),
+                name: None,
+                role: RpcRole::Both,
+            }],
+            RpcFailover::default(),
+            Arc::new(Metrics::new().unwrap()),
+        );
+        ConfirmationTracker::new(Arc::new(rpc), Duration::from_millis(1), resend_interval)
+    }
+
+    fn pending_tx() -> PendingTransaction {
+        let payer = Keypair::new();
+        let transaction = Transaction::new_signed_with_payer(
+            &[],
+            Some(&payer.pubkey()),
+            &[&payer],
+            Hash::default(),
+        );
+        PendingTransaction::new(
+            transaction.signatures[0],
+            transaction,
+            vec![Pubkey::new_unique()],
+            5000,
+        )
+    }
+
+    fn status(confirmation_status: &str, err: Value) -> Value {
+        let status = if err.is_null() {
+            json!({"Ok": null})
+        } else {
+            json!({ "Err": err })
+        };
+        json!({
+            "slot": 42,
+            "confirmations": 0,
+            "status": status,
+            "err": err,
+            "confirmationStatus": confirmation_status,
+        })
+    }
+
+    fn outcomes(confirmations: Confirmations) -> Vec<CrankOutcome> {
+        assert!(!confirmations.interrupted);
+        confirmations
+            .outcomes
+            .into_iter()
+            .map(|(_, outcome)| outcome)
+            .collect()
+    }
+
+    #[test]
+    fn confirmed_and_failed_transactions_resolve() {
+        let (_exit_sender, exit) = crossbeam_channel::unbounded();
+        let cluster = Arc::new(MockCluster {
+            status: Some(Box::new(|_| status("confirmed", Value::Null))),
+            ..MockCluster::default()
+        });
+        let confirmations =
+            tracker(&cluster, Duration::from_secs(60)).confirm(vec![pending_tx()], &exit);
+        assert_eq!(outcomes(confirmations), vec![CrankOutcome::Confirmed(42)]);
+        let cluster = Arc::new(MockCluster {
+            status: Some(Box::new(|_| status("processed", json!("AccountInUse")))),
+            ..MockCluster::default()
+        });
+        let confirmations =
+            tracker(&cluster, Duration::from_secs(60)).confirm(vec![pending_tx()], &exit);
+        assert_eq!(
+            outcomes(confirmations),
+            vec![CrankOutcome::Failed(TransactionError::AccountInUse)]
+        );
+    }
+
+    #[test]
+    fn transactions_without_status_expire_with_their_blockhash() {
+        let (_exit_sender, exit) = crossbeam_channel::unbounded();
+        let cluster = Arc::new(MockCluster::default());
+        let confirmations =
+            tracker(&cluster, Duration::from_secs(60)).confirm(vec![pending_tx()], &exit);
+        assert_eq!(outcomes(confirmations), vec![CrankOutcome::Expired]);
+    }
+
+    #[test]
+    fn short_signature_status_answers_are_rejected() {
+        let cluster = Arc::new(MockCluster {
+            status: Some(Box::new(|_| status("confirmed", Value::Null))),
+            short_statuses: true,
+            ..MockCluster::default()
+        });
+        let pending = vec![pending_tx(), pending_tx()];
+        assert!(tracker(&cluster, Duration::from_secs(60))
+            .signature_statuses(&pending)
+            .is_err());
+    }
+
+    #[test]
+    fn processed_transactions_stay_pending_after_their_blockhash_expires() {
+        let (_exit_sender, exit) = crossbeam_channel::unbounded();
+        let cluster = Arc::new(MockCluster {
+            status: Some(Box::new(|request| {
+                if request < 3 {
+                    status("processed", Value::Null)
+                } else {
+                    status("confirmed", Value::Null)
+                }
+            })),
+            ..MockCluster::default()
+        });
+        let confirmations =
+            tracker(&cluster, Duration::from_millis(0)).confirm(vec![pending_tx()], &exit);
+        assert_eq!(outcomes(confirmations), vec![CrankOutcome::Confirmed(42)]);
+        assert_eq!(cluster.status_requests.load(Ordering::SeqCst), 4);
+        // landed transactions aren't re-broadcast
+        assert_eq!(cluster.sends.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn unconfirmed_transactions_are_resent_while_their_blockhash_is_valid() {
+        let (_exit_sender, exit) = crossbeam_channel::unbounded();
+        let cluster = Arc::new(MockCluster {
+            status: Some(Box::new(|request| {
+                if request < 3 {
+                    Value::Null
+                } else {
+                    status("confirmed", Value::Null)
+                }
+            })),
+            blockhash_valid: true,
+            ..MockCluster::default()
+        });
+        let confirmations =
+            tracker(&cluster, Duration::from_millis(0)).confirm(vec![pending_tx()], &exit);
+        assert_eq!(outcomes(confirmations), vec![CrankOutcome::Confirmed(42)]);
+        assert_eq!(cluster.sends.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn exit_signal_interrupts_waiting() {
+        let (exit_sender, exit) = crossbeam_channel::unbounded();
+        let cluster = Arc::new(MockCluster {
+            blockhash_valid: true,
+            ..MockCluster::default()
+        });
+        exit_sender.send(true).unwrap();
+        let confirmations =
+            tracker(&cluster, Duration::from_secs(60)).confirm(vec![pending_tx()], &exit);
+        assert!(confirmations.interrupted);
+        assert!(confirmations.outcomes.is_empty());
+    }
+}
@@ -0,0 +1,495 @@
+use crate::config::Apportion;
+use crate::confirm::PendingTransaction;
+use crate::metrics::Metrics;
+use crate::rpc::RpcPool;
+use crate::simulate::compute_units_consumed;
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use solana_client::rpc_request::RpcRequest;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// the parts of a getTransaction response used to account for its cost
+#[derive(Clone, Debug, Deserialize)]
+struct ConfirmedTransaction {
+    meta: Option<TransactionMeta>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionMeta {
+    fee: u64,
+    #[serde(default)]
+    log_messages: Option<Vec<String>>,
+}
+
+/// fees and compute units spent cranking a market, or paid for by a payer
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Cost {
+    pub lamports: u64,
+    pub compute_units: u64,
+    /// number of landed crank transactions which included the market, or were paid for by the payer
+    pub transactions: u64,
+}
+
+/// running totals of the crank costs, keyed by market and payer pubkey
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CostTotals {
+    /// when the first cost was recorded, as an rfc3339 timestamp
+    #[serde(default)]
+    pub since: Option<String>,
+    #[serde(default)]
+    pub markets: BTreeMap<String, Cost>,
+    #[serde(default)]
+    pub payers: BTreeMap<String, Cost>,
+}
+
+/// the fee paid by a landed crank transaction, split between the markets it cranks
+#[derive(Clone, Debug)]
+pub struct TransactionCost {
+    pub fee: u64,
+    pub compute_units: u64,
+    pub markets: Vec<(Pubkey, Cost)>,
+}
+
+/// keeps the running cost totals in a json file, so they survive
+/// restarts and can be queried while the crank is running
+pub struct CostLedger {
+    path: String,
+    totals: CostTotals,
+    /// true when the totals changed since they were last saved
+    changed: bool,
+}
+
+impl CostLedger {
+    /// opens the ledger, continuing from the totals in the file if it exists
+    pub fn open(path: &str) -> Result<Self> {
+        let totals = if Path::new(path).exists() {
+            CostTotals::load(path)?
+        } else {
+            CostTotals::default()
+        };
+        Ok(Self {
+            path: path.to_string(),
+            totals,
+            changed: false,
+        })
+    }
+    pub fn record(&mut self, payer: &Pubkey, cost: &TransactionCost) {
+        if self.totals.since.is_none() {
+            self.totals.since = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+        }
+        for (market, market_cost) in cost.markets.iter() {
+            let total = self.totals.markets.entry(market.to_string()).or_default();
+            total.lamports += market_cost.lamports;
+            total.compute_units += market_cost.compute_units;
+            total.transactions += market_cost.transactions;
+        }
+        let total = self.totals.payers.entry(payer.to_string()).or_default();
+        total.lamports += cost.fee;
+        total.compute_units += cost.compute_units;
+        total.transactions += 1;
+        self.changed = true;
+    }
+    /// writes the totals to the file if they changed, replacing
+    /// it in one step so readers never see a partial file
+    pub fn save(&mut self) -> Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let tmp_path = format!("{}.tmp", self.path);
+        fs::write(&tmp_path, serde_json::to_string_pretty(&self.totals)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+impl CostTotals {
+    pub fn load(path: &str) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .map_err(|err| anyhow!("failed to read cost ledger {}: {}", path, err))?;
+        serde_json::from_str(&data)
+            .map_err(|err| anyhow!("failed to parse cost ledger {}: {}", path, err))
+    }
+    /// keeps only the costs of the given markets, dropping the payers
+    pub fn filter_markets(&mut self, markets: &[String]) {
+        self.markets.retain(|market, _| markets.contains(market));
+        self.payers.clear();
+    }
+    /// formats the totals as a table, naming the markets found in names
+    pub fn to_table(&self, names: &HashMap<String, String>) -> String {
+        let mut out = String::new();
+        if let Some(since) = self.since.as_ref() {
+            out.push_str(&format!("costs since {}\n\n", since));
+        }
+        out.push_str(&format!(
+            "{:<44} {:<16} {:>16} {:>14} {:>16}\n",
+            "market", "name", "lamports", "transactions", "compute units"
+        ));
+        for (market, cost) in self.markets.iter() {
+            out.push_str(&format!(
+                "{:<44} {:<16} {:>16} {:>14} {:>16}\n",
+                market,
+                names.get(market).map_or("", String::as_str),
+                cost.lamports,
+                cost.transactions,
+                cost.compute_units
+            ));
+        }
+        if self.payers.is_empty() {
+            return out;
+        }
+        out.push_str(&format!(
+            "\n{:<44} {:<16} {:>16} {:>14} {:>16}\n",
+            "payer", "", "lamports", "transactions", "compute units"
+        ));
+        for (payer, cost) in self.payers.iter() {
+            out.push_str(&format!(
+                "{:<44} {:<16} {:>16} {:>14} {:>16}\n",
+                payer, "", cost.lamports, cost.transactions, cost.compute_units
+            ));
+        }
+        out
+    }
+    /// formats the totals as csv with one row per market and payer
+    pub fn to_csv(&self, names: &HashMap<String, String>) -> String {
+        let mut out = String::from("kind,account,name,lamports,transactions,compute_units\n");
+        let rows = self
+            .markets
+            .iter()
+            .map(|(market, cost)| ("market", market, names.get(market), cost))
+            .chain(
+                self.payers
+                    .iter()
+                    .map(|(payer, cost)| ("payer", payer, None, cost)),
+            );
+        for (kind, account, name, cost) in rows {
+            out.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                kind,
+                account,
+                name.map_or_else(String::new, |name| csv_field(name)),
+                cost.lamports,
+                cost.transactions,
+                cost.compute_units
+            ));
+        }
+        out
+    }
+}
+
+/// reads the fee actually paid by a landed crank transaction and splits it between
+/// its markets, either evenly per crank instruction or by the compute units each
+/// market's instructions consumed. the estimated fee is split per instruction
+/// when the transaction can't be fetched
+pub fn transaction_cost(
+    rpc: &RpcPool,
+    metrics: &Metrics,
+    dex_program: &Pubkey,
+    apportion: Apportion,
+    pending_tx: &PendingTransaction,
+) -> TransactionCost {
+    let (fee, logs) = match get_transaction_meta(rpc, metrics, &pending_tx.signature) {
+        Ok(meta) => (meta.fee, meta.log_messages.unwrap_or_default()),
+        Err(err) => {
+            warn!(
+                "failed to fetch crank transaction {}, using the estimated fee of {} lamports: {:#?}",
+                pending_tx.signature, pending_tx.fee, err
+            );
+            (pending_tx.fee, vec![])
+        }
+    };
+    split_fee(fee, &logs, dex_program, apportion, pending_tx)
+}
+
+/// splits the fee between the markets of the transaction, by the compute units
+/// the logs show each market's instructions consumed when apportioning by them
+fn split_fee(
+    fee: u64,
+    logs: &[String],
+    dex_program: &Pubkey,
+    apportion: Apportion,
+    pending_tx: &PendingTransaction,
+) -> TransactionCost {
+    // the market cranked by each dex instruction, in the order they were invoked
+    let message = &pending_tx.transaction.message;
+    let ix_markets: Vec<Option<Pubkey>> = message
+        .instructions
+        .iter()
+        .filter(|ix| ix.program_id(&message.account_keys) == dex_program)
+        .map(|ix| {
+            ix.accounts
+                .iter()
+                .map(|idx| message.account_keys[*idx as usize])
+                .find(|key| pending_tx.markets.contains(key))
+        })
+        .collect();
+    let units = compute_units_consumed(logs, dex_program);
+    let mut market_units = vec![0_u64; pending_tx.markets.len()];
+    let mut market_ixs = vec![0_u64; pending_tx.markets.len()];
+    for (idx, market) in ix_markets.iter().enumerate() {
+        let market_idx =
+            market.and_then(|market| pending_tx.markets.iter().position(|key| *key == market));
+        let market_idx = match market_idx {
+            Some(market_idx) => market_idx,
+            None => continue,
+        };
+        market_ixs[market_idx] += 1;
+        market_units[market_idx] += units.get(idx).copied().unwrap_or(0);
+    }
+    let compute_units: u64 = market_units.iter().sum();
+    let weights = if apportion == Apportion::ComputeUnits && compute_units > 0 {
+        &market_units
+    } else {
+        &market_ixs
+    };
+    let shares = split(fee, weights);
+    let markets = pending_tx
+        .markets
+        .iter()
+        .enumerate()
+        .map(|(idx, market)| {
+            let cost = Cost {
+                lamports: shares[idx],
+                compute_units: market_units[idx],
+                // packed markets whose instructions didn't make it into the transaction
+                transactions: (market_ixs[idx] > 0) as u64,
+            };
+            (*market, cost)
+        })
+        .collect();
+    TransactionCost {
+        fee,
+        compute_units,
+        markets,
+    }
+}
+
+fn get_transaction_meta(
+    rpc: &RpcPool,
+    metrics: &Metrics,
+    signature: &Signature,
+) -> Result<TransactionMeta> {
+    let transaction = metrics.time_rpc("getTransaction", || {
+        rpc.read(|client| {
+            client.send::<Option<ConfirmedTransaction>>(
+                RpcRequest::Custom {
+                    method: "getTransaction",
+                },
+                json!([
+                    signature.to_string(),
+                    {"encoding": "json", "commitment": "confirmed"}
+                ]),
+            )
+        })
+    })?;
+    transaction
+        .and_then(|transaction| transaction.meta)
+        .ok_or_else(|| anyhow!("transaction {} not found", signature))
+}
+
+/// splits the lamports in proportion to the weights, evenly when every weight is 0.
+/// the remainder is handed out one lamport at a time, to shares with a weight, so the
+/// shares add up to lamports
+fn split(lamports: u64, weights: &[u64]) -> Vec<u64> {
+    if weights.is_empty() {
+        return vec![];
+    }
+    let total: u128 = weights.iter().map(|weight| *weight as u128).sum();
+    let mut shares: Vec<u64> = if total == 0 {
+        vec![lamports / weights.len() as u64; weights.len()]
+    } else {
+        weights
+            .iter()
+            .map(|weight| (lamports as u128 * *weight as u128 / total) as u64)
+            .collect()
+    };
+    let remainder = lamports - shares.iter().sum::<u64>();
+    let weighted = shares
+        .iter_mut()
+        .zip(weights.iter())
+        .filter(|(_, weight)| total == 0 || **weight > 0);
+    for (share, _) in weighted.take(remainder as usize) {
+        *share += 1;
+    }
+    shares
+}
+
+/// quotes the csv field if it contains a separator or quote
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::instruction::{AccountMeta, Instruction};
+    use solana_sdk::transaction::Transaction;
+
+    fn crank_ix(dex_program: &Pubkey, market: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            *dex_program,
+            &[],
+            vec![
+                AccountMeta::new(Pubkey::new_unique(), false),
+                AccountMeta::new(*market, false),
+            ],
+        )
+    }
+
+    fn consumed(dex_program: &Pubkey, units: u64) -> String {
+        format!(
+            "Program {} consumed {} of 200000 compute units",
+            dex_program, units
+        )
+    }
+
+    /// a transaction cranking the first market twice and the second once,
+    /// along with an unused market
+    fn pending_tx(dex_program: &Pubkey) -> PendingTransaction {
+        let markets = vec![
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        ];
+        let ixs = vec![
+            crank_ix(dex_program, &markets[0]),
+            crank_ix(dex_program, &markets[1]),
+            crank_ix(dex_program, &markets[0]),
+            Instruction::new_with_bytes(Pubkey::new_unique(), &[], vec![]),
+        ];
+        let transaction = Transaction::new_with_payer(&ixs, Some(&Pubkey::new_unique()));
+        PendingTransaction::new(Signature::default(), transaction, markets, 5_000)
+    }
+
+    fn lamports(cost: &TransactionCost) -> Vec<u64> {
+        cost.markets
+            .iter()
+            .map(|(_, market_cost)| market_cost.lamports)
+            .collect()
+    }
+
+    #[test]
+    fn splits_add_up_to_the_lamports() {
+        assert!(split(10, &[]).is_empty());
+        assert_eq!(split(10, &[0, 0, 0]), vec![4, 3, 3]);
+        assert_eq!(split(7, &[1, 0, 1]), vec![4, 0, 3]);
+        assert_eq!(split(10_001, &[3_000, 7_000]), vec![3_001, 7_000]);
+        for weights in [vec![1], vec![1, 1, 1], vec![5, 0, 2, 9], vec![u64::MAX, 1]].iter() {
+            for lamports in [0, 1, 5_000, 10_001, u64::MAX].iter() {
+                let shares = split(*lamports, weights);
+                let total: u128 = shares.iter().map(|share| *share as u128).sum();
+                assert_eq!(total, *lamports as u128, "{} {:?}", lamports, weights);
+            }
+        }
+    }
+
+    #[test]
+    fn fees_are_split_per_instruction() {
+        let dex_program = Pubkey::new_unique();
+        let pending_tx = pending_tx(&dex_program);
+        let logs = vec![
+            consumed(&dex_program, 1_000),
+            consumed(&dex_program, 7_000),
+            consumed(&dex_program, 2_000),
+        ];
+        let cost = split_fee(
+            10_001,
+            &logs,
+            &dex_program,
+            Apportion::Instructions,
+            &pending_tx,
+        );
+        assert_eq!(cost.fee, 10_001);
+        assert_eq!(cost.compute_units, 10_000);
+        assert_eq!(lamports(&cost), vec![6_668, 3_333, 0]);
+        assert_eq!(cost.markets[0].1.compute_units, 3_000);
+        assert_eq!(cost.markets[1].1.compute_units, 7_000);
+        let transactions: Vec<u64> = cost
+            .markets
+            .iter()
+            .map(|(_, market_cost)| market_cost.transactions)
+            .collect();
+        assert_eq!(transactions, vec![1, 1, 0]);
+    }
+
+    #[test]
+    fn fees_are_split_by_compute_units() {
+        let dex_program = Pubkey::new_unique();
+        let pending_tx = pending_tx(&dex_program);
+        let logs = vec![
+            consumed(&dex_program, 1_000),
+            consumed(&dex_program, 7_000),
+            consumed(&dex_program, 2_000),
+        ];
+        let cost = split_fee(
+            10_001,
+            &logs,
+            &dex_program,
+            Apportion::ComputeUnits,
+            &pending_tx,
+        );
+        assert_eq!(lamports(&cost), vec![3_001, 7_000, 0]);
+        assert_eq!(lamports(&cost).iter().sum::<u64>(), cost.fee);
+        // without logs the fee is split per instruction
+        let cost = split_fee(
+            10_001,
+            &[],
+            &dex_program,
+            Apportion::ComputeUnits,
+            &pending_tx,
+        );
+        assert_eq!(cost.compute_units, 0);
+        assert_eq!(lamports(&cost), vec![6_668, 3_333, 0]);
+    }
+
+    #[test]
+    fn ledger_totals_survive_restarts() {
+        let path = std::env::temp_dir()
+            .join(format!("crank-costs-test-{}.json", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = fs::remove_file(&path);
+        let dex_program = Pubkey::new_unique();
+        let pending_tx = pending_tx(&dex_program);
+        let cost = split_fee(
+            9_000,
+            &[],
+            &dex_program,
+            Apportion::Instructions,
+            &pending_tx,
+        );
+        let mut ledger = CostLedger::open(&path).unwrap();
+        ledger.record(pending_tx.fee_payer(), &cost);
+        ledger.record(pending_tx.fee_payer(), &cost);
+        ledger.save().unwrap();
+        let totals = CostLedger::open(&path).unwrap().totals;
+        fs::remove_file(&path).unwrap();
+        let market = &totals.markets[&pending_tx.markets[0].to_string()];
+        assert_eq!((market.lamports, market.transactions), (12_000, 2));
+        // the unused market isn't counted as included in the transactions
+        let market = &totals.markets[&pending_tx.markets[2].to_string()];
+        assert_eq!((market.lamports, market.transactions), (0, 0));
+        let payer = &totals.payers[&pending_tx.fee_payer().to_string()];
+        assert_eq!((payer.lamports, payer.transactions), (18_000, 2));
+        assert!(totals.since.is_some());
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        assert_eq!(csv_field("SOL-USDC"), "SOL-USDC");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
@@ -1,8 +1,27 @@
+use crate::alerts::AlertManager;
+use crate::balance::BalanceMonitor;
+use crate::compute_budget::{prioritization_fee, ComputeBudget};
 use crate::config::{Configuration, ParsedMarketKeys};
+use crate::confirm::{ConfirmationTracker, CrankOutcome, PendingTransaction};
+use crate::costs::{transaction_cost, CostLedger};
+use crate::discovery::discover_markets;
+use crate::health::Health;
+use crate::history::{
+    HistoryStore, IterationRecord, MarketCheck, TransactionRecord, TransactionStatus,
+};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::packer::{MarketInstructions, TransactionPacker};
+use crate::payers::PayerRotation;
+use crate::reload::hot_reload;
+use crate::rpc::RpcPool;
+use crate::schedule::Schedule;
+use crate::simulate::simulate_crank;
+use crate::subscriber::{debounce, EventQueueSubscriber, SubscriberHandle, Wake, Woken};
+use crate::workers::WorkerPool;
 use anyhow::{anyhow, format_err, Result};
-use crossbeam::{select, sync::WaitGroup};
+use crossbeam::select;
 use crossbeam_channel::Receiver;
-use crossbeam_queue::ArrayQueue;
 use dashmap::DashMap;
 use log::{error, info, warn};
 use safe_transmute::{
@@ -10,6 +29,7 @@ use safe_transmute::{
     to_bytes::{transmute_one_to_bytes, transmute_to_bytes},
     transmute_many, transmute_many_pedantic, transmute_one_pedantic,
 };
+use serde_json::{json, Value};
 use serum_dex::instruction::MarketInstruction;
 use serum_dex::state::gen_vault_signer_key;
 use serum_dex::state::Event;
@@ -18,6 +38,7 @@ use serum_dex::state::QueueHeader;
 use serum_dex::state::{AccountFlag, Market, MarketState, MarketStateV2};
 use solana_client::rpc_config::RpcSendTransactionConfig;
 use solana_client::{self, rpc_client::RpcClient};
+use solana_sdk::account::Account;
 use solana_sdk::commitment_config::CommitmentConfig;
 use solana_sdk::instruction::{AccountMeta, Instruction};
 use solana_sdk::signature::Signature;
@@ -30,309 +51,839 @@ use std::convert::identity;
 use std::mem::size_of;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use std::{borrow::Cow, collections::HashMap, sync::RwLock};
-use std::{
-    ops::Deref,
-};
+
+/// max number of accounts accepted by a single getMultipleAccounts request
+pub const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
 pub struct Crank {
-    pub config: Arc<Configuration>,
+    config: RwLock<Arc<Configuration>>,
+    pub metrics: Arc<Metrics>,
+    pub health: Arc<Health>,
+    /// when true, crank transactions are simulated instead of sent
+    dry_run: bool,
 }
 
 impl Crank {
-    pub fn new(config: Arc<Configuration>) -> Arc<Self> {
-        Arc::new(Self { config })
+    pub fn new(
+        config: Arc<Configuration>,
+        metrics: Arc<Metrics>,
+        health: Arc<Health>,
+        dry_run: bool,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            config: RwLock::new(config),
+            metrics,
+            health,
+            dry_run,
+        })
+    }
+    /// returns the configuration currently in use
+    pub fn config(&self) -> Arc<Configuration> {
+        Arc::clone(&self.config.read().unwrap())
     }
-    pub fn start(self: &Arc<Self>, exit_chan: Receiver<bool>) -> Result<()> {
-        let rpc_client = Arc::new(RpcClient::new(self.config.http_rpc_url.clone()));
-        let payer = Arc::new(self.config.payer());
-        let dex_program = Pubkey::from_str(self.config.crank.dex_program.as_str()).unwrap();
-        let market_keys = Arc::new(self.config.crank.market_keys(&rpc_client, dex_program)?);
-        let slot_height_map = DashMap::new();
-        let q: Arc<ArrayQueue<(Vec<Instruction>, Pubkey)>> =
-            Arc::new(ArrayQueue::new(market_keys.len()));
+    pub fn start(
+        self: &Arc<Self>,
+        payers: Vec<Keypair>,
+        exit_chan: Receiver<bool>,
+        reload_chan: Receiver<Configuration>,
+    ) -> Result<()> {
+        let mut config = self.config();
+        if self.dry_run {
+            warn!("dry run enabled, crank transactions are simulated and never sent");
+        }
+        let rpc = Arc::new(RpcPool::new(
+            config.rpc_endpoints(),
+            config.rpc_failover.clone(),
+            Arc::clone(&self.metrics),
+        ));
+        RpcPool::start_health_checks(&rpc);
+        let mut payers = PayerRotation::new(payers);
+        info!("rotating between {} payers", payers.pubkeys().len());
+        let mut dex_program = Pubkey::from_str(config.crank.dex_program.as_str())?;
+        // markets from the config file, in the same order as config.crank.markets
+        let mut configured_keys = rpc.read_with(|client| config.crank.market_keys(client, dex_program))?;
+        let mut discovered_keys: Vec<ParsedMarketKeys> = vec![];
+        let mut last_discovery: Option<Instant> = None;
+        let mut market_keys: Arc<Vec<ParsedMarketKeys>> = Arc::new(vec![]);
+        let mut markets_changed = true;
+        // queue state of each market when its last confirmed crank was built
+        let queue_markers: Arc<DashMap<Pubkey, QueueMarker>> = Arc::new(DashMap::new());
+        let mut workers = WorkerPool::new(config.crank.worker_threads);
+        let mut balance_monitor =
+            BalanceMonitor::new(config.payer_balance.clone(), Arc::clone(&self.metrics));
+        // running totals of the fees paid per market and per payer
+        let mut cost_ledger = if config.cost_accounting.ledger_file.is_empty() {
+            None
+        } else {
+            Some(CostLedger::open(&config.cost_accounting.ledger_file)?)
+        };
+        let mut alerts = AlertManager::new(config.alerts.clone());
+        let mut schedule = Schedule::default();
+        // dry runs never mark markets as cranked, so they aren't recorded
+        let mut history = if self.dry_run || config.history.database.is_empty() {
+            None
+        } else {
+            Some(HistoryStore::open(&config.history)?)
+        };
+        if let Some(history) = history.as_ref() {
+            // skip markets whose queues haven't advanced since their last crank before the restart
+            let restored = history.queue_markers()?;
+            info!("restored the queue markers of {} markets", restored.len());
+            for (market, marker, cranked_at) in restored {
+                queue_markers.insert(market, marker);
+                schedule.restore_crank(market, cranked_at);
+            }
+        }
+        let mut woken = Woken::default();
+        let (mut wake_chan, mut subscriber) = (None, None);
+        let mut pending_reload: Option<Configuration> = None;
+        // correlates the log records of a crank loop iteration
+        let mut iteration: u64 = 0;
         loop {
             select! {
                 recv(exit_chan) -> _msg => {
                     warn!("caught exit signal");
                     return Ok(());
                 },
+                recv(reload_chan) -> msg => {
+                    if let Ok(new_config) = msg {
+                        pending_reload = Some(new_config);
+                    }
+                },
                 default => {}
             }
-            let work_loop = |market_key: &ParsedMarketKeys| -> Result<Option<Vec<Instruction>>> {
-                let mut queue_accounts = rpc_client.get_multiple_accounts_with_commitment(
-                    &[market_key.keys.event_q, market_key.keys.req_q],
-                    CommitmentConfig::processed(),
-                )?;
-                if queue_accounts.value.len() != 2 {
-                    return Err(anyhow!("failed to find correct number of queue accounts"));
-                }
-                let event_q_slot = queue_accounts.context.slot;
-                {
-                    if let Some(height) = slot_height_map.get(&market_key.keys.market.to_string()) {
-                        let height = *height;
-                        if event_q_slot <= height {
-                            info!(
-                                "Skipping crank. Already cranked for slot. Event queue slot: {}, Max seen slot: {}",
-                                event_q_slot, height
-                            );
-                            return Ok(None);
+            if let Some(new_config) = pending_reload.take() {
+                match self.reload(&rpc, &config, &configured_keys, new_config) {
+                    Ok((new_config, new_configured_keys)) => {
+                        if new_config.crank.discovery != config.crank.discovery
+                            || new_config.crank.dex_program != config.crank.dex_program
+                        {
+                            // force a refresh of the discovered markets
+                            discovered_keys.clear();
+                            last_discovery = None;
                         }
+                        dex_program = Pubkey::from_str(new_config.crank.dex_program.as_str())?;
+                        if new_config.crank.worker_threads != workers.size() {
+                            info!("resizing worker pool to {} threads", new_config.crank.worker_threads);
+                            workers = WorkerPool::new(new_config.crank.worker_threads);
+                        }
+                        config = new_config;
+                        configured_keys = new_configured_keys;
+                        balance_monitor.set_config(config.payer_balance.clone());
+                        alerts.set_config(config.alerts.clone());
+                        self.health.set_config(&config);
+                        // the ws settings may have changed, so always restart the subscriber
+                        markets_changed = true;
                     }
-                }
-                let event_q_data = match std::mem::take(&mut queue_accounts.value[0]) {
-                    Some(event_q) => event_q.data,
-                    None => {
-                        return Err(anyhow!(
-                            "{} event q value and context is none, skipping....",
-                            market_key.keys.market
-                        ));
+                    Err(err) => {
+                        error!(
+                            "rejected new configuration, keeping the current configuration: {:#?}",
+                            err
+                        );
                     }
-                };
-                let req_q_data = match std::mem::take(&mut queue_accounts.value[1]) {
-                    Some(req_q_data) => req_q_data.data,
-                    None => {
-                        return Err(anyhow!(
-                            "{} request q value and context is none, skipping....",
-                            market_key.keys.market
-                        ));
+                }
+            }
+            match config.crank.discovery.as_ref() {
+                Some(discovery) => {
+                    let refresh_due = match last_discovery {
+                        Some(last_discovery) => {
+                            last_discovery.elapsed() >= Duration::from_secs(discovery.refresh_interval)
+                        }
+                        None => true,
+                    };
+                    if refresh_due {
+                        let payer = payers.primary().pubkey();
+                        match rpc.read_with(|client| discover_markets(client, &dex_program, discovery, &payer)) {
+                            Ok(new_discovered_keys) => {
+                                if new_discovered_keys != discovered_keys {
+                                    discovered_keys = new_discovered_keys;
+                                    markets_changed = true;
+                                }
+                            }
+                            Err(err) => error!("failed to discover markets {:#?}", err),
+                        }
+                        last_discovery = Some(Instant::now());
                     }
-                };
-                let inner: Cow<[u64]> = remove_dex_account_padding(&event_q_data)?;
-                let (_header, seg0, seg1) = parse_event_queue(&inner)?;
-                let req_inner: Cow<[u64]> = remove_dex_account_padding(&req_q_data)?;
-                let (_req_header, req_seg0, req_seg1) = parse_event_queue(&req_inner)?;
-                let event_q_len = seg0.len() + seg1.len();
-                let req_q_len = req_seg0.len() + req_seg1.len();
-                info!(
-                    "event q len {}, req q len {}, market {}, coin {}, pc {}",
-                    event_q_len, req_q_len, market_key.keys.market, market_key.coin_wallet, market_key.pc_wallet
-                );
-                if event_q_len == 0 {
-                    return Ok(None);
                 }
-                info!(
-                    "Total event queue length: {}, market {}, coin {}, pc {}",
-                    event_q_len,
-                    market_key.keys.market,
-                    market_key.coin_wallet,
-                    market_key.pc_wallet
-                );
-                let accounts = seg0.iter().chain(seg1.iter()).map(|event| event.owner);
-                let mut used_accounts = BTreeSet::new();
-                for account in accounts {
-                    used_accounts.insert(account);
-                    if used_accounts.len() >= self.config.crank.num_accounts {
-                        warn!("found too many accounts for market {}, skipping remaining...", market_key.keys.market);
-                        break;
+                None => {
+                    if !discovered_keys.is_empty() {
+                        discovered_keys.clear();
+                        markets_changed = true;
                     }
                 }
-                // todo(bonedaddy): verify this works
-                let orders_accounts: Vec<_> = used_accounts.into_iter().collect();
-                info!(
-                    "Number of unique order accounts: {}, market {}, coin {}, pc {}",
-                    orders_accounts.len(),
-                    market_key.keys.market,
-                    market_key.coin_wallet,
-                    market_key.pc_wallet
-                );
-                info!(
-                    "First {} accounts: {:?}",
-                    self.config.crank.num_accounts,
-                    orders_accounts
+            }
+            if markets_changed {
+                let new_market_keys = merge_market_keys(&configured_keys, &discovered_keys);
+                // stop exporting metrics for markets which are no longer cranked
+                for old_keys in market_keys.iter() {
+                    if !new_market_keys
                         .iter()
-                        .take(self.config.crank.num_accounts)
-                        .map(hash_accounts)
-                        .collect::<Vec::<_>>(),
-                );
-
-                let mut account_metas = Vec::with_capacity(orders_accounts.len() + 4);
-                for pubkey_words in orders_accounts {
-                    let pubkey = Pubkey::new(transmute_to_bytes(&pubkey_words));
-                    account_metas.push(AccountMeta::new(pubkey, false));
+                        .any(|keys| keys.keys.market == old_keys.keys.market)
+                    {
+                        self.metrics.remove_market(&old_keys.keys.market.to_string());
+                    }
                 }
-                for pubkey in [
-                    &market_key.keys.market,
-                    &market_key.keys.event_q,
-                    &market_key.coin_wallet,
-                    &market_key.pc_wallet,
-                ]
-                .iter()
-                {
-                    account_metas.push(AccountMeta::new(**pubkey, false));
+                info!("cranking {} markets", new_market_keys.len());
+                market_keys = Arc::new(new_market_keys);
+                schedule.retain(&market_keys);
+                // restart the subscriber as the event queues or ws settings may have changed
+                drop(subscriber);
+                let (new_wake_chan, new_subscriber) = start_subscriber(&config, &market_keys);
+                wake_chan = new_wake_chan;
+                subscriber = new_subscriber;
+                markets_changed = false;
+                self.health.set_ready();
+            }
+            let compute_budget =
+                ComputeBudget::new(Arc::clone(&rpc), config.crank.priority_fee.clone());
+            let confirmation_tracker = ConfirmationTracker::new(
+                Arc::clone(&rpc),
+                Duration::from_millis(config.crank.confirm_poll_interval_ms),
+                Duration::from_millis(config.crank.resend_interval_ms),
+            );
+            iteration += 1;
+            let _iteration = logging::scope(vec![("iteration", json!(iteration))]);
+            let mut record = IterationRecord::new(iteration);
+            // group the due markets so the queues of each group are fetched in one request
+            let due_chunks = chunk_markets(&schedule.due_markets(&config.crank, &market_keys, &woken));
+            let num_due_markets: usize = due_chunks.iter().map(|(_, chunk)| chunk.len()).sum();
+            info!("starting crank run for {} of {} markets", num_due_markets, market_keys.len());
+            let loop_start = Instant::now();
+            // set when the exit signal is caught while waiting for transactions to confirm
+            let mut exiting = false;
+            // check the balances before sending, so payers whose balance is critical are skipped
+            let payer_keys = payers.pubkeys();
+            match self.metrics.time_rpc("getMultipleAccounts", || {
+                rpc.read(|client| {
+                    let mut accounts = Vec::with_capacity(payer_keys.len());
+                    for chunk in payer_keys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+                        accounts.extend(client.get_multiple_accounts(chunk)?);
+                    }
+                    Ok(accounts)
+                })
+            }) {
+                Ok(accounts) => {
+                    let mut total_balance = 0;
+                    for (payer, account) in payer_keys.iter().zip(accounts.iter()) {
+                        let balance = account.as_ref().map_or(0, |account| account.lamports);
+                        total_balance += balance;
+                        balance_monitor.update(payer, balance);
+                        alerts.record_payer_balance(payer, balance, config.payer_balance.warning_balance);
+                    }
+                    self.health.set_payer_balance(total_balance);
+                    alerts.record_rpc(None);
+                }
+                Err(err) => {
+                    error!("failed to retrieve payer balances {:#?}", err);
+                    alerts.record_rpc(Some(err.to_string()));
                 }
-                let instructions = vec![consume_events_ix(
-                    &dex_program,
-                    account_metas,
-                    self.config.crank.events_per_worker,
-                )];
-                Ok(Some(instructions))
-            };
-            info!("starting crank run");
+            }
             {
-                let res = crossbeam::thread::scope(|s| {
-                    let wg = WaitGroup::new();
-                    // spawn a locally scoped thread to process each market in parallel
-                    for market_key in market_keys.iter() {
-                        let wg = wg.clone();
-                        let q = Arc::clone(&q);
-                        s.spawn(move |_| {
-                            let ixs = work_loop(&market_key);
-                            match ixs {
-                                Ok(ixs) => match ixs {
-                                    Some(ixs) => {
-                                        let res = q.push((ixs, market_key.keys.market));
-                                        if res.is_err() {
-                                            error!("failed to push instruction set onto stack for market {}: {:#?}", market_key.keys.market, res.err());
-                                        }
-                                    }
-                                    None => {
-                                        warn!(
-                                            "found no instructions for market {}",
-                                            market_key.keys.market
-                                        );
-                                    }
-                                },
-                                Err(err) => {
-                                    error!(
-                                        "failed to run work loop for market {}: {:#?}",
-                                        market_key.keys.market, err
-                                    );
+                let results = {
+                    let crank = Arc::clone(self);
+                    let rpc = Arc::clone(&rpc);
+                    let config = Arc::clone(&config);
+                    let queue_markers = Arc::clone(&queue_markers);
+                    // process the groups of due markets in parallel on the worker pool
+                    workers.map(due_chunks, move |(chunk, market_keys): (usize, Vec<ParsedMarketKeys>)| {
+                        let _chunk = logging::scope(vec![("iteration", json!(iteration)), ("chunk", json!(chunk))]);
+                        crank.process_markets(&rpc, &config, &dex_program, &queue_markers, &market_keys)
+                    })
+                };
+                info!("collecting instructions");
+                let mut ix_sets = vec![];
+                let mut markers = HashMap::new();
+                for (market, res) in results.into_iter().flatten() {
+                    let _market = logging::scope(vec![("market", json!(market.to_string()))]);
+                    match res {
+                        Ok(work) => {
+                            alerts.record_queue_len(&market, work.event_q_len);
+                            record.markets.push(MarketCheck {
+                                market: market.to_string(),
+                                event_q_len: Some(work.event_q_len as u64),
+                                req_q_len: Some(work.req_q_len as u64),
+                                accounts: work.open_orders.iter().map(Pubkey::to_string).collect(),
+                                error: None,
+                            });
+                            match work.crank {
+                                Some((ixs, marker)) => {
+                                    ix_sets.push(MarketInstructions {
+                                        market,
+                                        instructions: ixs,
+                                        open_orders: work.open_orders.clone(),
+                                    });
+                                    markers.insert(market, marker);
                                 }
+                                None => warn!("found no instructions for market {}", market),
                             }
-                            drop(wg);
-                        });
-                    }
-                    // wait for market processing threads to finish, collecting 
-                    info!("waiting for spawned threads to finish");
-                    wg.wait();
-                    info!("collecting instructions");
-                    let mut instructions = vec![];
-                    let mut instructions_markets = vec![];
-                    // loop, popping instructions off the queue
-                    // until we have no more instructions to process
-                    loop {
-                        let ix_set = q.pop();
-                        if ix_set.is_none() {
-                            break;
                         }
-                        let ix_set = ix_set.unwrap();
-                        instructions.extend_from_slice(&ix_set.0);
-                        instructions_markets.push(ix_set.1);
+                        Err(err) => {
+                            error!("failed to run work loop for market {}: {:#?}", market, err);
+                            record.markets.push(MarketCheck {
+                                market: market.to_string(),
+                                event_q_len: None,
+                                req_q_len: None,
+                                accounts: vec![],
+                                error: Some(err.to_string()),
+                            });
+                        }
                     }
-                    if instructions.len() > 0 {
-                        info!(
-                            "found {} instructions for {} markets: {:#?}",
-                            instructions.len(),
-                            instructions_markets.len(),
-                            instructions_markets
+                }
+                if ix_sets.len() > 0 {
+                    info!(
+                        "found instructions for {} markets: {:#?}",
+                        ix_sets.len(),
+                        ix_sets.iter().map(|ix_set| ix_set.market).collect::<Vec<_>>(),
+                    );
+                    let build_txn = |payer: &Keypair, instructions: &Vec<Instruction>| -> Result<(Transaction, u64)> {
+                        let (recent_hash, fee_calc) = self.metrics.time_rpc("getRecentBlockhash", || {
+                            rpc.read(|client| client.get_recent_blockhash())
+                        })?;
+                        let txn = Transaction::new_signed_with_payer(
+                            &instructions[..],
+                            Some(&payer.pubkey()),
+                            &[payer],
+                            recent_hash,
                         );
-                        let run_loop = |instructions: &Vec<Instruction>| -> Result<Signature> {
-                            let (recent_hash, _fee_calc) = rpc_client.get_recent_blockhash()?;
-                            let txn = Transaction::new_signed_with_payer(
-                                &instructions[..],
-                                Some(&payer.pubkey()),
-                                &[payer.deref()],
-                                recent_hash,
-                            );
-                            info!("sending crank instructions");
-                            let signature = rpc_client.send_transaction_with_config(
+                        let fee = fee_calc.lamports_per_signature * txn.signatures.len() as u64
+                            + prioritization_fee(instructions);
+                        Ok((txn, fee))
+                    };
+                    let run_loop = |payer: &Keypair, instructions: &Vec<Instruction>| -> Result<(Signature, Transaction, u64)> {
+                        let (txn, fee) = build_txn(payer, instructions)?;
+                        info!("sending crank instructions");
+                        let signature = self.metrics.time_rpc("sendTransaction", || {
+                            rpc.send_transaction(
                                 &txn,
                                 RpcSendTransactionConfig {
                                     skip_preflight: true,
                                     ..RpcSendTransactionConfig::default()
                                 },
-                            )?;
-                            Ok(signature)
+                            )
+                        })?;
+                        Ok((signature, txn, fee))
+                    };
+                    // every payer adds the same single signature and account to a transaction
+                    let packer = TransactionPacker::new(
+                        payers.primary().pubkey(),
+                        config.crank.max_accounts_per_tx,
+                        config.crank.max_markets_per_tx,
+                        compute_budget.placeholder_instructions(&market_keys),
+                    );
+                    let packed_txs = packer.pack(ix_sets);
+                    // record the open orders accounts which are cranked once trimmed to fit
+                    for packed_tx in packed_txs.iter() {
+                        for (market, open_orders) in packed_tx.markets.iter().zip(packed_tx.open_orders.iter()) {
+                            let market = market.to_string();
+                            if let Some(check) = record.markets.iter_mut().find(|check| check.market == market) {
+                                check.accounts = open_orders.iter().map(Pubkey::to_string).collect();
+                            }
+                        }
+                    }
+                    let num_txs = packed_txs.len();
+                    let mut pending_txs = Vec::with_capacity(num_txs);
+                    for (idx, mut packed_tx) in packed_txs.into_iter().enumerate() {
+                        // prepend the compute budget instructions for the markets in this tx
+                        let packed_markets: Vec<&ParsedMarketKeys> = market_keys
+                            .iter()
+                            .filter(|market_key| packed_tx.markets.contains(&market_key.keys.market))
+                            .collect();
+                        let mut ixs = compute_budget.instructions(&packed_markets);
+                        ixs.append(&mut packed_tx.instructions);
+                        packed_tx.instructions = ixs;
+                        if self.dry_run {
+                            // simulate the exact transaction which would have been sent
+                            let res = build_txn(payers.primary(), &packed_tx.instructions).and_then(|(txn, _fee)| {
+                                simulate_crank(&rpc, &self.metrics, &dex_program, &txn, &packed_tx.markets)
+                            });
+                            match res {
+                                Ok(report) => report.log(),
+                                Err(err) => error!(
+                                    "failed to simulate crank transaction {} of {}: {:#?}",
+                                    idx + 1,
+                                    num_txs,
+                                    err
+                                ),
+                            }
+                            continue;
+                        }
+                        let payer = match payers.next(&balance_monitor) {
+                            Some(payer) => payer,
+                            None => {
+                                warn!(
+                                    "every payer balance is below the critical threshold, not sending crank transaction {} of {} for markets: {:#?}",
+                                    idx + 1,
+                                    num_txs,
+                                    packed_tx.markets
+                                );
+                                continue;
+                            }
                         };
-                        if instructions_markets.len() > self.config.crank.max_markets_per_tx {
-                            warn!(
-                                "number of market instructions {} greater than max markets per tx {}, processing in chunks",
-                                instructions_markets.len(), self.config.crank.max_markets_per_tx
-                            );
-                            let instructions_chunks = instructions.chunks(self.config.crank.max_markets_per_tx);
-                            let num_chunks = instructions_chunks.len();
-                            info!("starting chunked crank instruction processing");
-                            for (idx, chunk) in instructions_chunks.enumerate() {
-                                // pre-allocate the vector to size of chunks + 1 to include the
-                                // random transfer instruction
-                                let mut ixs = Vec::with_capacity(chunk.len() + 1);
-                                // extend instruction slice by cloning instructions in this chunk
-                                ixs.extend_from_slice(chunk);
-                                // add the random transfer instruction
-                                ixs.push(solana_sdk::system_instruction::transfer(
-                                    &payer.pubkey(),
-                                    &payer.pubkey(),
-                                    rand::random::<u64>() % 10000 + 1,                                    
-                                ));
-                                let res = run_loop(&ixs);
-                                if res.is_err() {
-                                    error!(
-                                        "failed to process chunk instruction index {} of {}: {:#?}",
-                                        idx + 1,
-                                        num_chunks,
-                                        res.err().unwrap()
-                                    );
-                                } else {
-                                    info!("processed chunk instruction index {} of {}: {}", idx + 1, num_chunks, res.unwrap());
-                                }
+                        let res = run_loop(&payer, &packed_tx.instructions);
+                        match res {
+                            Ok((signature, txn, fee)) => {
+                                self.metrics.transactions_sent.inc();
+                                let _transaction = logging::scope(transaction_fields(Some(&signature), &packed_tx.markets));
+                                info!(
+                                    "crank ran {} processed {} instructions for {} markets: {:#?}",
+                                    signature,
+                                    packed_tx.instructions.len(),
+                                    packed_tx.markets.len(),
+                                    packed_tx.markets,
+                                );
+                                pending_txs.push(PendingTransaction::new(signature, txn, packed_tx.markets, fee));
+                                alerts.record_send(None);
                             }
-                            info!("finished chunked crank instruction processing")
-                        } else {
-                            if instructions_markets.len() > 0 {
-                                // add the random transfer instruction
-                                instructions.push(solana_sdk::system_instruction::transfer(
-                                    &payer.pubkey(),
-                                    &payer.pubkey(),
-                                    rand::random::<u64>() % 10000 + 1,                                    
-                                ));
-                                let res = run_loop(&instructions);
-                                if res.is_err() {
-                                    error!("failed to send crank instructions {:#?}", res.err());
-                                } else {
-                                    info!(
-                                        "crank ran {} processed {} instructions for {} markets: {:#?}",
-                                        res.unwrap(),
-                                        instructions.len(),
-                                        instructions_markets.len(),
-                                        instructions_markets,
-                                    );
-                                }
-                            } else {
-                                warn!("no markets needed cranking");
+                            Err(err) => {
+                                self.metrics.transactions_failed.with_label_values(&["send_error"]).inc();
+                                let _transaction = logging::scope(transaction_fields(None, &packed_tx.markets));
+                                error!(
+                                    "failed to send crank transaction {} of {}: {:#?}",
+                                    idx + 1,
+                                    num_txs,
+                                    err
+                                );
+                                let mut transaction =
+                                    TransactionRecord::new(None, &payer.pubkey(), &packed_tx.markets, TransactionStatus::SendError);
+                                transaction.error = Some(err.to_string());
+                                record.transactions.push(transaction);
+                                alerts.record_send(Some(err.to_string()));
                             }
                         }
-                        // update slot number for any markets included in this crank
-                        let slot_number = rpc_client.get_slot();
-                        match slot_number {
-                            Ok(slot_number) => {
-                                    for market in instructions_markets.iter() {
-                                        slot_height_map.insert(market.to_string(), slot_number);
+                    }
+                    // wait for the sent transactions to land, only markets included
+                    // in a confirmed transaction have their queue marker updated
+                    info!("waiting for {} crank transactions to confirm", pending_txs.len());
+                    let confirmations = confirmation_tracker.confirm(pending_txs, &exit_chan);
+                    exiting = confirmations.interrupted;
+                    for (pending_tx, outcome) in confirmations.outcomes {
+                        let _transaction = logging::scope(transaction_fields(Some(&pending_tx.signature), &pending_tx.markets));
+                        let mut transaction = TransactionRecord::new(
+                            Some(&pending_tx.signature),
+                            pending_tx.fee_payer(),
+                            &pending_tx.markets,
+                            TransactionStatus::Expired,
+                        );
+                        match outcome {
+                            CrankOutcome::Confirmed(slot_number) => {
+                                self.metrics.transactions_confirmed.inc();
+                                let fee = self.record_cost(&rpc, &config, &dex_program, cost_ledger.as_mut(), &pending_tx);
+                                balance_monitor.record_spend(pending_tx.fee_payer(), fee);
+                                info!("crank confirmed {} in slot {} for markets: {:#?}", pending_tx.signature, slot_number, pending_tx.markets);
+                                for market in pending_tx.markets.iter() {
+                                    if let Some(marker) = markers.get(market) {
+                                        queue_markers.insert(*market, *marker);
+                                        record.markers.push((*market, *marker));
                                     }
-                            },
-                            Err(err) => {
-                                error!("failed to retrieve slot number {:#?}", err);
+                                    schedule.record_crank(*market);
+                                }
+                                transaction.status = TransactionStatus::Confirmed;
+                                transaction.slot = Some(slot_number);
+                                transaction.fee = Some(fee);
+                            }
+                            CrankOutcome::Failed(err) => {
+                                // failed transactions still pay fees
+                                self.metrics.transactions_failed.with_label_values(&["failed"]).inc();
+                                let fee = self.record_cost(&rpc, &config, &dex_program, cost_ledger.as_mut(), &pending_tx);
+                                balance_monitor.record_spend(pending_tx.fee_payer(), fee);
+                                error!("crank failed {} with error {:#?} for markets: {:#?}", pending_tx.signature, err, pending_tx.markets);
+                                transaction.status = TransactionStatus::Failed;
+                                transaction.fee = Some(fee);
+                                transaction.error = Some(format!("{:?}", err));
+                            }
+                            CrankOutcome::Expired => {
+                                self.metrics.transactions_failed.with_label_values(&["expired"]).inc();
+                                error!("crank expired {} for markets: {:#?}", pending_tx.signature, pending_tx.markets);
                             }
                         }
+                        record.transactions.push(transaction);
                     }
-                });
-                if res.is_err() {
-                    error!("failed to run crossbeam scope {:#?}", res.err());
+                    if let Some(cost_ledger) = cost_ledger.as_mut() {
+                        if let Err(err) = cost_ledger.save() {
+                            error!("failed to save cost ledger {:#?}", err);
+                        }
+                    }
+                }
+            }
+            self.metrics.loop_duration.observe(loop_start.elapsed().as_secs_f64());
+            if let Some(history) = history.as_mut() {
+                record.duration_ms = loop_start.elapsed().as_millis() as u64;
+                if let Err(err) = history.record(&record) {
+                    error!("failed to record crank history {:#?}", err);
                 }
             }
+            self.health.record_iteration();
             info!("finished crank run");
-            std::thread::sleep(std::time::Duration::from_secs(
-                self.config.crank.max_wait_for_events_delay,
-            ));
-            // clear the array queue in between runs 
-            loop {
-                match q.pop() {
-                    Some(_) => continue,
-                    None => break,
+            if exiting {
+                return Ok(());
+            }
+            let poll_delay = schedule.next_delay(&config.crank, &market_keys);
+            woken = Woken::default();
+            match self.wait_for_events(&config, poll_delay, &exit_chan, &reload_chan, wake_chan.as_ref()) {
+                Wakeup::Exit => {
+                    warn!("caught exit signal");
+                    return Ok(());
+                }
+                Wakeup::Reload(new_config) => pending_reload = Some(*new_config),
+                Wakeup::Run(new_woken) => woken = new_woken,
+            }
+        }
+    }
+    /// records the fee paid by a landed crank transaction, apportioned between its
+    /// markets, and returns it. the estimated fee is used when costs aren't tracked
+    fn record_cost(
+        &self,
+        rpc: &RpcPool,
+        config: &Configuration,
+        dex_program: &Pubkey,
+        cost_ledger: Option<&mut CostLedger>,
+        pending_tx: &PendingTransaction,
+    ) -> u64 {
+        let cost_ledger = match cost_ledger {
+            Some(cost_ledger) => cost_ledger,
+            None => {
+                self.metrics.lamports_spent.inc_by(pending_tx.fee);
+                return pending_tx.fee;
+            }
+        };
+        let cost = transaction_cost(rpc, &self.metrics, dex_program, config.cost_accounting.apportion, pending_tx);
+        self.metrics.lamports_spent.inc_by(cost.fee);
+        for (market, market_cost) in cost.markets.iter() {
+            self.metrics
+                .market_lamports_spent
+                .with_label_values(&[&market.to_string()])
+                .inc_by(market_cost.lamports);
+        }
+        info!(
+            "crank transaction {} paid {} lamports for {} compute units, apportioned: {:#?}",
+            pending_tx.signature,
+            cost.fee,
+            cost.compute_units,
+            cost.markets.iter().map(|(market, market_cost)| (market, market_cost.lamports)).collect::<Vec<_>>()
+        );
+        cost_ledger.record(pending_tx.fee_payer(), &cost);
+        cost.fee
+    }
+    /// fetches the event and request queues of the markets with a single
+    /// getMultipleAccounts request, returning the crank instructions of each market
+    fn process_markets(
+        &self,
+        rpc: &RpcPool,
+        config: &Configuration,
+        dex_program: &Pubkey,
+        queue_markers: &DashMap<Pubkey, QueueMarker>,
+        market_keys: &[ParsedMarketKeys],
+    ) -> Vec<(Pubkey, Result<MarketWork>)> {
+        let keys = queue_keys(market_keys);
+        let res = self.metrics.time_rpc("getMultipleAccounts", || {
+            rpc.read(|client| {
+                client.get_multiple_accounts_with_commitment(&keys, CommitmentConfig::processed())
+            })
+        });
+        let mut accounts = match res {
+            Ok(res) if res.value.len() == keys.len() => res.value,
+            Ok(_) => {
+                return market_keys
+                    .iter()
+                    .map(|market_key| {
+                        (market_key.keys.market, Err(anyhow!("failed to find correct number of queue accounts")))
+                    })
+                    .collect()
+            }
+            Err(err) => {
+                return market_keys
+                    .iter()
+                    .map(|market_key| {
+                        (market_key.keys.market, Err(anyhow!("failed to fetch queue accounts: {:#?}", err)))
+                    })
+                    .collect()
+            }
+        };
+        market_keys
+            .iter()
+            .zip(accounts.chunks_mut(2))
+            .map(|(market_key, queue_accounts)| {
+                let _market = logging::scope(vec![("market", json!(market_key.keys.market.to_string()))]);
+                let res = self.work_loop(config, dex_program, queue_markers, market_key, queue_accounts);
+                (market_key.keys.market, res)
+            })
+            .collect()
+    }
+    /// parses the fetched queues of the market and returns their lengths, along with
+    /// the market's crank instructions and the state of its event queue when there is
+    /// something to crank
+    fn work_loop(
+        &self,
+        config: &Configuration,
+        dex_program: &Pubkey,
+        queue_markers: &DashMap<Pubkey, QueueMarker>,
+        market_key: &ParsedMarketKeys,
+        queue_accounts: &mut [Option<Account>],
+    ) -> Result<MarketWork> {
+        let event_q_data = match std::mem::take(&mut queue_accounts[0]) {
+            Some(event_q) => event_q.data,
+            None => {
+                return Err(anyhow!(
+                    "{} event q value and context is none, skipping....",
+                    market_key.keys.market
+                ));
+            }
+        };
+        let req_q_data = match std::mem::take(&mut queue_accounts[1]) {
+            Some(req_q_data) => req_q_data.data,
+            None => {
+                return Err(anyhow!(
+                    "{} request q value and context is none, skipping....",
+                    market_key.keys.market
+                ));
+            }
+        };
+        let inner: Cow<[u64]> = remove_dex_account_padding(&event_q_data)?;
+        let (header, seg0, seg1) = parse_event_queue(&inner)?;
+        let req_inner: Cow<[u64]> = remove_dex_account_padding(&req_q_data)?;
+        let (_req_header, req_seg0, req_seg1) = parse_event_queue(&req_inner)?;
+        let event_q_len = seg0.len() + seg1.len();
+        let req_q_len = req_seg0.len() + req_seg1.len();
+        let _queues = logging::scope(vec![("event_q_len", json!(event_q_len)), ("req_q_len", json!(req_q_len))]);
+        info!(
+            "event q len {}, req q len {}, market {}, coin {}, pc {}",
+            event_q_len, req_q_len, market_key.keys.market, market_key.coin_wallet, market_key.pc_wallet
+        );
+        let market_label = market_key.keys.market.to_string();
+        self.metrics.event_queue_depth.with_label_values(&[&market_label]).set(event_q_len as i64);
+        self.metrics.request_queue_depth.with_label_values(&[&market_label]).set(req_q_len as i64);
+        let mut work = MarketWork {
+            event_q_len,
+            req_q_len,
+            open_orders: vec![],
+            crank: None,
+        };
+        if event_q_len == 0 {
+            self.metrics.open_orders_accounts.with_label_values(&[&market_label]).set(0);
+            return Ok(work);
+        }
+        let marker = QueueMarker::new(&inner, &header);
+        if !queue_advanced(queue_markers, &market_key.keys.market, &marker) {
+            info!(
+                "Skipping crank. Event queue hasn't advanced since the last confirmed crank. Market: {}, seq num: {}, head: {}",
+                market_key.keys.market, marker.seq_num, marker.head
+            );
+            return Ok(work);
+        }
+        info!(
+            "Total event queue length: {}, market {}, coin {}, pc {}",
+            event_q_len,
+            market_key.keys.market,
+            market_key.coin_wallet,
+            market_key.pc_wallet
+        );
+        let accounts = seg0.iter().chain(seg1.iter()).map(|event| event.owner);
+        // the dex looks up the owners of events in the sorted accounts, the order of their
+        // first event is kept so the accounts of the latest events can be trimmed
+        let mut used_accounts = BTreeSet::new();
+        let mut first_event_order = vec![];
+        for account in accounts {
+            if used_accounts.insert(account) {
+                first_event_order.push(account);
+            }
+            if used_accounts.len() >= market_key.num_accounts(&config.crank) {
+                warn!("found too many accounts for market {}, skipping remaining...", market_key.keys.market);
+                break;
+            }
+        }
+        // todo(bonedaddy): verify this works
+        let orders_accounts: Vec<_> = used_accounts.into_iter().collect();
+        self.metrics.open_orders_accounts.with_label_values(&[&market_label]).set(orders_accounts.len() as i64);
+        info!(
+            "Number of unique order accounts: {}, market {}, coin {}, pc {}",
+            orders_accounts.len(),
+            market_key.keys.market,
+            market_key.coin_wallet,
+            market_key.pc_wallet
+        );
+        info!(
+            "First {} accounts: {:?}",
+            market_key.num_accounts(&config.crank),
+            orders_accounts
+                .iter()
+                .take(market_key.num_accounts(&config.crank))
+                .map(hash_accounts)
+                .collect::<Vec::<_>>(),
+        );
+
+        let mut account_metas = Vec::with_capacity(orders_accounts.len() + 4);
+        for pubkey_words in orders_accounts {
+            account_metas.push(AccountMeta::new(Pubkey::new(transmute_to_bytes(&pubkey_words)), false));
+        }
+        for pubkey_words in first_event_order {
+            work.open_orders.push(Pubkey::new(transmute_to_bytes(&pubkey_words)));
+        }
+        for pubkey in [
+            &market_key.keys.market,
+            &market_key.keys.event_q,
+            &market_key.coin_wallet,
+            &market_key.pc_wallet,
+        ]
+        .iter()
+        {
+            account_metas.push(AccountMeta::new(**pubkey, false));
+        }
+        let instructions = vec![consume_events_ix(
+            dex_program,
+            account_metas,
+            market_key.events_per_worker(&config.crank),
+        )];
+        work.crank = Some((instructions, marker));
+        Ok(work)
+    }
+    /// resolves the keys of the markets in a new configuration, reusing the keys of
+    /// markets whose settings are unchanged, and swaps its hot reloadable settings into
+    /// the current configuration.
+    /// returns an error, leaving the current configuration in place, if any market
+    /// fails to resolve
+    fn reload(
+        &self,
+        rpc: &RpcPool,
+        old_config: &Configuration,
+        old_market_keys: &[ParsedMarketKeys],
+        new_config: Configuration,
+    ) -> Result<(Arc<Configuration>, Vec<ParsedMarketKeys>)> {
+        let (new_config, restart_required) = hot_reload(old_config, new_config);
+        if restart_required {
+            warn!("changes to rpc, key, log, cost ledger, history, http and health check settings require a restart and are ignored");
+        }
+        let dex_program = Pubkey::from_str(new_config.crank.dex_program.as_str())?;
+        let dex_program_changed = new_config.crank.dex_program != old_config.crank.dex_program;
+        let mut market_keys = Vec::with_capacity(new_config.crank.markets.len());
+        for market in new_config.crank.markets.iter() {
+            let unchanged = old_config
+                .crank
+                .markets
+                .iter()
+                .position(|old_market| old_market == market)
+                .filter(|_| !dex_program_changed);
+            match unchanged {
+                Some(idx) => market_keys.push(old_market_keys[idx].clone()),
+                None => {
+                    info!("resolving keys for market {}", market.name);
+                    market_keys.push(rpc.read_with(|client| market.parsed_keys(client, dex_program))?);
                 }
             }
         }
+        let new_config = Arc::new(new_config);
+        *self.config.write().unwrap() = Arc::clone(&new_config);
+        info!("reloaded configuration with {} markets", market_keys.len());
+        Ok((new_config, market_keys))
+    }
+    /// blocks until the next crank run is due, which is either when the subscriber
+    /// reports an event queue with events, or poll_delay has elapsed.
+    /// wake ups are debounced so a burst of fills results in a single crank run.
+    /// a reloaded configuration also ends the wait so it is applied immediately
+    fn wait_for_events(
+        &self,
+        config: &Configuration,
+        poll_delay: Duration,
+        exit_chan: &Receiver<bool>,
+        reload_chan: &Receiver<Configuration>,
+        wake_chan: Option<&Receiver<Wake>>,
+    ) -> Wakeup {
+        let mut woken = Woken::default();
+        // a disconnected channel is always ready, so it is replaced by one which
+        // never is instead of ending the wait
+        let never_reload = crossbeam_channel::never();
+        let never_wake = crossbeam_channel::never();
+        let mut reload_chan = reload_chan;
+        let mut wake_chan = wake_chan.unwrap_or(&never_wake);
+        let deadline = Instant::now() + poll_delay;
+        loop {
+            select! {
+                recv(exit_chan) -> _msg => return Wakeup::Exit,
+                recv(reload_chan) -> msg => match msg {
+                    Ok(new_config) => return Wakeup::Reload(Box::new(new_config)),
+                    // the config watcher stopped
+                    Err(_) => reload_chan = &never_reload,
+                },
+                recv(wake_chan) -> msg => match msg {
+                    Ok(wake) => {
+                        match wake {
+                            Wake::Market(market) => info!("woken up by event queue notification for market {}", market),
+                            Wake::All => info!("woken up to check every market after subscribing to the event queues"),
+                        }
+                        woken.insert(wake);
+                        let window = Duration::from_millis(config.crank.ws_debounce_ms);
+                        return match debounce(woken, wake_chan, exit_chan, window) {
+                            Some(woken) => Wakeup::Run(woken),
+                            None => Wakeup::Exit,
+                        };
+                    }
+                    // the subscriber stopped
+                    Err(_) => wake_chan = &never_wake,
+                },
+                default(deadline.saturating_duration_since(Instant::now())) => return Wakeup::Run(woken),
+            }
+        }
+    }
+}
+
+/// the reason the crank stopped waiting for events
+enum Wakeup {
+    Exit,
+    /// run the crank for the polled markets and the markets woken by
+    /// event queue notifications
+    Run(Woken),
+    Reload(Box<Configuration>),
+}
+
+/// returns the configured markets followed by any discovered markets which aren't configured
+fn merge_market_keys(
+    configured_keys: &[ParsedMarketKeys],
+    discovered_keys: &[ParsedMarketKeys],
+) -> Vec<ParsedMarketKeys> {
+    let mut market_keys = configured_keys.to_vec();
+    for discovered in discovered_keys.iter() {
+        if !configured_keys
+            .iter()
+            .any(|configured| configured.keys.market == discovered.keys.market)
+        {
+            market_keys.push(discovered.clone());
+        }
+    }
+    market_keys
+}
+
+/// when enabled, starts a subscriber that wakes up the crank as soon as an event queue
+/// has events, otherwise the crank only polls each market at its polling interval
+fn start_subscriber(
+    config: &Configuration,
+    market_keys: &[ParsedMarketKeys],
+) -> (Option<Receiver<Wake>>, Option<SubscriberHandle>) {
+    if !config.crank.use_ws_subscriptions {
+        return (None, None);
+    }
+    let (s, r) = crossbeam_channel::unbounded();
+    let subscriber = EventQueueSubscriber::new(
+        config.ws_rpc_url.clone(),
+        market_keys
+            .iter()
+            .map(|market_key| (market_key.keys.market, market_key.keys.event_q))
+            .collect(),
+    );
+    (Some(r), Some(subscriber.start(s)))
+}
+
+/// returns the log fields identifying a crank transaction and the markets it cranks
+fn transaction_fields(signature: Option<&Signature>, markets: &[Pubkey]) -> Vec<(&'static str, Value)> {
+    let mut fields = vec![(
+        "markets",
+        json!(markets.iter().map(|market| market.to_string()).collect::<Vec<_>>()),
+    )];
+    if let Some(signature) = signature {
+        fields.push(("signature", json!(signature.to_string())));
     }
+    fields
 }
 
 // todo(bonedaddy): remove the returned vector and just return the single instruction
@@ -357,9 +908,19 @@ pub fn get_keys_for_market<'a>(
     market: &'a Pubkey,
 ) -> Result<MarketPubkeys> {
     let account_data: Vec<u8> = client.get_account_data(&market)?;
-    let words: Cow<[u64]> = remove_dex_account_padding(&account_data)?;
+    market_keys_from_account_data(program_id, market, &account_data)
+}
+
+/// decodes the keys of a market from its account data
+#[cfg(target_endian = "little")]
+pub fn market_keys_from_account_data(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    account_data: &[u8],
+) -> Result<MarketPubkeys> {
+    let words: Cow<[u64]> = remove_dex_account_padding(account_data)?;
     let market_state: MarketState = {
-        let account_flags = Market::account_flags(&account_data)?;
+        let account_flags = Market::account_flags(account_data)?;
         if account_flags.intersects(AccountFlag::Permissioned) {
             let state = transmute_one_pedantic::<MarketStateV2>(transmute_to_bytes(&words))
                 .map_err(|e| e.without_src())?;
@@ -372,10 +933,9 @@ pub fn get_keys_for_market<'a>(
     market_state.check_flags()?;
     let vault_signer_key =
         gen_vault_signer_key(market_state.vault_signer_nonce, market, program_id)?;
-    assert_eq!(
-        transmute_to_bytes(&identity(market_state.own_address)),
-        market.as_ref()
-    );
+    if transmute_to_bytes(&identity(market_state.own_address)) != market.as_ref() {
+        return Err(format_err!("market {} own address mismatch", market));
+    }
     Ok(MarketPubkeys {
         market: *market,
         req_q: Pubkey::new(transmute_one_to_bytes(&identity(market_state.req_q))),
@@ -385,19 +945,21 @@ pub fn get_keys_for_market<'a>(
         coin_vault: Pubkey::new(transmute_one_to_bytes(&identity(market_state.coin_vault))),
         pc_vault: Pubkey::new(transmute_one_to_bytes(&identity(market_state.pc_vault))),
         vault_signer_key: vault_signer_key,
+        coin_mint: Pubkey::new(transmute_one_to_bytes(&identity(market_state.coin_mint))),
+        pc_mint: Pubkey::new(transmute_one_to_bytes(&identity(market_state.pc_mint))),
     })
 }
 
 #[cfg(target_endian = "little")]
-fn remove_dex_account_padding<'a>(data: &'a [u8]) -> Result<Cow<'a, [u64]>> {
+pub fn remove_dex_account_padding<'a>(data: &'a [u8]) -> Result<Cow<'a, [u64]>> {
     use serum_dex::state::{ACCOUNT_HEAD_PADDING, ACCOUNT_TAIL_PADDING};
-    let head = &data[..ACCOUNT_HEAD_PADDING.len()];
     if data.len() < ACCOUNT_HEAD_PADDING.len() + ACCOUNT_TAIL_PADDING.len() {
         return Err(format_err!(
             "dex account length {} is too small to contain valid padding",
             data.len()
         ));
     }
+    let head = &data[..ACCOUNT_HEAD_PADDING.len()];
     if head != ACCOUNT_HEAD_PADDING {
         return Err(format_err!("dex account head padding mismatch"));
     }
@@ -418,11 +980,26 @@ fn remove_dex_account_padding<'a>(data: &'a [u8]) -> Result<Cow<'a, [u64]>> {
 }
 
 pub fn parse_event_queue(data_words: &[u64]) -> Result<(EventQueueHeader, &[Event], &[Event])> {
-    let (header_words, event_words) = data_words.split_at(size_of::<EventQueueHeader>() >> 3);
+    let header_len = size_of::<EventQueueHeader>() >> 3;
+    if data_words.len() < header_len {
+        return Err(format_err!(
+            "event queue length {} is too small to contain its header",
+            data_words.len() << 3
+        ));
+    }
+    let (header_words, event_words) = data_words.split_at(header_len);
     let header: EventQueueHeader =
         transmute_one_pedantic(transmute_to_bytes(header_words)).map_err(|e| e.without_src())?;
     let events: &[Event] = transmute_many::<_, SingleManyGuard>(transmute_to_bytes(event_words))
         .map_err(|e| e.without_src())?;
+    if header.head() as usize > events.len() || header.count() as usize > events.len() {
+        return Err(format_err!(
+            "event queue head {} and count {} are out of bounds of its {} events",
+            header.head(),
+            header.count(),
+            events.len()
+        ));
+    }
     let (tail_seg, head_seg) = events.split_at(header.head() as usize);
     let head_len = head_seg.len().min(header.count() as usize);
     let tail_len = header.count() as usize - head_len;
@@ -433,6 +1010,63 @@ fn hash_accounts(val: &[u64; 4]) -> u64 {
     val.iter().fold(0, |a, b| b.wrapping_add(a))
 }
 
+/// groups the markets so the queues of each group fit in one getMultipleAccounts
+/// request, numbering the groups
+fn chunk_markets(market_keys: &[&ParsedMarketKeys]) -> Vec<(usize, Vec<ParsedMarketKeys>)> {
+    market_keys
+        .chunks(MAX_MULTIPLE_ACCOUNTS / 2)
+        .map(|chunk| chunk.iter().map(|market_key| (*market_key).clone()).collect())
+        .enumerate()
+        .collect()
+}
+
+/// returns the event and request queue of each market, in the order
+/// process_markets pairs the fetched accounts with the markets
+fn queue_keys(market_keys: &[ParsedMarketKeys]) -> Vec<Pubkey> {
+    market_keys
+        .iter()
+        .flat_map(|market_key| vec![market_key.keys.event_q, market_key.keys.req_q])
+        .collect()
+}
+
+/// returns false when the event queue of the market is where it was when the
+/// market was last cranked, as the crank transaction would consume nothing
+fn queue_advanced(queue_markers: &DashMap<Pubkey, QueueMarker>, market: &Pubkey, marker: &QueueMarker) -> bool {
+    match queue_markers.get(market) {
+        Some(last_marker) => *last_marker != *marker,
+        None => true,
+    }
+}
+
+/// the queue lengths of a market checked by the crank, along with its crank
+/// instructions and the state of its event queue when there is something to crank
+struct MarketWork {
+    event_q_len: usize,
+    req_q_len: usize,
+    /// open orders accounts of the crank instruction, in the order of their first event
+    open_orders: Vec<Pubkey>,
+    crank: Option<(Vec<Instruction>, QueueMarker)>,
+}
+
+/// the position of an event queue, which only advances when events
+/// are pushed onto the queue or consumed from it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueMarker {
+    pub seq_num: u64,
+    pub head: u64,
+}
+
+impl QueueMarker {
+    /// the dex doesn't expose the seq_num of a queue, so it is read from the
+    /// header words, where it follows the account flags, head and count
+    pub fn new(queue_words: &[u64], header: &EventQueueHeader) -> Self {
+        Self {
+            seq_num: queue_words[3],
+            head: header.head(),
+        }
+    }
+}
+
 #[derive(Clone, Default, Debug, PartialEq)]
 pub struct MarketPubkeys {
     pub market: Pubkey,
@@ -443,4 +1077,117 @@ pub struct MarketPubkeys {
     pub coin_vault: Pubkey,
     pub pc_vault: Pubkey,
     pub vault_signer_key: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serum_dex::state::{ACCOUNT_HEAD_PADDING, ACCOUNT_TAIL_PADDING};
+
+    /// an event queue account with room for capacity events, holding count of them
+    fn event_queue_data(head: u64, count: u64, capacity: usize) -> Vec<u8> {
+        let mut data = ACCOUNT_HEAD_PADDING.to_vec();
+        // account flags, head, count and seq_num
+        for word in [0, head, count, count].iter() {
+            data.extend_from_slice(&u64::to_le_bytes(*word));
+        }
+        data.extend(vec![0; capacity * size_of::<Event>()]);
+        data.extend_from_slice(ACCOUNT_TAIL_PADDING);
+        data
+    }
+
+    #[test]
+    fn short_accounts_are_rejected() {
+        assert!(remove_dex_account_padding(&[]).is_err());
+        assert!(remove_dex_account_padding(b"ser").is_err());
+        assert!(remove_dex_account_padding(b"serumpaddin").is_err());
+        assert!(parse_event_queue(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn mismatched_padding_is_rejected() {
+        let mut data = event_queue_data(0, 0, 1);
+        data[0] = b'x';
+        assert!(remove_dex_account_padding(&data).is_err());
+        let mut data = event_queue_data(0, 0, 1);
+        let last = data.len() - 1;
+        data[last] = b'x';
+        assert!(remove_dex_account_padding(&data).is_err());
+    }
+
+    #[test]
+    fn event_queue_wraps_around() {
+        let data = event_queue_data(3, 2, 4);
+        let words = remove_dex_account_padding(&data).unwrap();
+        let (header, seg0, seg1) = parse_event_queue(&words).unwrap();
+        assert_eq!((header.head(), header.count()), (3, 2));
+        assert_eq!((seg0.len(), seg1.len()), (1, 1));
+    }
+
+    #[test]
+    fn out_of_bounds_event_queue_is_rejected() {
+        let data = event_queue_data(0, 5, 4);
+        let words = remove_dex_account_padding(&data).unwrap();
+        assert!(parse_event_queue(&words).is_err());
+    }
+
+    #[test]
+    fn queue_marker_reads_seq_num_and_head() {
+        let mut data = event_queue_data(3, 2, 4);
+        // seq_num is the fourth header word
+        let offset = ACCOUNT_HEAD_PADDING.len() + 3 * size_of::<u64>();
+        data[offset..offset + 8].copy_from_slice(&u64::to_le_bytes(42));
+        let words = remove_dex_account_padding(&data).unwrap();
+        let (header, _, _) = parse_event_queue(&words).unwrap();
+        assert_eq!(QueueMarker::new(&words, &header), QueueMarker { seq_num: 42, head: 3 });
+    }
+
+    #[test]
+    fn unchanged_queues_are_skipped() {
+        let market = Pubkey::new_unique();
+        let marker = QueueMarker { seq_num: 10, head: 2 };
+        let queue_markers = DashMap::new();
+        assert!(queue_advanced(&queue_markers, &market, &marker));
+        queue_markers.insert(market, marker);
+        assert!(!queue_advanced(&queue_markers, &market, &marker));
+        assert!(queue_advanced(&queue_markers, &market, &QueueMarker { seq_num: 11, head: 2 }));
+        assert!(queue_advanced(&queue_markers, &market, &QueueMarker { seq_num: 10, head: 3 }));
+        assert!(queue_advanced(&queue_markers, &Pubkey::new_unique(), &marker));
+    }
+
+    fn market_keys() -> ParsedMarketKeys {
+        ParsedMarketKeys {
+            keys: MarketPubkeys {
+                market: Pubkey::new_unique(),
+                event_q: Pubkey::new_unique(),
+                req_q: Pubkey::new_unique(),
+                ..MarketPubkeys::default()
+            },
+            ..ParsedMarketKeys::default()
+        }
+    }
+
+    #[test]
+    fn markets_are_chunked_by_the_multiple_accounts_limit() {
+        let market_keys: Vec<ParsedMarketKeys> = (0..MAX_MULTIPLE_ACCOUNTS / 2 + 1).map(|_| market_keys()).collect();
+        let due: Vec<&ParsedMarketKeys> = market_keys.iter().collect();
+        let chunks = chunk_markets(&due);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!((chunks[0].0, chunks[0].1.len()), (0, MAX_MULTIPLE_ACCOUNTS / 2));
+        assert_eq!((chunks[1].0, chunks[1].1.len()), (1, 1));
+        assert_eq!(chunks[1].1[0], market_keys[MAX_MULTIPLE_ACCOUNTS / 2]);
+        assert!(chunks.iter().all(|(_, chunk)| queue_keys(chunk).len() <= MAX_MULTIPLE_ACCOUNTS));
+        assert!(chunk_markets(&[]).is_empty());
+    }
+
+    #[test]
+    fn queue_keys_pair_with_their_market() {
+        let market_keys = vec![market_keys(), market_keys()];
+        let keys = queue_keys(&market_keys);
+        for (market_key, queues) in market_keys.iter().zip(keys.chunks(2)) {
+            assert_eq!(queues, &[market_key.keys.event_q, market_key.keys.req_q][..]);
+        }
+    }
 }
@@ -0,0 +1,203 @@
+use crate::config::{Discovery, ParsedMarketKeys};
+use crate::crank::{market_keys_from_account_data, MarketPubkeys};
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use serum_dex::state::{AccountFlag, MarketState, MarketStateV2, ACCOUNT_HEAD_PADDING};
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
+use solana_client::rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType};
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
+use std::mem::size_of;
+use std::str::FromStr;
+
+/// size of the padding surrounding the state of every dex account
+const ACCOUNT_PADDING_SIZE: usize = 12;
+
+/// discovers the markets owned by the dex program using getProgramAccounts,
+/// filtered by the size and initialized market flags of market accounts and
+/// decoded like `get_keys_for_market`.
+/// markets are filtered by the include and exclude mint lists of the discovery
+/// settings, and use the configured wallets, defaulting to `payer`
+pub fn discover_markets(
+    rpc: &RpcClient,
+    program_id: &Pubkey,
+    discovery: &Discovery,
+    payer: &Pubkey,
+) -> Result<Vec<ParsedMarketKeys>> {
+    let include_mints = parse_mints(&discovery.include_mints)?;
+    let exclude_mints = parse_mints(&discovery.exclude_mints)?;
+    // the wallets are not checked by the dex when consuming events, and the payer
+    // is already writable, so it is used when no wallets are configured
+    let coin_wallet = match discovery.coin_wallet.as_ref() {
+        Some(wallet) => Pubkey::from_str(wallet)?,
+        None => *payer,
+    };
+    let pc_wallet = match discovery.pc_wallet.as_ref() {
+        Some(wallet) => Pubkey::from_str(wallet)?,
+        None => *payer,
+    };
+    let mut markets = vec![];
+    for market_size in [
+        size_of::<MarketState>() + ACCOUNT_PADDING_SIZE,
+        size_of::<MarketStateV2>() + ACCOUNT_PADDING_SIZE,
+    ]
+    .iter()
+    {
+        let accounts = rpc.get_program_accounts_with_config(
+            program_id,
+            RpcProgramAccountsConfig {
+                filters: Some(vec![
+                    RpcFilterType::DataSize(*market_size as u64),
+                    market_flags_filter(),
+                ]),
+                account_config: RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    commitment: Some(CommitmentConfig::confirmed()),
+                    ..RpcAccountInfoConfig::default()
+                },
+                ..RpcProgramAccountsConfig::default()
+            },
+        )?;
+        for (market, account) in accounts {
+            // decoding checks the account flags, skipping anything that isn't an initialized market
+            let keys = match market_keys_from_account_data(program_id, &market, &account.data) {
+                Ok(keys) => keys,
+                Err(err) => {
+                    debug!("skipping program account {}: {:#?}", market, err);
+                    continue;
+                }
+            };
+            if !mints_included(&keys, &include_mints, &exclude_mints) {
+                continue;
+            }
+            markets.push(ParsedMarketKeys {
+                keys,
+                coin_wallet,
+                pc_wallet,
+                ..ParsedMarketKeys::default()
+            });
+        }
+    }
+    // sort so that refreshes returning the same markets compare equal
+    markets.sort_by_key(|market| market.keys.market);
+    info!("discovered {} markets", markets.len());
+    Ok(markets)
+}
+
+/// matches the low byte of the account flags, the first word after the head
+/// padding, against the initialized and market bits. memcmp can only compare
+/// exact bytes, so the higher flags of permissioned markets and markets requiring
+/// a crank authority are left out, and decoding checks the full flags instead
+fn market_flags_filter() -> RpcFilterType {
+    let market_flags = AccountFlag::Initialized | AccountFlag::Market;
+    RpcFilterType::Memcmp(Memcmp {
+        offset: ACCOUNT_HEAD_PADDING.len(),
+        bytes: MemcmpEncodedBytes::Binary(
+            bs58::encode([market_flags.bits().to_le_bytes()[0]]).into_string(),
+        ),
+        encoding: None,
+    })
+}
+
+/// whether the market trades a mint of `include_mints`, when any are given,
+/// and none of `exclude_mints`
+fn mints_included(
+    keys: &MarketPubkeys,
+    include_mints: &HashSet<Pubkey>,
+    exclude_mints: &HashSet<Pubkey>,
+) -> bool {
+    if !include_mints.is_empty()
+        && !include_mints.contains(&keys.coin_mint)
+        && !include_mints.contains(&keys.pc_mint)
+    {
+        return false;
+    }
+    !exclude_mints.contains(&keys.coin_mint) && !exclude_mints.contains(&keys.pc_mint)
+}
+
+fn parse_mints(mints: &[String]) -> Result<HashSet<Pubkey>> {
+    mints
+        .iter()
+        .map(|mint| Pubkey::from_str(mint).map_err(|err| anyhow!("invalid mint {}: {}", mint, err)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn market(coin_mint: Pubkey, pc_mint: Pubkey) -> MarketPubkeys {
+        MarketPubkeys {
+            coin_mint,
+            pc_mint,
+            ..MarketPubkeys::default()
+        }
+    }
+
+    fn mints(mints: &[Pubkey]) -> HashSet<Pubkey> {
+        mints.iter().copied().collect()
+    }
+
+    #[test]
+    fn market_flags_filter_matches_the_low_byte() {
+        match market_flags_filter() {
+            RpcFilterType::Memcmp(Memcmp {
+                offset,
+                bytes: MemcmpEncodedBytes::Binary(bytes),
+                ..
+            }) => {
+                assert_eq!(offset, ACCOUNT_HEAD_PADDING.len());
+                assert_eq!(bs58::decode(bytes).into_vec().unwrap(), vec![0b11]);
+            }
+            filter => panic!("unexpected filter {:?}", filter),
+        }
+    }
+
+    #[test]
+    fn every_market_is_included_without_mint_lists() {
+        let keys = market(Pubkey::new_unique(), Pubkey::new_unique());
+        assert!(mints_included(&keys, &mints(&[]), &mints(&[])));
+    }
+
+    #[test]
+    fn markets_trading_an_included_mint_are_included() {
+        let (coin, pc, other) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let keys = market(coin, pc);
+        assert!(mints_included(&keys, &mints(&[coin]), &mints(&[])));
+        assert!(mints_included(&keys, &mints(&[other, pc]), &mints(&[])));
+        assert!(!mints_included(&keys, &mints(&[other]), &mints(&[])));
+    }
+
+    #[test]
+    fn markets_trading_an_excluded_mint_are_excluded() {
+        let (coin, pc, other) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let keys = market(coin, pc);
+        assert!(!mints_included(&keys, &mints(&[]), &mints(&[coin])));
+        assert!(!mints_included(&keys, &mints(&[]), &mints(&[pc])));
+        assert!(mints_included(&keys, &mints(&[]), &mints(&[other])));
+    }
+
+    #[test]
+    fn exclusion_wins_over_inclusion() {
+        let (coin, pc) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let keys = market(coin, pc);
+        assert!(!mints_included(&keys, &mints(&[coin]), &mints(&[pc])));
+        assert!(!mints_included(&keys, &mints(&[coin]), &mints(&[coin])));
+        assert!(mints_included(
+            &keys,
+            &mints(&[coin]),
+            &mints(&[Pubkey::new_unique()])
+        ));
+    }
+}
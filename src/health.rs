@@ -0,0 +1,138 @@
+use crate::config::Configuration;
+use crate::confirm::MAX_CONFIRMATION_TIME;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// tracks the liveness and readiness of the crank service
+pub struct Health {
+    ready: AtomicBool,
+    /// unix timestamp in seconds of the last completed crank loop iteration
+    last_iteration: AtomicU64,
+    /// last known total balance of the payers in lamports, u64::MAX if unknown
+    payer_balance: AtomicU64,
+    /// seconds since the last iteration after which the service is unhealthy,
+    /// updated when the polling intervals are reloaded
+    max_iteration_age: AtomicU64,
+    min_payer_balance: u64,
+}
+
+impl Health {
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            ready: AtomicBool::new(false),
+            // count from startup so resolving the markets is also covered
+            last_iteration: AtomicU64::new(unix_timestamp()),
+            payer_balance: AtomicU64::new(u64::MAX),
+            max_iteration_age: AtomicU64::new(max_iteration_age(config).as_secs()),
+            min_payer_balance: config.health_check.min_payer_balance,
+        }
+    }
+    /// applies a reloaded configuration, the health check settings themselves
+    /// require a restart but the iteration age limit follows the polling intervals
+    pub fn set_config(&self, config: &Configuration) {
+        self.max_iteration_age
+            .store(max_iteration_age(config).as_secs(), Ordering::SeqCst);
+    }
+    /// marks the service as ready, which happens once the market keys have been resolved
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+    /// records the completion of a crank loop iteration
+    pub fn record_iteration(&self) {
+        self.last_iteration
+            .store(unix_timestamp(), Ordering::SeqCst);
+    }
+    pub fn set_payer_balance(&self, lamports: u64) {
+        self.payer_balance.store(lamports, Ordering::SeqCst);
+    }
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+    /// returns an error describing why the service is unhealthy, if it is
+    pub fn check(&self) -> Result<(), String> {
+        let last_iteration = self.last_iteration.load(Ordering::SeqCst);
+        let iteration_age = unix_timestamp().saturating_sub(last_iteration);
+        if iteration_age > self.max_iteration_age.load(Ordering::SeqCst) {
+            return Err(format!(
+                "no crank loop iteration completed in the last {} seconds",
+                iteration_age
+            ));
+        }
+        let payer_balance = self.payer_balance.load(Ordering::SeqCst);
+        if payer_balance != u64::MAX && payer_balance < self.min_payer_balance {
+            return Err(format!(
+                "total payer balance {} is below the minimum of {} lamports",
+                payer_balance, self.min_payer_balance
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// the longest time a crank loop iteration may take, which is a multiple of the
+/// largest polling interval of any market, plus the time spent waiting for the
+/// crank transactions of an iteration to confirm
+fn max_iteration_age(config: &Configuration) -> Duration {
+    let poll_interval = config
+        .crank
+        .markets
+        .iter()
+        .filter_map(|market| market.max_wait_for_events_delay)
+        .fold(config.crank.max_wait_for_events_delay, u64::max);
+    Duration::from_secs(poll_interval * config.health_check.max_iteration_delay_multiple)
+        + MAX_CONFIRMATION_TIME
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iteration_age_limit_covers_the_largest_poll_interval_and_confirmations() {
+        let mut config = Configuration::default();
+        config.crank.max_wait_for_events_delay = 10;
+        config.health_check.max_iteration_delay_multiple = 5;
+        let health = Health::new(&config);
+        assert_eq!(
+            health.max_iteration_age.load(Ordering::SeqCst),
+            50 + MAX_CONFIRMATION_TIME.as_secs()
+        );
+        assert!(health.check().is_ok());
+        config.crank.markets[0].max_wait_for_events_delay = Some(30);
+        assert_eq!(
+            Health::new(&config)
+                .max_iteration_age
+                .load(Ordering::SeqCst),
+            150 + MAX_CONFIRMATION_TIME.as_secs()
+        );
+    }
+
+    #[test]
+    fn reloading_updates_the_iteration_age_limit() {
+        let mut config = Configuration::default();
+        config.crank.max_wait_for_events_delay = 10;
+        config.health_check.max_iteration_delay_multiple = 5;
+        let health = Health::new(&config);
+        config.crank.max_wait_for_events_delay = 20;
+        health.set_config(&config);
+        assert_eq!(
+            health.max_iteration_age.load(Ordering::SeqCst),
+            100 + MAX_CONFIRMATION_TIME.as_secs()
+        );
+    }
+
+    #[test]
+    fn health_check_fields_default_individually() {
+        let health_check: crate::config::HealthCheck =
+            serde_yaml::from_str("min_payer_balance: 1000").unwrap();
+        assert_eq!(health_check.min_payer_balance, 1000);
+        assert_eq!(health_check.max_iteration_delay_multiple, 5);
+    }
+}
@@ -0,0 +1,752 @@
+use crate::config::History;
+use crate::crank::QueueMarker;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// time to wait for the database to be unlocked by another connection
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// time between deletions of the iterations older than the retention period
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS iterations (
+    id INTEGER PRIMARY KEY,
+    iteration INTEGER NOT NULL,
+    started_at INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS iterations_started_at ON iterations (started_at);
+CREATE TABLE IF NOT EXISTS market_checks (
+    iteration_id INTEGER NOT NULL REFERENCES iterations (id) ON DELETE CASCADE,
+    market TEXT NOT NULL,
+    event_q_len INTEGER,
+    req_q_len INTEGER,
+    accounts TEXT NOT NULL,
+    error TEXT
+);
+CREATE INDEX IF NOT EXISTS market_checks_market ON market_checks (market, iteration_id);
+CREATE INDEX IF NOT EXISTS market_checks_iteration ON market_checks (iteration_id);
+CREATE TABLE IF NOT EXISTS transactions (
+    iteration_id INTEGER NOT NULL REFERENCES iterations (id) ON DELETE CASCADE,
+    signature TEXT,
+    payer TEXT NOT NULL,
+    markets TEXT NOT NULL,
+    status TEXT NOT NULL,
+    slot INTEGER,
+    fee INTEGER,
+    error TEXT
+);
+CREATE INDEX IF NOT EXISTS transactions_iteration ON transactions (iteration_id);
+CREATE TABLE IF NOT EXISTS queue_markers (
+    market TEXT PRIMARY KEY,
+    seq_num INTEGER NOT NULL,
+    head INTEGER NOT NULL,
+    cranked_at INTEGER NOT NULL
+);
+";
+
+/// a crank loop iteration, the markets whose queues were checked
+/// and the crank transactions sent for them
+#[derive(Clone, Debug, Serialize)]
+pub struct IterationRecord {
+    pub iteration: u64,
+    /// unix timestamp in milliseconds
+    pub started_at: i64,
+    pub duration_ms: u64,
+    pub markets: Vec<MarketCheck>,
+    pub transactions: Vec<TransactionRecord>,
+    /// queue state of the markets included in a confirmed crank transaction
+    #[serde(skip)]
+    pub markers: Vec<(Pubkey, QueueMarker)>,
+}
+
+/// the queues of a market checked during an iteration, with the open orders
+/// accounts of its crank instruction, or the error processing the market
+#[derive(Clone, Debug, Serialize)]
+pub struct MarketCheck {
+    pub market: String,
+    pub event_q_len: Option<u64>,
+    pub req_q_len: Option<u64>,
+    pub accounts: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TransactionRecord {
+    /// not set when the transaction failed to send
+    pub signature: Option<String>,
+    pub payer: String,
+    pub markets: Vec<String>,
+    pub status: TransactionStatus,
+    pub slot: Option<u64>,
+    /// fee in lamports, not set when the transaction didn't land
+    pub fee: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionStatus {
+    Confirmed,
+    Failed,
+    Expired,
+    SendError,
+}
+
+/// selects the iterations returned by HistoryStore::query
+#[derive(Clone, Debug, Default)]
+pub struct HistoryQuery {
+    /// only iterations which checked one of these markets, every iteration when empty
+    pub markets: Vec<String>,
+    /// unix timestamps in milliseconds bounding when the iterations started
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    /// max number of iterations returned, the most recent first
+    pub limit: u64,
+}
+
+/// an sqlite database recording every crank loop iteration along with the queue
+/// state of the last confirmed crank of each market, which is restored on restart.
+/// the database is in wal mode, so it can be queried while the crank is running
+pub struct HistoryStore {
+    conn: Connection,
+    retention_days: u64,
+    last_prune: Option<Instant>,
+}
+
+impl IterationRecord {
+    pub fn new(iteration: u64) -> Self {
+        Self {
+            iteration,
+            started_at: Utc::now().timestamp_millis(),
+            duration_ms: 0,
+            markets: vec![],
+            transactions: vec![],
+            markers: vec![],
+        }
+    }
+}
+
+impl TransactionRecord {
+    pub fn new(
+        signature: Option<&Signature>,
+        payer: &Pubkey,
+        markets: &[Pubkey],
+        status: TransactionStatus,
+    ) -> Self {
+        Self {
+            signature: signature.map(Signature::to_string),
+            payer: payer.to_string(),
+            markets: markets.iter().map(Pubkey::to_string).collect(),
+            status,
+            slot: None,
+            fee: None,
+            error: None,
+        }
+    }
+}
+
+impl TransactionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionStatus::Confirmed => "confirmed",
+            TransactionStatus::Failed => "failed",
+            TransactionStatus::Expired => "expired",
+            TransactionStatus::SendError => "send_error",
+        }
+    }
+}
+
+impl FromStr for TransactionStatus {
+    type Err = anyhow::Error;
+    fn from_str(status: &str) -> Result<Self> {
+        match status {
+            "confirmed" => Ok(TransactionStatus::Confirmed),
+            "failed" => Ok(TransactionStatus::Failed),
+            "expired" => Ok(TransactionStatus::Expired),
+            "send_error" => Ok(TransactionStatus::SendError),
+            _ => Err(anyhow!("unknown transaction status {}", status)),
+        }
+    }
+}
+
+impl HistoryStore {
+    /// opens the database, creating it when it doesn't exist
+    pub fn open(config: &History) -> Result<Self> {
+        let conn = Connection::open(&config.database)
+            .map_err(|err| anyhow!("failed to open history {}: {}", config.database, err))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.query_row("PRAGMA journal_mode = WAL", params![], |row| {
+            row.get::<_, String>(0)
+        })?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn,
+            retention_days: config.retention_days,
+            last_prune: None,
+        })
+    }
+    /// writes the iteration and the queue markers of the markets
+    /// it cranked, deleting iterations past the retention period
+    pub fn record(&mut self, record: &IterationRecord) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO iterations (iteration, started_at, duration_ms) VALUES (?1, ?2, ?3)",
+            params![
+                record.iteration as i64,
+                record.started_at,
+                record.duration_ms as i64
+            ],
+        )?;
+        let iteration_id = tx.last_insert_rowid();
+        for check in record.markets.iter() {
+            tx.prepare_cached(
+                "INSERT INTO market_checks
+                (iteration_id, market, event_q_len, req_q_len, accounts, error)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .execute(params![
+                iteration_id,
+                check.market,
+                check.event_q_len.map(|len| len as i64),
+                check.req_q_len.map(|len| len as i64),
+                check.accounts.join(","),
+                check.error,
+            ])?;
+        }
+        for transaction in record.transactions.iter() {
+            tx.prepare_cached(
+                "INSERT INTO transactions
+                (iteration_id, signature, payer, markets, status, slot, fee, error)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?
+            .execute(params![
+                iteration_id,
+                transaction.signature,
+                transaction.payer,
+                transaction.markets.join(","),
+                transaction.status.as_str(),
+                transaction.slot.map(|slot| slot as i64),
+                transaction.fee.map(|fee| fee as i64),
+                transaction.error,
+            ])?;
+        }
+        for (market, marker) in record.markers.iter() {
+            tx.prepare_cached(
+                "INSERT OR REPLACE INTO queue_markers (market, seq_num, head, cranked_at)
+                VALUES (?1, ?2, ?3, ?4)",
+            )?
+            .execute(params![
+                market.to_string(),
+                marker.seq_num as i64,
+                marker.head as i64,
+                record.started_at,
+            ])?;
+        }
+        tx.commit()?;
+        let prune_due = self
+            .last_prune
+            .map_or(true, |last_prune| last_prune.elapsed() >= PRUNE_INTERVAL);
+        if self.retention_days > 0 && prune_due {
+            let cutoff =
+                Utc::now().timestamp_millis() - self.retention_days as i64 * MILLIS_PER_DAY;
+            self.conn.execute(
+                "DELETE FROM iterations WHERE started_at < ?1",
+                params![cutoff],
+            )?;
+            self.last_prune = Some(Instant::now());
+        }
+        Ok(())
+    }
+    /// returns the queue state of the last confirmed crank of each
+    /// market, along with when the crank's iteration started
+    pub fn queue_markers(&self) -> Result<Vec<(Pubkey, QueueMarker, SystemTime)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT market, seq_num, head, cranked_at FROM queue_markers")?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?;
+        let mut markers = vec![];
+        for row in rows {
+            let (market, seq_num, head, cranked_at) = row?;
+            let marker = QueueMarker {
+                seq_num: seq_num as u64,
+                head: head as u64,
+            };
+            let cranked_at = UNIX_EPOCH + Duration::from_millis(cranked_at.max(0) as u64);
+            markers.push((Pubkey::from_str(&market)?, marker, cranked_at));
+        }
+        Ok(markers)
+    }
+    /// returns the iterations matching the query, the most recent first. when
+    /// filtering by market, only the checks and transactions of those markets
+    /// are included in each iteration
+    pub fn query(&self, query: &HistoryQuery) -> Result<Vec<IterationRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, iteration, started_at, duration_ms FROM iterations
+            WHERE started_at >= ?1 AND started_at < ?2
+            AND (?3 = '' OR id IN (
+                SELECT iteration_id FROM market_checks
+                WHERE instr(',' || ?3 || ',', ',' || market || ',') > 0
+            ))
+            ORDER BY started_at DESC LIMIT ?4",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                query.since.unwrap_or(0),
+                query.until.unwrap_or(i64::MAX),
+                query.markets.join(","),
+                query.limit as i64
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    IterationRecord {
+                        iteration: row.get::<_, i64>(1)? as u64,
+                        started_at: row.get(2)?,
+                        duration_ms: row.get::<_, i64>(3)? as u64,
+                        markets: vec![],
+                        transactions: vec![],
+                        markers: vec![],
+                    },
+                ))
+            },
+        )?;
+        let mut iterations = vec![];
+        for row in rows {
+            let (iteration_id, mut record) = row?;
+            record.markets = self.market_checks(iteration_id)?;
+            record.transactions = self.transactions(iteration_id)?;
+            if !query.markets.is_empty() {
+                record
+                    .markets
+                    .retain(|check| query.markets.contains(&check.market));
+                record.transactions.retain(|transaction| {
+                    transaction
+                        .markets
+                        .iter()
+                        .any(|market| query.markets.contains(market))
+                });
+            }
+            iterations.push(record);
+        }
+        Ok(iterations)
+    }
+    fn market_checks(&self, iteration_id: i64) -> Result<Vec<MarketCheck>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT market, event_q_len, req_q_len, accounts, error
+            FROM market_checks WHERE iteration_id = ?1",
+        )?;
+        let rows = stmt.query_map(params![iteration_id], |row| {
+            Ok(MarketCheck {
+                market: row.get(0)?,
+                event_q_len: row.get::<_, Option<i64>>(1)?.map(|len| len as u64),
+                req_q_len: row.get::<_, Option<i64>>(2)?.map(|len| len as u64),
+                accounts: split_list(row, 3)?,
+                error: row.get(4)?,
+            })
+        })?;
+        let checks = rows.collect::<rusqlite::Result<_>>()?;
+        Ok(checks)
+    }
+    fn transactions(&self, iteration_id: i64) -> Result<Vec<TransactionRecord>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT signature, payer, markets, status, slot, fee, error
+            FROM transactions WHERE iteration_id = ?1",
+        )?;
+        let rows = stmt.query_map(params![iteration_id], |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                row.get::<_, String>(1)?,
+                split_list(row, 2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+                row.get::<_, Option<i64>>(5)?,
+                row.get::<_, Option<String>>(6)?,
+            ))
+        })?;
+        let mut transactions = vec![];
+        for row in rows {
+            let (signature, payer, markets, status, slot, fee, error) = row?;
+            transactions.push(TransactionRecord {
+                signature,
+                payer,
+                markets,
+                status: status.parse()?,
+                slot: slot.map(|slot| slot as u64),
+                fee: fee.map(|fee| fee as u64),
+                error,
+            });
+        }
+        Ok(transactions)
+    }
+}
+
+/// formats the iterations as a table of market checks followed by a table of transactions
+pub fn to_table(iterations: &[IterationRecord], names: &HashMap<String, String>) -> String {
+    let mut out = format!(
+        "{:<24} {:>9} {:<44} {:<16} {:>9} {:>9} {:>8}  {}\n",
+        "time", "iteration", "market", "name", "event q", "req q", "accounts", "error"
+    );
+    for record in iterations.iter() {
+        for check in record.markets.iter() {
+            out.push_str(&format!(
+                "{:<24} {:>9} {:<44} {:<16} {:>9} {:>9} {:>8}  {}\n",
+                format_time(record.started_at),
+                record.iteration,
+                check.market,
+                names.get(&check.market).map_or("", String::as_str),
+                display(check.event_q_len),
+                display(check.req_q_len),
+                check.accounts.len(),
+                check.error.as_deref().unwrap_or("")
+            ));
+        }
+    }
+    out.push_str(&format!(
+        "\n{:<24} {:>9} {:<88} {:<10} {:>10} {:>8}  {}\n",
+        "time", "iteration", "signature", "status", "slot", "fee", "markets"
+    ));
+    for record in iterations.iter() {
+        for transaction in record.transactions.iter() {
+            let mut markets = transaction.markets.join(", ");
+            if let Some(error) = transaction.error.as_ref() {
+                markets.push_str(&format!(" ({})", error));
+            }
+            out.push_str(&format!(
+                "{:<24} {:>9} {:<88} {:<10} {:>10} {:>8}  {}\n",
+                format_time(record.started_at),
+                record.iteration,
+                transaction.signature.as_deref().unwrap_or(""),
+                transaction.status.as_str(),
+                display(transaction.slot),
+                display(transaction.fee),
+                markets
+            ));
+        }
+    }
+    out
+}
+
+/// parses an rfc3339 timestamp, or a duration before now such as `30m`, `12h`
+/// or `7d`, into a unix timestamp in milliseconds
+pub fn parse_time(value: &str) -> Result<i64> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp_millis());
+    }
+    let amount = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let millis_per_unit = match &value[amount.len()..] {
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => MILLIS_PER_DAY,
+        _ => {
+            return Err(anyhow!(
+                "invalid time {}, expected an rfc3339 timestamp or a duration such as 12h",
+                value
+            ))
+        }
+    };
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| anyhow!("invalid duration {}", value))?;
+    Ok(Utc::now().timestamp_millis() - amount * millis_per_unit)
+}
+
+fn format_time(millis: i64) -> String {
+    Utc.timestamp_millis(millis)
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn display(value: Option<u64>) -> String {
+    value.map_or_else(String::new, |value| value.to_string())
+}
+
+/// reads a comma separated list column
+fn split_list(row: &Row, idx: usize) -> rusqlite::Result<Vec<String>> {
+    let list: String = row.get(idx)?;
+    Ok(list
+        .split(',')
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// a store in a new database file, removed when the test finishes
+    struct TestStore {
+        store: Option<HistoryStore>,
+        path: String,
+    }
+
+    impl TestStore {
+        fn open(name: &str, retention_days: u64) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!(
+                    "crank-history-test-{}-{}.db",
+                    std::process::id(),
+                    name
+                ))
+                .to_string_lossy()
+                .into_owned();
+            remove_database(&path);
+            let store = HistoryStore::open(&History {
+                database: path.clone(),
+                retention_days,
+            })
+            .unwrap();
+            Self {
+                store: Some(store),
+                path,
+            }
+        }
+        fn store(&mut self) -> &mut HistoryStore {
+            self.store.as_mut().unwrap()
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            // close the database before removing its files
+            self.store = None;
+            remove_database(&self.path);
+        }
+    }
+
+    fn remove_database(path: &str) {
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    fn check(market: &str) -> MarketCheck {
+        MarketCheck {
+            market: market.to_string(),
+            event_q_len: Some(3),
+            req_q_len: Some(0),
+            accounts: vec!["a".to_string(), "b".to_string()],
+            error: None,
+        }
+    }
+
+    fn record(iteration: u64, started_at: i64, markets: &[&str]) -> IterationRecord {
+        IterationRecord {
+            iteration,
+            started_at,
+            duration_ms: 10,
+            markets: markets.iter().map(|market| check(market)).collect(),
+            transactions: vec![],
+            markers: vec![],
+        }
+    }
+
+    fn iterations(store: &HistoryStore, query: HistoryQuery) -> Vec<u64> {
+        store
+            .query(&query)
+            .unwrap()
+            .iter()
+            .map(|record| record.iteration)
+            .collect()
+    }
+
+    #[test]
+    fn durations_are_parsed_before_now() {
+        let before = Utc::now().timestamp_millis();
+        let time = parse_time("12h").unwrap();
+        let after = Utc::now().timestamp_millis();
+        let twelve_hours = 12 * 60 * 60 * 1000;
+        assert!(time >= before - twelve_hours && time <= after - twelve_hours);
+        let time = parse_time("7d").unwrap();
+        assert!(time <= after - 7 * MILLIS_PER_DAY);
+        assert!(parse_time("30m").unwrap() > parse_time("1h").unwrap());
+    }
+
+    #[test]
+    fn rfc3339_timestamps_are_parsed() {
+        assert_eq!(
+            parse_time("2021-08-01T12:00:00Z").unwrap(),
+            1_627_819_200_000
+        );
+        assert_eq!(
+            parse_time("2021-08-01T14:00:00.500+02:00").unwrap(),
+            1_627_819_200_500
+        );
+    }
+
+    #[test]
+    fn invalid_times_are_rejected() {
+        for value in ["", "12", "12w", "h", "twelve h", "2021-08-01"].iter() {
+            assert!(parse_time(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn iterations_are_filtered_by_market_and_time() {
+        let mut test_store = TestStore::open("query", 0);
+        let store = test_store.store();
+        store.record(&record(1, 1_000, &["market1"])).unwrap();
+        store
+            .record(&record(2, 2_000, &["market1", "market2"]))
+            .unwrap();
+        store.record(&record(3, 3_000, &["market3"])).unwrap();
+        let query = HistoryQuery {
+            limit: 10,
+            ..HistoryQuery::default()
+        };
+        assert_eq!(iterations(store, query.clone()), vec![3, 2, 1]);
+        assert_eq!(
+            iterations(
+                store,
+                HistoryQuery {
+                    limit: 2,
+                    ..query.clone()
+                }
+            ),
+            vec![3, 2]
+        );
+        assert_eq!(
+            iterations(
+                store,
+                HistoryQuery {
+                    since: Some(2_000),
+                    until: Some(3_000),
+                    ..query.clone()
+                }
+            ),
+            vec![2]
+        );
+        let records = store
+            .query(&HistoryQuery {
+                markets: vec!["market2".to_string(), "market3".to_string()],
+                ..query
+            })
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].iteration, 3);
+        // only the checks of the queried markets are included
+        assert_eq!(records[1].markets.len(), 1);
+        assert_eq!(records[1].markets[0].market, "market2");
+        assert_eq!(records[1].markets[0].accounts, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn transactions_are_recorded() {
+        let mut test_store = TestStore::open("transactions", 0);
+        let store = test_store.store();
+        let payer = Pubkey::new_unique();
+        let markets = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut record = record(1, 1_000, &[]);
+        let mut confirmed = TransactionRecord::new(
+            Some(&Signature::default()),
+            &payer,
+            &markets,
+            TransactionStatus::Confirmed,
+        );
+        confirmed.slot = Some(100);
+        confirmed.fee = Some(5_000);
+        let mut send_error =
+            TransactionRecord::new(None, &payer, &markets[1..], TransactionStatus::SendError);
+        send_error.error = Some("blockhash not found".to_string());
+        record.transactions = vec![confirmed, send_error];
+        store.record(&record).unwrap();
+        let records = store
+            .query(&HistoryQuery {
+                limit: 1,
+                ..HistoryQuery::default()
+            })
+            .unwrap();
+        let transactions = &records[0].transactions;
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].status, TransactionStatus::Confirmed);
+        assert_eq!(transactions[0].markets.len(), 2);
+        assert_eq!(
+            (transactions[0].slot, transactions[0].fee),
+            (Some(100), Some(5_000))
+        );
+        assert_eq!(transactions[1].signature, None);
+        assert_eq!(transactions[1].status, TransactionStatus::SendError);
+        assert_eq!(
+            transactions[1].error.as_deref(),
+            Some("blockhash not found")
+        );
+    }
+
+    #[test]
+    fn the_last_queue_marker_of_each_market_is_kept() {
+        let mut test_store = TestStore::open("markers", 0);
+        let store = test_store.store();
+        let market = Pubkey::new_unique();
+        let mut first = record(1, 1_000, &[]);
+        first.markers = vec![(
+            market,
+            QueueMarker {
+                seq_num: 5,
+                head: 1,
+            },
+        )];
+        store.record(&first).unwrap();
+        let mut second = record(2, 2_000, &[]);
+        second.markers = vec![(
+            market,
+            QueueMarker {
+                seq_num: 9,
+                head: 3,
+            },
+        )];
+        store.record(&second).unwrap();
+        let markers = store.queue_markers().unwrap();
+        assert_eq!(
+            markers,
+            vec![(
+                market,
+                QueueMarker {
+                    seq_num: 9,
+                    head: 3
+                },
+                UNIX_EPOCH + Duration::from_millis(2_000)
+            )]
+        );
+    }
+
+    #[test]
+    fn iterations_past_retention_are_deleted() {
+        let mut test_store = TestStore::open("retention", 1);
+        let store = test_store.store();
+        let now = Utc::now().timestamp_millis();
+        store
+            .record(&record(1, now - 2 * MILLIS_PER_DAY, &["market1"]))
+            .unwrap();
+        store.record(&record(2, now, &["market1"])).unwrap();
+        let query = HistoryQuery {
+            limit: 10,
+            ..HistoryQuery::default()
+        };
+        assert_eq!(iterations(store, query), vec![2]);
+        let checks: i64 = store
+            .conn
+            .query_row("SELECT COUNT(*) FROM market_checks", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(checks, 1);
+    }
+}
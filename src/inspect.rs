@@ -0,0 +1,455 @@
+use crate::crank::{
+    get_keys_for_market, market_keys_from_account_data, parse_event_queue,
+    remove_dex_account_padding, MarketPubkeys,
+};
+use anyhow::{anyhow, Result};
+use safe_transmute::to_bytes::transmute_to_bytes;
+use serde::Serialize;
+use serde_json::json;
+use serum_dex::state::EventView;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::pubkey::Pubkey;
+use std::borrow::Cow;
+use std::fs;
+
+/// number of words in the header of a dex queue, the account flags, head, count and seq_num
+const QUEUE_HEADER_WORDS: usize = 4;
+
+/// where to read the accounts of the inspected market from. accounts without
+/// a file are fetched from the rpc node, so that inspecting works offline when
+/// every file is given. files contain the raw account data, as written by
+/// `solana account <address> --output-file <file>`
+#[derive(Clone, Debug, Default)]
+pub struct InspectSource {
+    pub market_file: Option<String>,
+    pub event_queue_file: Option<String>,
+    pub request_queue_file: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueueHeaderReport {
+    pub head: u64,
+    pub count: u64,
+    pub seq_num: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EventReport {
+    /// position of the event in the queue, starting from the head
+    pub index: usize,
+    pub event_type: &'static str,
+    pub side: String,
+    pub maker: bool,
+    pub owner: String,
+    pub owner_slot: u8,
+    /// order ids are 128 bit so they are reported as strings
+    pub order_id: String,
+    pub client_order_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub native_qty_paid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub native_qty_received: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub native_fee_or_rebate: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub native_qty_unlocked: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub native_qty_still_locked: Option<u64>,
+}
+
+/// decoded state of a market's event and request queues
+#[derive(Clone, Debug)]
+pub struct InspectReport {
+    pub keys: MarketPubkeys,
+    pub event_queue: QueueHeaderReport,
+    pub request_queue: QueueHeaderReport,
+    pub events: Vec<EventReport>,
+}
+
+/// decodes the keys and queues of the market, reading accounts from
+/// the files of `source` when given and from the rpc node otherwise
+pub fn inspect_market(
+    rpc_url: &str,
+    program_id: &Pubkey,
+    market: &Pubkey,
+    source: &InspectSource,
+) -> Result<InspectReport> {
+    let rpc = RpcClient::new(rpc_url.to_string());
+    let keys = match source.market_file.as_ref() {
+        Some(file) => market_keys_from_account_data(program_id, market, &fs::read(file)?)?,
+        None => get_keys_for_market(&rpc, program_id, market)?,
+    };
+    let event_q_data = account_data(&rpc, &keys.event_q, source.event_queue_file.as_ref())?;
+    let req_q_data = account_data(&rpc, &keys.req_q, source.request_queue_file.as_ref())?;
+    decode_queues(keys, &event_q_data, &req_q_data)
+}
+
+/// decodes the event and request queue accounts of the market
+fn decode_queues(
+    keys: MarketPubkeys,
+    event_q_data: &[u8],
+    req_q_data: &[u8],
+) -> Result<InspectReport> {
+    let event_q_words: Cow<[u64]> = remove_dex_account_padding(event_q_data)?;
+    let (_header, seg0, seg1) = parse_event_queue(&event_q_words)?;
+    let event_queue = queue_header(&event_q_words)?;
+    let mut events = Vec::with_capacity(seg0.len() + seg1.len());
+    for (index, event) in seg0.iter().chain(seg1.iter()).enumerate() {
+        let view = event
+            .as_view()
+            .map_err(|err| anyhow!("failed to decode event {}: {:?}", index, err))?;
+        events.push(event_report(index, view));
+    }
+    // the request queue header shares the layout of the event queue header
+    let req_q_words: Cow<[u64]> = remove_dex_account_padding(req_q_data)?;
+    let request_queue = queue_header(&req_q_words)?;
+    Ok(InspectReport {
+        keys,
+        event_queue,
+        request_queue,
+        events,
+    })
+}
+
+impl InspectReport {
+    pub fn to_json(&self) -> Result<String> {
+        let value = json!({
+            "keys": {
+                "market": self.keys.market.to_string(),
+                "req_q": self.keys.req_q.to_string(),
+                "event_q": self.keys.event_q.to_string(),
+                "bids": self.keys.bids.to_string(),
+                "asks": self.keys.asks.to_string(),
+                "coin_vault": self.keys.coin_vault.to_string(),
+                "pc_vault": self.keys.pc_vault.to_string(),
+                "vault_signer_key": self.keys.vault_signer_key.to_string(),
+                "coin_mint": self.keys.coin_mint.to_string(),
+                "pc_mint": self.keys.pc_mint.to_string(),
+            },
+            "event_queue": self.event_queue,
+            "request_queue": self.request_queue,
+            "events": self.events,
+        });
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+    pub fn to_table(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("{:#?}\n\n", self.keys));
+        for (name, header) in [
+            ("event queue", &self.event_queue),
+            ("request queue", &self.request_queue),
+        ]
+        .iter()
+        {
+            out.push_str(&format!(
+                "{}: head {}, count {}, seq_num {}\n",
+                name, header.head, header.count, header.seq_num
+            ));
+        }
+        out.push_str(&format!(
+            "\n{:>5} {:<5} {:<4} {:<5} {:<44} {:>4} {:>40} {:>20} {:>20} {:>20} {:>20}\n",
+            "#",
+            "type",
+            "side",
+            "maker",
+            "owner",
+            "slot",
+            "order id",
+            "client order id",
+            "paid / unlocked",
+            "received / locked",
+            "fee or rebate",
+        ));
+        for event in self.events.iter() {
+            out.push_str(&format!(
+                "{:>5} {:<5} {:<4} {:<5} {:<44} {:>4} {:>40} {:>20} {:>20} {:>20} {:>20}\n",
+                event.index,
+                event.event_type,
+                event.side,
+                event.maker,
+                event.owner,
+                event.owner_slot,
+                event.order_id,
+                display(event.client_order_id),
+                display(event.native_qty_paid.or(event.native_qty_unlocked)),
+                display(event.native_qty_received.or(event.native_qty_still_locked)),
+                display(event.native_fee_or_rebate),
+            ));
+        }
+        out
+    }
+}
+
+/// returns the account data from the file when given, or from the rpc node
+fn account_data(rpc: &RpcClient, account: &Pubkey, file: Option<&String>) -> Result<Vec<u8>> {
+    match file {
+        Some(file) => Ok(fs::read(file)?),
+        None => Ok(rpc.get_account_data(account)?),
+    }
+}
+
+/// the dex doesn't expose the seq_num of a queue, so the header is read from its words
+fn queue_header(words: &[u64]) -> Result<QueueHeaderReport> {
+    if words.len() < QUEUE_HEADER_WORDS {
+        return Err(anyhow!("queue account is too small to contain a header"));
+    }
+    Ok(QueueHeaderReport {
+        head: words[1],
+        count: words[2],
+        seq_num: words[3],
+    })
+}
+
+fn event_report(index: usize, view: EventView) -> EventReport {
+    match view {
+        EventView::Fill {
+            side,
+            maker,
+            native_qty_paid,
+            native_qty_received,
+            native_fee_or_rebate,
+            order_id,
+            owner,
+            owner_slot,
+            client_order_id,
+            ..
+        } => EventReport {
+            index,
+            event_type: "fill",
+            side: format!("{:?}", side),
+            maker,
+            owner: Pubkey::new(transmute_to_bytes(&owner)).to_string(),
+            owner_slot,
+            order_id: order_id.to_string(),
+            client_order_id: client_order_id.map(|id| id.get()),
+            native_qty_paid: Some(native_qty_paid),
+            native_qty_received: Some(native_qty_received),
+            native_fee_or_rebate: Some(native_fee_or_rebate),
+            native_qty_unlocked: None,
+            native_qty_still_locked: None,
+        },
+        EventView::Out {
+            side,
+            native_qty_unlocked,
+            native_qty_still_locked,
+            order_id,
+            owner,
+            owner_slot,
+            client_order_id,
+            ..
+        } => EventReport {
+            index,
+            event_type: "out",
+            side: format!("{:?}", side),
+            maker: false,
+            owner: Pubkey::new(transmute_to_bytes(&owner)).to_string(),
+            owner_slot,
+            order_id: order_id.to_string(),
+            client_order_id: client_order_id.map(|id| id.get()),
+            native_qty_paid: None,
+            native_qty_received: None,
+            native_fee_or_rebate: None,
+            native_qty_unlocked: Some(native_qty_unlocked),
+            native_qty_still_locked: Some(native_qty_still_locked),
+        },
+    }
+}
+
+fn display(value: Option<u64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safe_transmute::to_bytes::transmute_one_to_bytes;
+    use serde_json::Value;
+    use serum_dex::fees::FeeTier;
+    use serum_dex::matching::Side;
+    use serum_dex::state::{Event, ACCOUNT_HEAD_PADDING, ACCOUNT_TAIL_PADDING};
+    use std::num::NonZeroU64;
+
+    /// nothing listens on this port, so requests fail right away
+    const UNREACHABLE_URL: &str = "http://127.0.0.1:1";
+
+    /// a padded queue account with the header words followed by the events
+    fn queue_data(header: [u64; 4], events: &[Event], capacity: usize) -> Vec<u8> {
+        let mut data = ACCOUNT_HEAD_PADDING.to_vec();
+        for word in header.iter() {
+            data.extend_from_slice(&u64::to_le_bytes(*word));
+        }
+        for event in events.iter() {
+            data.extend_from_slice(transmute_one_to_bytes(event));
+        }
+        data.extend(vec![
+            0;
+            (capacity - events.len()) * std::mem::size_of::<Event>()
+        ]);
+        data.extend_from_slice(ACCOUNT_TAIL_PADDING);
+        data
+    }
+
+    fn owner_words(owner: &Pubkey) -> [u64; 4] {
+        let mut words = [0_u64; 4];
+        for (word, bytes) in words.iter_mut().zip(owner.as_ref().chunks(8)) {
+            let mut le = [0_u8; 8];
+            le.copy_from_slice(bytes);
+            *word = u64::from_le_bytes(le);
+        }
+        words
+    }
+
+    fn fill(owner: &Pubkey) -> Event {
+        Event::new(EventView::Fill {
+            side: Side::Bid,
+            maker: true,
+            native_qty_paid: 100,
+            native_qty_received: 5,
+            native_fee_or_rebate: 1,
+            order_id: 7,
+            owner: owner_words(owner),
+            owner_slot: 2,
+            fee_tier: FeeTier::Base,
+            client_order_id: NonZeroU64::new(9),
+        })
+    }
+
+    fn out(owner: &Pubkey) -> Event {
+        Event::new(EventView::Out {
+            side: Side::Ask,
+            release_funds: false,
+            native_qty_unlocked: 30,
+            native_qty_still_locked: 4,
+            order_id: u128::MAX,
+            owner: owner_words(owner),
+            owner_slot: 0,
+            client_order_id: None,
+        })
+    }
+
+    /// a report of an event queue holding a fill and an out event
+    fn report(maker: &Pubkey, taker: &Pubkey) -> InspectReport {
+        let event_q = queue_data([1 | 8, 0, 2, 12], &[fill(maker), out(taker)], 4);
+        let req_q = queue_data([1 | 4, 3, 1, 20], &[], 0);
+        decode_queues(MarketPubkeys::default(), &event_q, &req_q).unwrap()
+    }
+
+    #[test]
+    fn queue_headers_are_decoded() {
+        let report = report(&Pubkey::new_unique(), &Pubkey::new_unique());
+        let header = &report.event_queue;
+        assert_eq!((header.head, header.count, header.seq_num), (0, 2, 12));
+        let header = &report.request_queue;
+        assert_eq!((header.head, header.count, header.seq_num), (3, 1, 20));
+    }
+
+    #[test]
+    fn events_are_reported_per_owner() {
+        let (maker, taker) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let events = report(&maker, &taker).events;
+        assert_eq!(events.len(), 2);
+        let fill = &events[0];
+        assert_eq!((fill.index, fill.event_type), (0, "fill"));
+        assert_eq!(fill.side, "Bid");
+        assert!(fill.maker);
+        assert_eq!(fill.owner, maker.to_string());
+        assert_eq!((fill.owner_slot, fill.order_id.as_str()), (2, "7"));
+        assert_eq!(fill.client_order_id, Some(9));
+        assert_eq!(
+            (
+                fill.native_qty_paid,
+                fill.native_qty_received,
+                fill.native_fee_or_rebate
+            ),
+            (Some(100), Some(5), Some(1))
+        );
+        assert_eq!(fill.native_qty_unlocked, None);
+        let out = &events[1];
+        assert_eq!((out.index, out.event_type), (1, "out"));
+        assert_eq!(out.side, "Ask");
+        assert!(!out.maker);
+        assert_eq!(out.owner, taker.to_string());
+        assert_eq!(out.order_id, u128::MAX.to_string());
+        assert_eq!(out.client_order_id, None);
+        assert_eq!(
+            (out.native_qty_unlocked, out.native_qty_still_locked),
+            (Some(30), Some(4))
+        );
+        assert_eq!(out.native_qty_paid, None);
+    }
+
+    #[test]
+    fn reports_are_printed_as_json() {
+        let (maker, taker) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let json: Value = serde_json::from_str(&report(&maker, &taker).to_json().unwrap()).unwrap();
+        assert_eq!(json["event_queue"]["seq_num"], 12);
+        assert_eq!(json["request_queue"]["head"], 3);
+        assert_eq!(json["keys"]["market"], Pubkey::default().to_string());
+        assert_eq!(json["events"][0]["owner"], maker.to_string());
+        assert_eq!(json["events"][0]["native_qty_paid"], 100);
+        // quantities which don't apply to the event are left out
+        assert!(json["events"][0].get("native_qty_unlocked").is_none());
+        assert_eq!(json["events"][1]["order_id"], u128::MAX.to_string());
+        assert_eq!(json["events"][1]["client_order_id"], Value::Null);
+    }
+
+    #[test]
+    fn reports_are_printed_as_tables() {
+        let (maker, taker) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let table = report(&maker, &taker).to_table();
+        assert!(table.contains("event queue: head 0, count 2, seq_num 12\n"));
+        assert!(table.contains("request queue: head 3, count 1, seq_num 20\n"));
+        let rows: Vec<&str> = table
+            .lines()
+            .filter(|line| line.contains(&maker.to_string()) || line.contains(&taker.to_string()))
+            .collect();
+        assert_eq!(rows.len(), 2);
+        let fill: Vec<&str> = rows[0].split_whitespace().collect();
+        assert_eq!(&fill[..4], &["0", "fill", "Bid", "true"]);
+        assert_eq!(&fill[6..], &["7", "9", "100", "5", "1"]);
+        let out: Vec<&str> = rows[1].split_whitespace().collect();
+        assert_eq!(&out[..4], &["1", "out", "Ask", "false"]);
+        assert_eq!(&out[6..], &[u128::MAX.to_string().as_str(), "30", "4"]);
+    }
+
+    #[test]
+    fn accounts_are_read_from_files() {
+        let path = std::env::temp_dir().join(format!(
+            "crank-inspect-test-{}-event-queue",
+            std::process::id()
+        ));
+        let data = queue_data([1 | 8, 0, 1, 1], &[fill(&Pubkey::new_unique())], 1);
+        fs::write(&path, &data).unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let rpc = RpcClient::new(UNREACHABLE_URL.to_string());
+        let read = account_data(&rpc, &Pubkey::new_unique(), Some(&path));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), data);
+        // missing files aren't fetched from the rpc node instead
+        assert!(account_data(&rpc, &Pubkey::new_unique(), Some(&path)).is_err());
+    }
+
+    #[test]
+    fn truncated_queues_are_rejected() {
+        let event_q = queue_data([1 | 8, 0, 2, 12], &[fill(&Pubkey::new_unique())], 1);
+        let req_q = queue_data([1 | 4, 0, 0, 0], &[], 0);
+        // count is beyond the events the account holds
+        assert!(decode_queues(MarketPubkeys::default(), &event_q, &req_q).is_err());
+        // missing tail padding
+        let event_q = queue_data([1 | 8, 0, 1, 1], &[fill(&Pubkey::new_unique())], 1);
+        let truncated = &event_q[..event_q.len() - 3];
+        assert!(decode_queues(MarketPubkeys::default(), truncated, &req_q).is_err());
+        assert!(decode_queues(MarketPubkeys::default(), &event_q, b"serum").is_err());
+    }
+
+    #[test]
+    fn short_queue_headers_are_rejected() {
+        assert!(queue_header(&[1, 2, 3]).is_err());
+        let mut data = ACCOUNT_HEAD_PADDING.to_vec();
+        data.extend_from_slice(&u64::to_le_bytes(1));
+        data.extend_from_slice(ACCOUNT_TAIL_PADDING);
+        let event_q = queue_data([1 | 8, 0, 0, 0], &[], 1);
+        assert!(decode_queues(MarketPubkeys::default(), &event_q, &data).is_err());
+        assert!(decode_queues(MarketPubkeys::default(), &data, &event_q).is_err());
+    }
+}
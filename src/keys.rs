@@ -0,0 +1,291 @@
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::Hmac;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use solana_sdk::signature::{read_keypair, Keypair, Signer};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+/// key sources starting with this read the keypair from the named environment variable
+const ENV_PREFIX: &str = "env:";
+/// environment variable holding the keystore password, prompted for when not set
+pub const KEYSTORE_PASSWORD_ENV: &str = "CRANK_KEYSTORE_PASSWORD";
+const KEYSTORE_VERSION: u8 = 1;
+const KEYSTORE_KDF: &str = "pbkdf2-sha256";
+const KEYSTORE_CIPHER: &str = "chacha20poly1305";
+const KEYSTORE_ITERATIONS: u32 = 600_000;
+
+/// a keypair encrypted with a password, stored as json
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Keystore {
+    pub version: u8,
+    /// public key of the encrypted keypair, so the file can be identified without the password
+    pub pubkey: String,
+    pub kdf: String,
+    pub iterations: u32,
+    /// base64 encoded salt of the key derivation
+    pub salt: String,
+    pub cipher: String,
+    /// base64 encoded nonce of the cipher
+    pub nonce: String,
+    /// base64 encoded encrypted keypair bytes
+    pub ciphertext: String,
+}
+
+/// loads keypairs from key sources, asking for the keystore password at most once
+#[derive(Default)]
+pub struct KeyLoader {
+    password: Option<String>,
+}
+
+impl KeyLoader {
+    /// loads the keypairs of a key source, which is either `env:<VAR>` with a
+    /// keypair as a json array or base58 string, a keypair or keystore file, or
+    /// a directory whose json files are loaded in the order of their names
+    pub fn load(&mut self, source: &str) -> Result<Vec<Keypair>> {
+        if let Some(var) = source.strip_prefix(ENV_PREFIX) {
+            let value = env::var(var)
+                .map_err(|err| anyhow!("failed to read keypair from {}: {}", var, err))?;
+            let keypair = parse_keypair(&value)
+                .map_err(|err| anyhow!("failed to read keypair from {}: {}", var, err))?;
+            return Ok(vec![keypair]);
+        }
+        let path = expand_tilde(source)?;
+        let metadata =
+            fs::metadata(&path).map_err(|err| anyhow!("failed to read {}: {}", path, err))?;
+        if !metadata.is_dir() {
+            return Ok(vec![self.read_file(&path)?]);
+        }
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let file = entry?.path();
+            if file.is_file() && file.extension().map_or(false, |ext| ext == "json") {
+                files.push(file.to_string_lossy().into_owned());
+            }
+        }
+        files.sort();
+        files.iter().map(|file| self.read_file(file)).collect()
+    }
+    /// reads a keypair file, decrypting it when it is a keystore
+    fn read_file(&mut self, path: &str) -> Result<Keypair> {
+        let data = fs::read_to_string(path)
+            .map_err(|err| anyhow!("failed to read keypair file {}: {}", path, err))?;
+        let keypair = match serde_json::from_str::<Keystore>(&data) {
+            Ok(keystore) => {
+                let password = self.password()?;
+                keystore.decrypt(&password)
+            }
+            Err(_) => parse_keypair(&data),
+        };
+        keypair.map_err(|err| anyhow!("failed to read keypair file {}: {}", path, err))
+    }
+    fn password(&mut self) -> Result<String> {
+        if self.password.is_none() {
+            self.password = Some(read_password("keystore password: ")?);
+        }
+        Ok(self.password.clone().unwrap())
+    }
+}
+
+impl Keystore {
+    pub fn encrypt(keypair: &Keypair, password: &str) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let key = derive_key(password, &salt, KEYSTORE_ITERATIONS);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(Nonce::from_slice(&nonce), keypair.to_bytes().as_ref())
+            .map_err(|_| anyhow!("failed to encrypt keypair"))?;
+        Ok(Self {
+            version: KEYSTORE_VERSION,
+            pubkey: keypair.pubkey().to_string(),
+            kdf: KEYSTORE_KDF.to_string(),
+            iterations: KEYSTORE_ITERATIONS,
+            salt: base64::encode(salt),
+            cipher: KEYSTORE_CIPHER.to_string(),
+            nonce: base64::encode(nonce),
+            ciphertext: base64::encode(ciphertext),
+        })
+    }
+    pub fn decrypt(&self, password: &str) -> Result<Keypair> {
+        if self.version != KEYSTORE_VERSION
+            || self.kdf != KEYSTORE_KDF
+            || self.cipher != KEYSTORE_CIPHER
+        {
+            return Err(anyhow!(
+                "unsupported keystore version {} with kdf {} and cipher {}",
+                self.version,
+                self.kdf,
+                self.cipher
+            ));
+        }
+        let salt = base64::decode(&self.salt)?;
+        let nonce = base64::decode(&self.nonce)?;
+        if nonce.len() != 12 {
+            return Err(anyhow!("invalid keystore nonce"));
+        }
+        let key = derive_key(password, &salt, self.iterations);
+        let bytes = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                base64::decode(&self.ciphertext)?.as_ref(),
+            )
+            .map_err(|_| anyhow!("incorrect password or corrupted keystore"))?;
+        let keypair =
+            Keypair::from_bytes(&bytes).map_err(|err| anyhow!("invalid keypair: {}", err))?;
+        if keypair.pubkey().to_string() != self.pubkey {
+            return Err(anyhow!(
+                "keystore pubkey {} does not match the decrypted keypair",
+                self.pubkey
+            ));
+        }
+        Ok(keypair)
+    }
+    /// writes the keystore to a file readable only by its owner
+    pub fn write(&self, path: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|err| anyhow!("failed to create keystore {}: {}", path, err))?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// parses a keypair given as a json array of bytes, as written by
+/// `solana-keygen`, or as a base58 string, as exported by wallets
+pub fn parse_keypair(value: &str) -> Result<Keypair> {
+    let value = value.trim();
+    if value.starts_with('[') {
+        return read_keypair(&mut value.as_bytes()).map_err(|err| anyhow!("{}", err));
+    }
+    let bytes = bs58::decode(value)
+        .into_vec()
+        .map_err(|err| anyhow!("invalid base58 keypair: {}", err))?;
+    Keypair::from_bytes(&bytes).map_err(|err| anyhow!("invalid keypair: {}", err))
+}
+
+/// replaces a leading `~` with the home directory
+pub fn expand_tilde(path: &str) -> Result<String> {
+    if path != "~" && !path.starts_with("~/") {
+        return Ok(path.to_string());
+    }
+    let home = env::var("HOME").map_err(|_| anyhow!("cannot expand {}, HOME is not set", path))?;
+    Ok(format!("{}{}", home, &path[1..]))
+}
+
+/// returns the keystore password from the environment, or prompts for it
+pub fn read_password(prompt: &str) -> Result<String> {
+    if let Ok(password) = env::var(KEYSTORE_PASSWORD_ENV) {
+        return Ok(password);
+    }
+    rpassword::read_password_from_tty(Some(prompt))
+        .map_err(|err| anyhow!("failed to read keystore password: {}", err))
+}
+
+fn derive_key(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair_json(keypair: &Keypair) -> String {
+        serde_json::to_string(&keypair.to_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn keystore_round_trip() {
+        let keypair = Keypair::new();
+        let keystore = Keystore::encrypt(&keypair, "correct horse").unwrap();
+        assert_eq!(keystore.pubkey, keypair.pubkey().to_string());
+        let keystore: Keystore =
+            serde_json::from_str(&serde_json::to_string(&keystore).unwrap()).unwrap();
+        let decrypted = keystore.decrypt("correct horse").unwrap();
+        assert_eq!(decrypted.to_bytes().to_vec(), keypair.to_bytes().to_vec());
+        let err = keystore.decrypt("battery staple").unwrap_err();
+        assert!(err.to_string().contains("incorrect password"));
+    }
+
+    #[test]
+    fn unsupported_keystores_are_rejected() {
+        let keystore = Keystore {
+            version: KEYSTORE_VERSION + 1,
+            pubkey: Keypair::new().pubkey().to_string(),
+            kdf: KEYSTORE_KDF.to_string(),
+            iterations: 1,
+            salt: String::new(),
+            cipher: KEYSTORE_CIPHER.to_string(),
+            nonce: String::new(),
+            ciphertext: String::new(),
+        };
+        let err = keystore.decrypt("password").unwrap_err();
+        assert!(err.to_string().contains("unsupported keystore"));
+    }
+
+    #[test]
+    fn keypairs_parse_from_json_and_base58() {
+        let keypair = Keypair::new();
+        let parsed = parse_keypair(&keypair_json(&keypair)).unwrap();
+        assert_eq!(parsed.pubkey(), keypair.pubkey());
+        let base58 = bs58::encode(keypair.to_bytes()).into_string();
+        let parsed = parse_keypair(&format!(" {}\n", base58)).unwrap();
+        assert_eq!(parsed.pubkey(), keypair.pubkey());
+        assert!(parse_keypair("not a keypair").is_err());
+        assert!(parse_keypair("[1, 2, 3]").is_err());
+    }
+
+    #[test]
+    fn keypairs_load_from_env_vars() {
+        let keypair = Keypair::new();
+        let var = format!("CRANK_TEST_KEYPAIR_{}", std::process::id());
+        env::set_var(&var, keypair_json(&keypair));
+        let keypairs = KeyLoader::default()
+            .load(&format!("{}{}", ENV_PREFIX, var))
+            .unwrap();
+        env::remove_var(&var);
+        assert_eq!(keypairs.len(), 1);
+        assert_eq!(keypairs[0].pubkey(), keypair.pubkey());
+        assert!(KeyLoader::default()
+            .load(&format!("{}{}", ENV_PREFIX, var))
+            .is_err());
+    }
+
+    #[test]
+    fn directories_load_json_files_in_name_order() {
+        let dir = env::temp_dir().join(format!("crank-keys-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = Keypair::new();
+        let second = Keypair::new();
+        fs::write(dir.join("b.json"), keypair_json(&second)).unwrap();
+        fs::write(dir.join("a.json"), keypair_json(&first)).unwrap();
+        fs::write(dir.join("c.txt"), keypair_json(&Keypair::new())).unwrap();
+        let keypairs = KeyLoader::default().load(&dir.to_string_lossy());
+        fs::remove_dir_all(&dir).unwrap();
+        let pubkeys: Vec<_> = keypairs
+            .unwrap()
+            .iter()
+            .map(|keypair| keypair.pubkey())
+            .collect();
+        assert_eq!(pubkeys, vec![first.pubkey(), second.pubkey()]);
+    }
+
+    #[test]
+    fn only_leading_tildes_are_expanded() {
+        assert_eq!(expand_tilde("/etc/crank").unwrap(), "/etc/crank");
+        assert_eq!(expand_tilde("~user/crank").unwrap(), "~user/crank");
+        assert_eq!(expand_tilde("crank/~/id.json").unwrap(), "crank/~/id.json");
+    }
+}
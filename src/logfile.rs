@@ -0,0 +1,292 @@
+use crate::config::LogRotation;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// a log file which is appended to across restarts and rotated once it grows too
+/// large or old, keeping a number of rotated files named `<log_file>.1`, the most
+/// recent, to `<log_file>.<retain>`, gzip compressed when configured. clones
+/// share the same file, so one handle can be given to the logger and another
+/// kept to reopen the file on SIGUSR1 after it was moved by an external logrotate
+#[derive(Clone)]
+pub struct LogFile {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    path: PathBuf,
+    rotation: LogRotation,
+    file: Option<File>,
+    /// size in bytes of the current file
+    size: u64,
+    /// when the current file was created, so its age carries over restarts
+    opened: SystemTime,
+    /// true when the last write ended a line, files are only rotated
+    /// between lines so a log record never spans two files
+    at_line_start: bool,
+}
+
+impl LogFile {
+    pub fn open(path: &str, rotation: LogRotation) -> io::Result<Self> {
+        let mut state = State {
+            path: PathBuf::from(path),
+            rotation,
+            file: None,
+            size: 0,
+            opened: SystemTime::now(),
+            at_line_start: true,
+        };
+        state.open()?;
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+    /// closes and reopens the log file at its path, creating it when it was moved
+    pub fn reopen(&self) -> io::Result<()> {
+        self.lock()?.open()
+    }
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<State>> {
+        self.state
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "log file lock poisoned"))
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock()?.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self.lock()?.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl State {
+    fn open(&mut self) -> io::Result<()> {
+        self.file = None;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let metadata = file.metadata()?;
+        self.size = metadata.len();
+        // an existing file keeps its age, using the time it was last written
+        // to where the file system doesn't record creation times
+        self.opened = if self.size > 0 {
+            metadata
+                .created()
+                .or_else(|_| metadata.modified())
+                .unwrap_or_else(|_| SystemTime::now())
+        } else {
+            SystemTime::now()
+        };
+        self.file = Some(file);
+        Ok(())
+    }
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.at_line_start && self.rotation_due() {
+            // the logger can't log its own errors, so they go to stderr
+            if let Err(err) = self.rotate() {
+                eprintln!("failed to rotate log file {:?}: {:#?}", self.path, err);
+            }
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+        let written = self.file.as_mut().unwrap().write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+    fn rotation_due(&self) -> bool {
+        let max_size = self.rotation.max_size;
+        let max_age = Duration::from_secs(self.rotation.max_age_hours * 60 * 60);
+        (max_size > 0 && self.size >= max_size)
+            || (max_age.as_secs() > 0
+                && self.size > 0
+                && self.opened.elapsed().unwrap_or_default() >= max_age)
+    }
+    /// shifts the rotated files up by one, dropping the oldest, and moves
+    /// the current file to `<log_file>.1` before opening a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let retain = self.rotation.retain;
+        for idx in (1..=retain).rev() {
+            for compressed in [false, true].iter() {
+                let from = self.rotated_path(idx, *compressed);
+                if !from.exists() {
+                    continue;
+                }
+                if idx == retain {
+                    fs::remove_file(&from)?;
+                } else {
+                    fs::rename(&from, self.rotated_path(idx + 1, *compressed))?;
+                }
+            }
+        }
+        if retain == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let rotated = self.rotated_path(1, false);
+            fs::rename(&self.path, &rotated)?;
+            if self.rotation.compress {
+                compress(&rotated, &self.rotated_path(1, true))?;
+            }
+        }
+        self.open()
+    }
+    fn rotated_path(&self, idx: usize, compressed: bool) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", idx));
+        if compressed {
+            path.push(".gz");
+        }
+        PathBuf::from(path)
+    }
+}
+
+/// gzip compresses the file into `to`, removing the uncompressed file
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    /// an empty directory for the log files of the test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "crank-logfile-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn rotation(retain: usize, compress: bool) -> LogRotation {
+        LogRotation {
+            max_size: 10,
+            max_age_hours: 0,
+            retain,
+            compress,
+        }
+    }
+
+    /// writes `line <idx>` for each of the indexes
+    fn write_lines(log_file: &mut LogFile, idxs: std::ops::Range<usize>) {
+        for idx in idxs {
+            log_file
+                .write_all(format!("line {}\n", idx).as_bytes())
+                .unwrap();
+        }
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn rotated_files_are_numbered_keeping_retain_files() {
+        let dir = test_dir("retain");
+        let path = dir.join("crank.log");
+        let mut log_file = LogFile::open(&path.to_string_lossy(), rotation(2, false)).unwrap();
+        write_lines(&mut log_file, 0..7);
+        assert_eq!(read(dir.join("crank.log")), "line 6\n");
+        assert_eq!(read(dir.join("crank.log.1")), "line 4\nline 5\n");
+        assert_eq!(read(dir.join("crank.log.2")), "line 2\nline 3\n");
+        assert!(!dir.join("crank.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lines_are_never_split_between_files() {
+        let dir = test_dir("lines");
+        let path = dir.join("crank.log");
+        let mut log_file = LogFile::open(&path.to_string_lossy(), rotation(1, false)).unwrap();
+        log_file.write_all(b"a long record").unwrap();
+        log_file.write_all(b" continued\n").unwrap();
+        write_lines(&mut log_file, 0..1);
+        assert_eq!(read(dir.join("crank.log")), "line 0\n");
+        assert_eq!(read(dir.join("crank.log.1")), "a long record continued\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_files_are_compressed() {
+        let dir = test_dir("compress");
+        let path = dir.join("crank.log");
+        let mut log_file = LogFile::open(&path.to_string_lossy(), rotation(2, true)).unwrap();
+        write_lines(&mut log_file, 0..5);
+        assert!(!dir.join("crank.log.1").exists());
+        let mut rotated = String::new();
+        GzDecoder::new(File::open(dir.join("crank.log.2.gz")).unwrap())
+            .read_to_string(&mut rotated)
+            .unwrap();
+        assert_eq!(rotated, "line 0\nline 1\n");
+        assert!(dir.join("crank.log.1.gz").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nothing_is_kept_when_retain_is_zero() {
+        let dir = test_dir("none");
+        let path = dir.join("crank.log");
+        let mut log_file = LogFile::open(&path.to_string_lossy(), rotation(0, false)).unwrap();
+        write_lines(&mut log_file, 0..3);
+        assert_eq!(read(dir.join("crank.log")), "line 2\n");
+        assert!(!dir.join("crank.log.1").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_recreates_a_moved_file() {
+        let dir = test_dir("reopen");
+        let path = dir.join("crank.log");
+        let mut log_file = LogFile::open(&path.to_string_lossy(), LogRotation::default()).unwrap();
+        write_lines(&mut log_file, 0..1);
+        fs::rename(&path, dir.join("crank.log.moved")).unwrap();
+        log_file.reopen().unwrap();
+        write_lines(&mut log_file, 1..2);
+        assert_eq!(read(dir.join("crank.log.moved")), "line 0\n");
+        assert_eq!(read(path), "line 1\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopened_files_keep_their_age() {
+        let dir = test_dir("age");
+        let path = dir.join("crank.log");
+        let mut log_file = LogFile::open(&path.to_string_lossy(), LogRotation::default()).unwrap();
+        write_lines(&mut log_file, 0..1);
+        drop(log_file);
+        std::thread::sleep(Duration::from_millis(20));
+        let restarted = SystemTime::now();
+        let log_file = LogFile::open(&path.to_string_lossy(), LogRotation::default()).unwrap();
+        assert!(log_file.lock().unwrap().opened < restarted);
+        // a new file is as old as the time it was opened
+        let new_file = LogFile::open(
+            &dir.join("new.log").to_string_lossy(),
+            LogRotation::default(),
+        )
+        .unwrap();
+        assert!(new_file.lock().unwrap().opened >= restarted);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
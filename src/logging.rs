@@ -0,0 +1,197 @@
+use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
+use log::{LevelFilter, Log, Metadata, Record};
+use rand::Rng;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::io::Write;
+use std::sync::Mutex;
+
+thread_local! {
+    /// fields added to every record logged by the current thread
+    static FIELDS: RefCell<Vec<(&'static str, Value)>> = RefCell::new(Vec::new());
+}
+
+/// adds the fields to every json record logged by the current thread
+/// until the returned guard is dropped, such as the market being cranked
+pub fn scope(fields: Vec<(&'static str, Value)>) -> Scope {
+    let len = fields.len();
+    FIELDS.with(|current| current.borrow_mut().extend(fields));
+    Scope { len }
+}
+
+/// removes the fields of a scope when dropped
+pub struct Scope {
+    len: usize,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        FIELDS.with(|current| {
+            let mut current = current.borrow_mut();
+            let len = current.len().saturating_sub(self.len);
+            current.truncate(len);
+        });
+    }
+}
+
+/// logs every record as a single line json object, carrying the fields
+/// of the scopes active on the logging thread, to each of the outputs
+pub struct JsonLogger {
+    level: LevelFilter,
+    /// random id of this process, distinguishing iterations of different runs
+    run_id: String,
+    outputs: Vec<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl JsonLogger {
+    /// installs the logger as the global logger
+    pub fn init(level: LevelFilter, outputs: Vec<Box<dyn Write + Send>>) -> Result<()> {
+        let logger = Self {
+            level,
+            run_id: format!("{:08x}", rand::thread_rng().gen::<u32>()),
+            outputs: outputs.into_iter().map(Mutex::new).collect(),
+        };
+        log::set_boxed_logger(Box::new(logger))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut object = Map::new();
+        object.insert(
+            "ts".to_string(),
+            Value::String(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+        );
+        object.insert(
+            "level".to_string(),
+            Value::String(record.level().to_string().to_lowercase()),
+        );
+        object.insert(
+            "target".to_string(),
+            Value::String(record.target().to_string()),
+        );
+        object.insert("msg".to_string(), Value::String(record.args().to_string()));
+        object.insert("run_id".to_string(), Value::String(self.run_id.clone()));
+        FIELDS.with(|fields| {
+            for (name, value) in fields.borrow().iter() {
+                object.insert(name.to_string(), value.clone());
+            }
+        });
+        let line = Value::Object(object).to_string();
+        for output in self.outputs.iter() {
+            if let Ok(mut output) = output.lock() {
+                let _ = writeln!(output, "{}", line);
+            }
+        }
+    }
+    fn flush(&self) {
+        for output in self.outputs.iter() {
+            if let Ok(mut output) = output.lock() {
+                let _ = output.flush();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use serde_json::json;
+    use std::io;
+    use std::sync::Arc;
+
+    /// an output shared with the test, so the logged lines can be read back
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn lines(&self) -> Vec<Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    fn logger(level: LevelFilter, buffer: &Buffer) -> JsonLogger {
+        JsonLogger {
+            level,
+            run_id: "run".to_string(),
+            outputs: vec![Mutex::new(Box::new(buffer.clone()) as Box<dyn Write + Send>)],
+        }
+    }
+
+    fn log(logger: &JsonLogger, level: Level, msg: &str) {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("{}", msg))
+                .level(level)
+                .target("crank")
+                .build(),
+        );
+    }
+
+    #[test]
+    fn records_carry_the_fields_of_active_scopes() {
+        let buffer = Buffer::default();
+        let logger = logger(LevelFilter::Info, &buffer);
+        {
+            let _iteration = scope(vec![("iteration", json!(3))]);
+            let _market = scope(vec![("market", json!("market"))]);
+            log(&logger, Level::Warn, "checking market");
+        }
+        log(&logger, Level::Info, "done");
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "warn");
+        assert_eq!(lines[0]["target"], "crank");
+        assert_eq!(lines[0]["msg"], "checking market");
+        assert_eq!(lines[0]["run_id"], "run");
+        assert_eq!(lines[0]["iteration"], 3);
+        assert_eq!(lines[0]["market"], "market");
+        assert!(lines[0]["ts"].is_string());
+        assert_eq!(lines[1]["msg"], "done");
+        assert!(lines[1].get("iteration").is_none());
+        assert!(lines[1].get("market").is_none());
+    }
+
+    #[test]
+    fn scopes_only_apply_to_their_thread() {
+        let buffer = Buffer::default();
+        let logger = logger(LevelFilter::Info, &buffer);
+        let _market = scope(vec![("market", json!("market"))]);
+        std::thread::spawn(move || log(&logger, Level::Info, "other thread"))
+            .join()
+            .unwrap();
+        assert!(buffer.lines()[0].get("market").is_none());
+    }
+
+    #[test]
+    fn records_below_the_level_are_dropped() {
+        let buffer = Buffer::default();
+        let logger = logger(LevelFilter::Info, &buffer);
+        log(&logger, Level::Debug, "debug");
+        assert!(buffer.lines().is_empty());
+    }
+}
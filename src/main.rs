@@ -5,14 +5,58 @@ use anyhow::{anyhow, Result};
 use clap::{Arg, SubCommand};
 use crossbeam::sync::WaitGroup;
 use crossbeam_channel;
-use log::{error, warn};
+use log::{error, info, warn};
 use signal_hook::{
-    consts::{SIGINT, SIGQUIT, SIGTERM},
+    consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1},
     iterator::Signals,
 };
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signer;
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+pub mod alerts;
+pub mod balance;
+pub mod compute_budget;
 pub mod config;
+pub mod confirm;
+pub mod costs;
 pub mod crank;
+pub mod discovery;
+pub mod health;
+pub mod history;
+pub mod inspect;
+pub mod keys;
+pub mod logfile;
+pub mod logging;
+pub mod metrics;
+pub mod overrides;
+pub mod packer;
+pub mod payers;
+pub mod reload;
+pub mod rpc;
+pub mod schedule;
+pub mod server;
+pub mod simulate;
+pub mod subscriber;
+pub mod validate;
+pub mod workers;
+
+/// seconds between checks of the config file for changes
+const CONFIG_POLL_INTERVAL: u64 = 5;
+/// number of iterations printed by the history command unless --limit is given
+const DEFAULT_HISTORY_LIMIT: &str = "20";
+/// cli flags overriding configuration fields, and the field each overrides
+const FLAG_OVERRIDES: &[(&str, &str)] = &[
+    ("rpc-url", "http_rpc_url"),
+    ("ws-url", "ws_rpc_url"),
+    ("keypair", "key_path"),
+    ("dex-program", "crank.dex_program"),
+    ("log-file", "log_file"),
+    ("http-listen-addr", "http_listen_addr"),
+];
 
 #[tokio::main]
 async fn main() {
@@ -28,17 +72,241 @@ async fn main() {
                 .help("sets the config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("rpc-url")
+                .long("rpc-url")
+                .value_name("URL")
+                .help("overrides http_rpc_url")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ws-url")
+                .long("ws-url")
+                .value_name("URL")
+                .help("overrides ws_rpc_url")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keypair")
+                .long("keypair")
+                .value_name("KEYPAIR")
+                .help("overrides key_path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dex-program")
+                .long("dex-program")
+                .value_name("PUBKEY")
+                .help("overrides crank.dex_program")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("FILE")
+                .help("overrides log_file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-listen-addr")
+                .long("http-listen-addr")
+                .value_name("ADDR")
+                .help("overrides http_listen_addr")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .help("enables debug logging, overriding debug_log")
+                .takes_value(false),
+        )
+        .subcommand(
+            SubCommand::with_name("alert")
+                .about("webhook alerting commands")
+                .subcommands(vec![
+                    SubCommand::with_name("test")
+                        .about("sends a test alert to every configured webhook"),
+                    SubCommand::with_name("receive")
+                        .about("runs a webhook receiver printing the alerts it receives")
+                        .arg(
+                            Arg::with_name("listen-addr")
+                                .value_name("ADDR")
+                                .help("the address to receive webhooks on, such as 127.0.0.1:9000")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                ]),
+        )
         .subcommand(
             SubCommand::with_name("config")
                 .about("configuration management commands")
                 .subcommands(vec![
-                    SubCommand::with_name("new").about("generates a new configuration file")
+                    SubCommand::with_name("new").about("generates a new configuration file"),
+                    SubCommand::with_name("show")
+                        .about("prints the configuration with secrets redacted")
+                        .arg(
+                            Arg::with_name("effective")
+                                .long("effective")
+                                .help("applies the environment variable and cli flag overrides")
+                                .takes_value(false),
+                        )
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .help("prints the configuration as json instead of yaml")
+                                .takes_value(false),
+                        ),
+                    SubCommand::with_name("validate")
+                        .about("checks the configuration file, reporting every problem found")
+                        .arg(
+                            Arg::with_name("offline")
+                                .long("offline")
+                                .help("skips the checks of the market and wallet accounts on chain")
+                                .takes_value(false),
+                        ),
                 ]),
         )
-        .subcommand(SubCommand::with_name("run").about("runs the serum crank"))
+        .subcommand(
+            SubCommand::with_name("costs")
+                .about("prints the fees paid for cranking per market and per payer")
+                .arg(
+                    Arg::with_name("market")
+                        .long("market")
+                        .value_name("MARKET")
+                        .help("only prints the costs of the market, given by name or pubkey")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("prints the costs as json instead of a table")
+                        .conflicts_with("csv")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("csv")
+                        .long("csv")
+                        .help("prints the costs as csv instead of a table")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("prints the recorded crank loop iterations, the most recent first")
+                .arg(
+                    Arg::with_name("market")
+                        .long("market")
+                        .value_name("MARKET")
+                        .help("only prints the iterations of the market, given by name or pubkey")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("TIME")
+                        .help("only prints iterations started at or after the rfc3339 time, or the duration ago such as 12h")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .value_name("TIME")
+                        .help("only prints iterations started before the rfc3339 time, or the duration ago such as 30m")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("max number of iterations printed")
+                        .default_value(DEFAULT_HISTORY_LIMIT)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("prints the iterations as json instead of a table")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("decodes and prints the event and request queues of a market")
+                .arg(
+                    Arg::with_name("market")
+                        .value_name("MARKET")
+                        .help("the market account public key")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("prints the market as json instead of a table")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("market-file")
+                        .long("market-file")
+                        .value_name("FILE")
+                        .help("reads the raw market account data from the file instead of the rpc node")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("event-queue-file")
+                        .long("event-queue-file")
+                        .value_name("FILE")
+                        .help("reads the raw event queue account data from the file instead of the rpc node")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("request-queue-file")
+                        .long("request-queue-file")
+                        .value_name("FILE")
+                        .help("reads the raw request queue account data from the file instead of the rpc node")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("key")
+                .about("payer key management commands")
+                .subcommands(vec![SubCommand::with_name("encrypt")
+                    .about("encrypts a keypair into a password protected keystore file")
+                    .arg(
+                        Arg::with_name("keypair")
+                            .value_name("KEYPAIR")
+                            .help("the keypair file, or env:<VAR> holding a json or base58 keypair")
+                            .required(true)
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("output")
+                            .short("o")
+                            .long("output")
+                            .value_name("FILE")
+                            .help("the keystore file to create")
+                            .required(true)
+                            .takes_value(true),
+                    )]),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("runs the serum crank")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("simulates crank transactions instead of sending them")
+                        .takes_value(false),
+                ),
+        )
         .get_matches();
     let config_file_path = get_config_or_default(&matches);
-    let res = process_matches(&matches, config_file_path).await;
+    let overrides = get_overrides(&matches);
+    let res = process_matches(&matches, config_file_path, overrides).await;
     if res.is_err() {
         error!("failed to process command matches {:#?}", res.err());
     }
@@ -46,29 +314,196 @@ async fn main() {
 async fn process_matches<'a>(
     matches: &clap::ArgMatches<'a>,
     config_file_path: String,
+    overrides: overrides::Overrides,
 ) -> Result<()> {
     match matches.subcommand() {
+        ("alert", Some(alert)) => match alert.subcommand() {
+            ("test", Some(_test)) => {
+                let cfg = overrides.load(config_file_path.as_str())?;
+                if cfg.alerts.webhooks.is_empty() {
+                    return Err(anyhow!("no alert webhooks are configured"));
+                }
+                // the blocking webhook client must not run on the async runtime
+                let failed = tokio::task::block_in_place(|| -> Result<usize> {
+                    let client = alerts::webhook_client()?;
+                    let notification = alerts::Notification::test();
+                    let mut failed = 0;
+                    for webhook in cfg.alerts.webhooks.iter() {
+                        match alerts::deliver(&client, webhook, &notification) {
+                            Ok(()) => println!("sent test alert to {}", webhook.label()),
+                            Err(err) => {
+                                println!(
+                                    "failed to send test alert to {}: {}",
+                                    webhook.label(),
+                                    err
+                                );
+                                failed += 1;
+                            }
+                        }
+                    }
+                    Ok(failed)
+                })?;
+                if failed > 0 {
+                    std::process::exit(1);
+                }
+            }
+            ("receive", Some(receive)) => {
+                let listen_addr = receive.value_of("listen-addr").unwrap();
+                tokio::task::block_in_place(|| alerts::receive(listen_addr))?;
+            }
+            _ => return Err(anyhow!("failed to match subcommand")),
+        },
         ("config", Some(config)) => match config.subcommand() {
             ("new", Some(_new_config)) => {
-                config::Configuration::new(config_file_path.as_str(), false)?;
+                config::Configuration::new(config_file_path.as_str())?;
+            }
+            ("show", Some(show)) => {
+                let cfg = if show.is_present("effective") {
+                    overrides.load(config_file_path.as_str())?
+                } else {
+                    config::Configuration::load(config_file_path.as_str())?
+                };
+                println!("{}", cfg.redacted().render(show.is_present("json"))?);
+            }
+            ("validate", Some(validate)) => {
+                let cfg = overrides.load(config_file_path.as_str())?;
+                let mut problems = cfg.problems();
+                if !validate.is_present("offline") {
+                    let rpc = RpcClient::new(cfg.http_rpc_url.clone());
+                    problems.extend(validate::check_accounts(&cfg, &rpc));
+                }
+                if !problems.is_empty() {
+                    println!("found {} problems in {}:", problems.len(), config_file_path);
+                    for problem in problems.iter() {
+                        println!("  - {}", problem);
+                    }
+                    std::process::exit(1);
+                }
+                println!("{} is valid", config_file_path);
             }
             _ => return Err(anyhow!("failed to match subcommand")),
         },
-        ("run", Some(_run_crank)) => {
-            let cfg = Arc::new(config::Configuration::load(
-                config_file_path.as_str(),
-                false,
-            )?);
+        ("key", Some(key)) => match key.subcommand() {
+            ("encrypt", Some(encrypt)) => {
+                let keypairs =
+                    keys::KeyLoader::default().load(encrypt.value_of("keypair").unwrap())?;
+                if keypairs.len() != 1 {
+                    return Err(anyhow!(
+                        "expected a single keypair, found {}",
+                        keypairs.len()
+                    ));
+                }
+                let password = keys::read_password("new keystore password: ")?;
+                if password.is_empty() {
+                    return Err(anyhow!("keystore password must not be empty"));
+                }
+                if keys::read_password("confirm keystore password: ")? != password {
+                    return Err(anyhow!("keystore passwords do not match"));
+                }
+                let output = encrypt.value_of("output").unwrap();
+                keys::Keystore::encrypt(&keypairs[0], &password)?.write(output)?;
+                println!("wrote keystore for {} to {}", keypairs[0].pubkey(), output);
+            }
+            _ => return Err(anyhow!("failed to match subcommand")),
+        },
+        ("costs", Some(costs)) => {
+            let cfg = overrides.load(config_file_path.as_str())?;
+            if cfg.cost_accounting.ledger_file.is_empty() {
+                return Err(anyhow!(
+                    "cost accounting is disabled, cost_accounting.ledger_file is empty"
+                ));
+            }
+            let mut totals = costs::CostTotals::load(&cfg.cost_accounting.ledger_file)?;
+            let names = market_names(&cfg);
+            if costs.is_present("market") {
+                totals.filter_markets(&market_accounts(&cfg, costs));
+            }
+            if costs.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&totals)?);
+            } else if costs.is_present("csv") {
+                print!("{}", totals.to_csv(&names));
+            } else {
+                print!("{}", totals.to_table(&names));
+            }
+        }
+        ("history", Some(history)) => {
+            let cfg = overrides.load(config_file_path.as_str())?;
+            if cfg.history.database.is_empty() {
+                return Err(anyhow!("history is disabled, history.database is empty"));
+            }
+            if !std::path::Path::new(&cfg.history.database).exists() {
+                return Err(anyhow!("no history found at {}", cfg.history.database));
+            }
+            let query = history::HistoryQuery {
+                markets: market_accounts(&cfg, history),
+                since: history.value_of("since").map(history::parse_time).transpose()?,
+                until: history.value_of("until").map(history::parse_time).transpose()?,
+                limit: history
+                    .value_of("limit")
+                    .unwrap()
+                    .parse()
+                    .map_err(|err| anyhow!("invalid limit: {}", err))?,
+            };
+            let iterations = history::HistoryStore::open(&cfg.history)?.query(&query)?;
+            if history.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&iterations)?);
+            } else {
+                print!("{}", history::to_table(&iterations, &market_names(&cfg)));
+            }
+        }
+        ("inspect", Some(inspect)) => {
+            let cfg = overrides.load(config_file_path.as_str())?;
             cfg.init_log(false)?;
-            let mut signals =
-                Signals::new(vec![SIGINT, SIGTERM, SIGQUIT]).expect("failed to registers signals");
+            let program_id = Pubkey::from_str(cfg.crank.dex_program.as_str())?;
+            let market = Pubkey::from_str(inspect.value_of("market").unwrap())?;
+            let source = inspect::InspectSource {
+                market_file: inspect.value_of("market-file").map(str::to_string),
+                event_queue_file: inspect.value_of("event-queue-file").map(str::to_string),
+                request_queue_file: inspect.value_of("request-queue-file").map(str::to_string),
+            };
+            let report =
+                inspect::inspect_market(cfg.http_rpc_url.as_str(), &program_id, &market, &source)?;
+            if inspect.is_present("json") {
+                println!("{}", report.to_json()?);
+            } else {
+                println!("{}", report.to_table());
+            }
+        }
+        ("run", Some(run_crank)) => {
+            let dry_run = run_crank.is_present("dry-run");
+            let cfg = Arc::new(overrides.load(config_file_path.as_str())?);
+            let log_file = cfg.init_log(!cfg.log_file.is_empty())?;
+            cfg.validate()?;
+            // read the payers before starting, so a keystore password can be prompted for
+            let payers = cfg.payers()?;
+            let metrics = Arc::new(metrics::Metrics::new()?);
+            let health = Arc::new(health::Health::new(&cfg));
+            if let Some(listen_addr) = cfg.http_listen_addr.as_ref() {
+                server::start(
+                    listen_addr.as_str(),
+                    Arc::clone(&metrics),
+                    Arc::clone(&health),
+                )?;
+            }
+            let mut signals = Signals::new(vec![SIGINT, SIGTERM, SIGQUIT, SIGHUP, SIGUSR1])
+                .expect("failed to registers signals");
             let (s, r) = crossbeam_channel::unbounded();
+            // reload the config file whenever it changes or SIGHUP is received
+            let (reload_trigger, reload_trigger_r) = crossbeam_channel::unbounded();
+            let (reload_s, reload_r) = crossbeam_channel::unbounded();
+            reload::watch_config(
+                config_file_path.clone(),
+                overrides,
+                Duration::from_secs(CONFIG_POLL_INTERVAL),
+                reload_trigger_r,
+                reload_s,
+            );
             let wg = WaitGroup::new();
             {
                 let wg = wg.clone();
                 tokio::task::spawn_blocking(move || {
-                    let crank_turner = crank::Crank::new(cfg);
-                    let res = crank_turner.start(r);
+                    let crank_turner = crank::Crank::new(cfg, metrics, health, dry_run);
+                    let res = crank_turner.start(payers, r, reload_r);
                     if res.is_err() {
                         error!("encountered error while turning crank {:#?}", res.err());
                     }
@@ -76,6 +511,21 @@ async fn process_matches<'a>(
                 });
             }
             for signal in signals.forever() {
+                if signal == SIGHUP {
+                    info!("encountered reload signal {}", signal);
+                    let _ = reload_trigger.send(());
+                    continue;
+                }
+                // reopen the log file after it was moved by an external logrotate
+                if signal == SIGUSR1 {
+                    if let Some(log_file) = log_file.as_ref() {
+                        info!("encountered reopen log file signal {}", signal);
+                        if let Err(err) = log_file.reopen() {
+                            error!("failed to reopen log file {:#?}", err);
+                        }
+                    }
+                    continue;
+                }
                 warn!("encountered exit signal {}", signal);
                 break;
             }
@@ -100,3 +550,44 @@ fn get_config_or_default(matches: &clap::ArgMatches) -> String {
         .unwrap_or("config.yaml")
         .to_string()
 }
+// returns the name of each configured market by its pubkey, discovered
+// markets are only known by their pubkey
+fn market_names(cfg: &config::Configuration) -> HashMap<String, String> {
+    cfg.crank
+        .markets
+        .iter()
+        .map(|market| (market.market_account.clone(), market.name.clone()))
+        .collect()
+}
+// returns the pubkeys of the markets given with --market, either
+// by the name of a configured market or by pubkey
+fn market_accounts(cfg: &config::Configuration, matches: &clap::ArgMatches) -> Vec<String> {
+    let markets = match matches.values_of("market") {
+        Some(markets) => markets,
+        None => return vec![],
+    };
+    markets
+        .map(|market| {
+            let configured = cfg.crank.markets.iter().find(|configured| configured.name == market);
+            match configured {
+                Some(configured) => configured.market_account.clone(),
+                None => market.to_string(),
+            }
+        })
+        .collect()
+}
+// returns the configuration overrides of the environment variables followed by those
+// of the cli flags, so flags take precedence over environment variables, which take
+// precedence over the config file
+fn get_overrides(matches: &clap::ArgMatches) -> overrides::Overrides {
+    let mut overrides = overrides::Overrides::from_env(std::env::vars());
+    for (flag, field) in FLAG_OVERRIDES.iter() {
+        if let Some(value) = matches.value_of(flag) {
+            overrides.set(&format!("--{}", flag), field, value);
+        }
+    }
+    if matches.is_present("debug") {
+        overrides.set("--debug", "debug_log", "true");
+    }
+    overrides
+}
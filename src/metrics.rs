@@ -0,0 +1,181 @@
+use anyhow::Result;
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::time::Instant;
+
+/// prometheus metrics exported by the crank service
+pub struct Metrics {
+    registry: Registry,
+    pub event_queue_depth: IntGaugeVec,
+    pub request_queue_depth: IntGaugeVec,
+    pub open_orders_accounts: IntGaugeVec,
+    pub transactions_sent: IntCounter,
+    pub transactions_confirmed: IntCounter,
+    /// labelled by reason, one of send_error, failed or expired
+    pub transactions_failed: IntCounterVec,
+    pub lamports_spent: IntCounter,
+    pub rpc_latency: HistogramVec,
+    pub loop_duration: Histogram,
+    pub rpc_endpoint_healthy: IntGaugeVec,
+    pub rpc_endpoint_errors: IntCounterVec,
+    pub payer_balance: IntGaugeVec,
+    pub payer_runway_days: GaugeVec,
+    pub payer_lamports_spent: IntCounterVec,
+    pub market_lamports_spent: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let event_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "crank_event_queue_depth",
+                "number of events in the event queue",
+            ),
+            &["market"],
+        )?;
+        let request_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "crank_request_queue_depth",
+                "number of requests in the request queue",
+            ),
+            &["market"],
+        )?;
+        let open_orders_accounts = IntGaugeVec::new(
+            Opts::new(
+                "crank_open_orders_accounts",
+                "number of unique open orders accounts included in the last crank instruction",
+            ),
+            &["market"],
+        )?;
+        let transactions_sent = IntCounter::new(
+            "crank_transactions_sent_total",
+            "number of crank transactions sent",
+        )?;
+        let transactions_confirmed = IntCounter::new(
+            "crank_transactions_confirmed_total",
+            "number of crank transactions confirmed",
+        )?;
+        let transactions_failed = IntCounterVec::new(
+            Opts::new(
+                "crank_transactions_failed_total",
+                "number of crank transactions which failed to send, failed on chain or expired",
+            ),
+            &["reason"],
+        )?;
+        let lamports_spent = IntCounter::new(
+            "crank_lamports_spent_total",
+            "lamports spent on fees for crank transactions which landed",
+        )?;
+        let rpc_latency = HistogramVec::new(
+            HistogramOpts::new("crank_rpc_latency_seconds", "latency of rpc requests"),
+            &["method"],
+        )?;
+        let loop_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "crank_loop_duration_seconds",
+                "duration of a single crank loop iteration",
+            )
+            .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]),
+        )?;
+        let rpc_endpoint_healthy = IntGaugeVec::new(
+            Opts::new(
+                "crank_rpc_endpoint_healthy",
+                "1 when the rpc endpoint is healthy, 0 otherwise",
+            ),
+            &["endpoint"],
+        )?;
+        let rpc_endpoint_errors = IntCounterVec::new(
+            Opts::new(
+                "crank_rpc_endpoint_errors_total",
+                "number of failed requests to the rpc endpoint",
+            ),
+            &["endpoint"],
+        )?;
+        let payer_balance = IntGaugeVec::new(
+            Opts::new(
+                "crank_payer_balance_lamports",
+                "last known balance of the payer in lamports",
+            ),
+            &["payer"],
+        )?;
+        let payer_runway_days = GaugeVec::new(
+            Opts::new(
+                "crank_payer_runway_days",
+                "estimated number of days until the payer runs dry at the recent spend rate",
+            ),
+            &["payer"],
+        )?;
+        let payer_lamports_spent = IntCounterVec::new(
+            Opts::new(
+                "crank_payer_lamports_spent_total",
+                "lamports spent by the payer on fees for crank transactions which landed",
+            ),
+            &["payer"],
+        )?;
+        let market_lamports_spent = IntCounterVec::new(
+            Opts::new(
+                "crank_market_lamports_spent_total",
+                "lamports of the fees of landed crank transactions apportioned to the market",
+            ),
+            &["market"],
+        )?;
+        registry.register(Box::new(event_queue_depth.clone()))?;
+        registry.register(Box::new(request_queue_depth.clone()))?;
+        registry.register(Box::new(open_orders_accounts.clone()))?;
+        registry.register(Box::new(transactions_sent.clone()))?;
+        registry.register(Box::new(transactions_confirmed.clone()))?;
+        registry.register(Box::new(transactions_failed.clone()))?;
+        registry.register(Box::new(lamports_spent.clone()))?;
+        registry.register(Box::new(rpc_latency.clone()))?;
+        registry.register(Box::new(loop_duration.clone()))?;
+        registry.register(Box::new(rpc_endpoint_healthy.clone()))?;
+        registry.register(Box::new(rpc_endpoint_errors.clone()))?;
+        registry.register(Box::new(payer_balance.clone()))?;
+        registry.register(Box::new(payer_runway_days.clone()))?;
+        registry.register(Box::new(payer_lamports_spent.clone()))?;
+        registry.register(Box::new(market_lamports_spent.clone()))?;
+        Ok(Self {
+            registry,
+            event_queue_depth,
+            request_queue_depth,
+            open_orders_accounts,
+            transactions_sent,
+            transactions_confirmed,
+            transactions_failed,
+            lamports_spent,
+            rpc_latency,
+            loop_duration,
+            rpc_endpoint_healthy,
+            rpc_endpoint_errors,
+            payer_balance,
+            payer_runway_days,
+            payer_lamports_spent,
+            market_lamports_spent,
+        })
+    }
+    /// runs the rpc request, recording its latency under the given method
+    pub fn time_rpc<T>(&self, method: &str, request: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = request();
+        self.rpc_latency
+            .with_label_values(&[method])
+            .observe(start.elapsed().as_secs_f64());
+        res
+    }
+    /// removes the per market metrics of a market which is no longer cranked
+    pub fn remove_market(&self, market: &str) {
+        let _ = self.event_queue_depth.remove_label_values(&[market]);
+        let _ = self.request_queue_depth.remove_label_values(&[market]);
+        let _ = self.open_orders_accounts.remove_label_values(&[market]);
+        let _ = self.market_lamports_spent.remove_label_values(&[market]);
+    }
+    /// returns the metrics in the prometheus text exposition format
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
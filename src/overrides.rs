@@ -0,0 +1,309 @@
+use crate::config::Configuration;
+use crate::keys::KEYSTORE_PASSWORD_ENV;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+/// prefix of the environment variables overriding configuration fields
+const ENV_PREFIX: &str = "CRANK_";
+/// separates the fields of a nested path in environment variable names
+const ENV_PATH_SEPARATOR: &str = "__";
+/// environment variables starting with ENV_PREFIX which aren't configuration fields,
+/// other than those naming a key source such as `env:CRANK_PAYER_KEY`
+const RESERVED_ENV_VARS: &[&str] = &[KEYSTORE_PASSWORD_ENV];
+
+/// overrides of configuration fields, applied on top of the config file. later
+/// overrides take precedence, so environment variables are added before cli flags
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    fields: Vec<Override>,
+}
+
+#[derive(Clone, Debug)]
+struct Override {
+    /// where the override came from, used in error messages
+    source: String,
+    /// field names, or array indexes, leading to the overridden field
+    path: Vec<String>,
+    value: String,
+    /// skipped, instead of failing, when the top level field doesn't exist.
+    /// set for environment variables, which share their prefix with variables
+    /// holding keys and passwords
+    skip_unknown: bool,
+}
+
+impl Overrides {
+    /// collects the overrides of the `CRANK_*` environment variables, given as
+    /// `(name, value)` pairs such as those of `std::env::vars`. nested fields are
+    /// separated by a double underscore, so `CRANK_CRANK__NUM_ACCOUNTS` overrides
+    /// `crank.num_accounts` and `CRANK_CRANK__MARKETS__0__NAME` the first market's
+    /// name. variables not starting with a top level field are ignored
+    pub fn from_env<I: IntoIterator<Item = (String, String)>>(vars: I) -> Self {
+        let mut vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(name, _)| {
+                name.starts_with(ENV_PREFIX) && !RESERVED_ENV_VARS.contains(&name.as_str())
+            })
+            .collect();
+        // apply the overrides in a stable order, so parents come before their fields
+        vars.sort();
+        let fields = vars
+            .into_iter()
+            .map(|(name, value)| Override {
+                path: name[ENV_PREFIX.len()..]
+                    .to_lowercase()
+                    .split(ENV_PATH_SEPARATOR)
+                    .map(str::to_string)
+                    .collect(),
+                source: name,
+                value,
+                skip_unknown: true,
+            })
+            .collect();
+        Self { fields }
+    }
+    /// overrides the field at the dot separated path, such as `crank.dex_program`
+    pub fn set(&mut self, source: &str, path: &str, value: &str) {
+        self.fields.push(Override {
+            source: source.to_string(),
+            path: path.split('.').map(str::to_string).collect(),
+            value: value.to_string(),
+            skip_unknown: false,
+        });
+    }
+    /// loads the config file and applies the overrides
+    pub fn load(&self, path: &str) -> Result<Configuration> {
+        self.apply(Configuration::load(path)?)
+    }
+    pub fn apply(&self, config: Configuration) -> Result<Configuration> {
+        if self.fields.is_empty() {
+            return Ok(config);
+        }
+        let mut value = serde_json::to_value(&config)?;
+        for field in self.fields.iter() {
+            if field.skip_unknown && !is_top_level_field(&value, &field.path[0]) {
+                continue;
+            }
+            value = field.apply(value)?;
+        }
+        serde_json::from_value(value)
+            .map_err(|err| anyhow!("invalid configuration override: {}", err))
+    }
+}
+
+impl Override {
+    /// sets the field in the serialized configuration. optional fields which
+    /// aren't set are left out when serializing, so a missing field is added
+    /// and only kept if the configuration still has it once deserialized
+    fn apply(&self, mut config: Value) -> Result<Value> {
+        let target = self.target(&mut config)?;
+        if !target.is_null() {
+            *target = parse_value(&self.value, target);
+            return Ok(config);
+        }
+        // the type of an unset field isn't known, so the value is tried as
+        // parsed and then as a string, for fields such as webhook templates
+        let mut candidates = vec![
+            parse_value(&self.value, &Value::Null),
+            Value::String(self.value.clone()),
+        ];
+        candidates.dedup();
+        let mut last_err = None;
+        for candidate in candidates {
+            let mut updated = config.clone();
+            *self.target(&mut updated)? = candidate;
+            match serde_json::from_value::<Configuration>(updated.clone()) {
+                Ok(parsed) => {
+                    // unknown fields are dropped when deserializing
+                    let pointer = self.pointer();
+                    if serde_json::to_value(&parsed)?.pointer(&pointer).is_none() {
+                        return Err(self.unknown_field());
+                    }
+                    return Ok(updated);
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(anyhow!(
+            "invalid configuration override {}: {}",
+            self.source,
+            last_err.map_or_else(String::new, |err| err.to_string())
+        ))
+    }
+    fn target<'a>(&self, config: &'a mut Value) -> Result<&'a mut Value> {
+        self.path
+            .iter()
+            .try_fold(config, |value, name| lookup(value, name))
+            .ok_or_else(|| self.unknown_field())
+    }
+    /// the json pointer of the field
+    fn pointer(&self) -> String {
+        self.path
+            .iter()
+            .map(|name| format!("/{}", name.replace('~', "~0").replace('/', "~1")))
+            .collect()
+    }
+    fn unknown_field(&self) -> anyhow::Error {
+        anyhow!("{} does not match a configuration field", self.source)
+    }
+}
+
+/// every top level field is serialized, optional ones as null
+fn is_top_level_field(config: &Value, name: &str) -> bool {
+    config.get(name).is_some()
+}
+
+/// returns the field or array item, adding the field when the object doesn't have it
+fn lookup<'a>(value: &'a mut Value, name: &str) -> Option<&'a mut Value> {
+    match value {
+        Value::Object(fields) => Some(fields.entry(name).or_insert(Value::Null)),
+        Value::Array(items) => name
+            .parse::<usize>()
+            .ok()
+            .and_then(move |idx| items.get_mut(idx)),
+        _ => None,
+    }
+}
+
+/// parses the override as yaml so numbers, booleans, lists and objects can be given,
+/// keeping it as is for string fields unless it is a list or object
+fn parse_value(raw: &str, current: &Value) -> Value {
+    let structured = raw.starts_with('[') || raw.starts_with('{');
+    if current.is_string() && !structured {
+        return Value::String(raw.to_string());
+    }
+    serde_yaml::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Webhook, WebhookFormat};
+
+    fn config() -> Configuration {
+        let mut config = Configuration::default();
+        config.alerts.webhooks.push(Webhook {
+            url: "http://127.0.0.1:9000".to_string(),
+            name: None,
+            format: WebhookFormat::Generic,
+            template: None,
+        });
+        config
+    }
+
+    #[test]
+    fn fields_are_overridden() {
+        let mut overrides = Overrides::default();
+        overrides.set(
+            "--dex-program",
+            "crank.dex_program",
+            "11111111111111111111111111111111",
+        );
+        overrides.set("--num-accounts", "crank.num_accounts", "10");
+        overrides.set("--market-name", "crank.markets.0.name", "SOL-USDC");
+        overrides.set("--debug", "debug_log", "true");
+        let config = overrides.apply(config()).unwrap();
+        assert_eq!(config.crank.dex_program, "11111111111111111111111111111111");
+        assert_eq!(config.crank.num_accounts, 10);
+        assert_eq!(config.crank.markets[0].name, "SOL-USDC");
+        assert!(config.debug_log);
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn flags_take_precedence_over_env_vars() {
+        let mut overrides = Overrides::from_env(vars(&[
+            ("CRANK_CRANK__EVENTS_PER_WORKER", "7"),
+            ("CRANK_LOG_FILE", "env.log"),
+            ("PATH", "/usr/bin"),
+        ]));
+        overrides.set("--log-file", "log_file", "flag.log");
+        let config = overrides.apply(config()).unwrap();
+        assert_eq!(config.crank.events_per_worker, 7);
+        assert_eq!(config.log_file, "flag.log");
+    }
+
+    #[test]
+    fn env_vars_which_are_not_fields_are_ignored() {
+        let overrides = Overrides::from_env(vars(&[
+            ("CRANK_PAYER_KEY", "[1, 2, 3]"),
+            ("CRANK_KEYSTORE_PASSWORD", "secret"),
+            ("CRANK_DEBUG_LOG", "true"),
+        ]));
+        let config = overrides.apply(config()).unwrap();
+        assert!(config.debug_log);
+        // a typo below a top level field is still an error
+        let overrides = Overrides::from_env(vars(&[("CRANK_CRANK__NUM_ACOUNTS", "10")]));
+        let err = overrides.apply(config).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("does not match a configuration field"));
+    }
+
+    #[test]
+    fn unset_optional_fields_are_overridden() {
+        let config = config();
+        assert_eq!(config.crank.markets[0].compute_unit_limit, None);
+        assert_eq!(config.alerts.webhooks[0].template, None);
+        let template = r#"{"text": "{{message}}"}"#;
+        let mut overrides = Overrides::default();
+        overrides.set(
+            "CRANK_CRANK__MARKETS__0__COMPUTE_UNIT_LIMIT",
+            "crank.markets.0.compute_unit_limit",
+            "200000",
+        );
+        overrides.set(
+            "CRANK_ALERTS__WEBHOOKS__0__TEMPLATE",
+            "alerts.webhooks.0.template",
+            template,
+        );
+        let config = overrides.apply(config).unwrap();
+        assert_eq!(config.crank.markets[0].compute_unit_limit, Some(200_000));
+        assert_eq!(
+            config.alerts.webhooks[0].template.as_deref(),
+            Some(template)
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        for path in [
+            "crank.no_such_field",
+            "crank.markets.0.no_such_field",
+            "crank.markets.5.name",
+            "debug_log.nested",
+        ]
+        .iter()
+        {
+            let mut overrides = Overrides::default();
+            overrides.set("--field", path, "1");
+            let err = overrides.apply(config()).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("does not match a configuration field"),
+                "{}: {}",
+                path,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let mut overrides = Overrides::default();
+        overrides.set("--num-accounts", "crank.num_accounts", "many");
+        assert!(overrides.apply(config()).is_err());
+        let mut overrides = Overrides::default();
+        overrides.set(
+            "--compute-unit-limit",
+            "crank.markets.0.compute_unit_limit",
+            "many",
+        );
+        let err = overrides.apply(config()).unwrap_err();
+        assert!(err.to_string().contains("invalid configuration override"));
+    }
+}
@@ -0,0 +1,353 @@
+use log::{debug, error, warn};
+use solana_sdk::instruction::Instruction;
+use solana_sdk::message::Message;
+use solana_sdk::packet::PACKET_DATA_SIZE;
+use solana_sdk::pubkey::Pubkey;
+
+/// size in bytes of a single transaction signature
+const SIGNATURE_SIZE: usize = 64;
+
+/// a set of crank instructions that fit into a single transaction,
+/// along with the markets they crank
+#[derive(Clone, Debug, Default)]
+pub struct PackedTransaction {
+    pub instructions: Vec<Instruction>,
+    pub markets: Vec<Pubkey>,
+    /// the open orders accounts cranked for each of the markets, after trimming
+    pub open_orders: Vec<Vec<Pubkey>>,
+}
+
+/// the crank instructions of a market
+#[derive(Clone, Debug)]
+pub struct MarketInstructions {
+    pub market: Pubkey,
+    pub instructions: Vec<Instruction>,
+    /// the open orders accounts of the last, consume events, instruction in the
+    /// order of their first event. the dex consumes events until it meets one whose
+    /// owner wasn't given, so accounts are trimmed from the end when the market
+    /// doesn't fit into a transaction
+    pub open_orders: Vec<Pubkey>,
+}
+
+/// greedily packs the instructions of multiple markets into as few transactions
+/// as possible, bounded by the serialized transaction size and the number of
+/// unique account keys. accounts shared between markets, the payer and the dex
+/// program are only counted once as the message deduplicates them
+pub struct TransactionPacker {
+    payer: Pubkey,
+    max_accounts_per_tx: usize,
+    max_markets_per_tx: Option<usize>,
+    /// instructions added to every packed transaction, these are included when
+    /// measuring the size of the transaction, which doesn't depend on their order
+    extra_instructions: Vec<Instruction>,
+}
+
+impl TransactionPacker {
+    pub fn new(
+        payer: Pubkey,
+        max_accounts_per_tx: usize,
+        max_markets_per_tx: Option<usize>,
+        extra_instructions: Vec<Instruction>,
+    ) -> Self {
+        Self {
+            payer,
+            max_accounts_per_tx,
+            max_markets_per_tx,
+            extra_instructions,
+        }
+    }
+    /// packs the instruction sets into transactions using a first fit strategy,
+    /// preserving the order in which markets were given within each transaction.
+    /// the returned instructions do not include the extra instructions
+    pub fn pack(&self, ix_sets: Vec<MarketInstructions>) -> Vec<PackedTransaction> {
+        let mut packed: Vec<PackedTransaction> = vec![];
+        for mut ix_set in ix_sets {
+            if !self.trim(&mut ix_set) {
+                error!(
+                    "crank instructions for market {} do not fit into a single transaction, consider lowering num_accounts",
+                    ix_set.market
+                );
+                continue;
+            }
+            let MarketInstructions {
+                market,
+                instructions: ixs,
+                open_orders,
+            } = ix_set;
+            let mut placed = false;
+            for tx in packed.iter_mut() {
+                if let Some(max_markets) = self.max_markets_per_tx {
+                    if tx.markets.len() >= max_markets {
+                        continue;
+                    }
+                }
+                let mut candidate = Vec::with_capacity(tx.instructions.len() + ixs.len());
+                candidate.extend_from_slice(&tx.instructions);
+                candidate.extend_from_slice(&ixs);
+                if self.fits(&candidate, tx.markets.len() + 1) {
+                    tx.instructions = candidate;
+                    tx.markets.push(market);
+                    tx.open_orders.push(open_orders.clone());
+                    placed = true;
+                    break;
+                }
+            }
+            if !placed {
+                packed.push(PackedTransaction {
+                    instructions: ixs,
+                    markets: vec![market],
+                    open_orders: vec![open_orders],
+                });
+            }
+        }
+        if packed.len() > 1 {
+            debug!(
+                "crank instructions packed into {} transactions",
+                packed.len()
+            );
+        }
+        packed
+    }
+    /// removes open orders accounts, starting with the one of the latest event, until
+    /// the instructions of the market fit into a transaction on their own. returns false
+    /// if they don't fit with a single open orders account
+    fn trim(&self, ix_set: &mut MarketInstructions) -> bool {
+        let num_open_orders = ix_set.open_orders.len();
+        while !self.fits(&ix_set.instructions, 1) {
+            if ix_set.open_orders.len() <= 1 {
+                return false;
+            }
+            let dropped = ix_set.open_orders.pop().unwrap();
+            if let Some(ix) = ix_set.instructions.last_mut() {
+                ix.accounts.retain(|meta| meta.pubkey != dropped);
+            }
+        }
+        if ix_set.open_orders.len() < num_open_orders {
+            warn!(
+                "cranking {} of {} open orders accounts of market {} to fit into a single transaction, consider lowering num_accounts",
+                ix_set.open_orders.len(),
+                num_open_orders,
+                ix_set.market
+            );
+        }
+        true
+    }
+    /// returns true if a transaction made up of the given instructions and the
+    /// extra instructions is within the size and account limits
+    fn fits(&self, instructions: &[Instruction], num_markets: usize) -> bool {
+        if let Some(max_markets) = self.max_markets_per_tx {
+            if num_markets > max_markets {
+                return false;
+            }
+        }
+        let mut ixs = Vec::with_capacity(instructions.len() + self.extra_instructions.len());
+        ixs.extend_from_slice(instructions);
+        ixs.extend_from_slice(&self.extra_instructions);
+        let (size, num_accounts) = transaction_size(&ixs, &self.payer);
+        size <= PACKET_DATA_SIZE && num_accounts <= self.max_accounts_per_tx
+    }
+}
+
+/// returns the serialized size in bytes of a transaction containing the
+/// instructions and paid for by payer, along with its number of unique account keys
+pub fn transaction_size(instructions: &[Instruction], payer: &Pubkey) -> (usize, usize) {
+    let message = Message::new(instructions, Some(payer));
+    let num_signatures = message.header.num_required_signatures as usize;
+    let size =
+        short_vec_len(num_signatures) + num_signatures * SIGNATURE_SIZE + message.serialize().len();
+    (size, message.account_keys.len())
+}
+
+/// returns the number of bytes used to encode the length prefix of a short vec
+fn short_vec_len(len: usize) -> usize {
+    let mut len = len;
+    let mut bytes = 1;
+    while len > 0x7f {
+        len >>= 7;
+        bytes += 1;
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compute_budget::{set_compute_unit_limit, set_compute_unit_price};
+    use solana_sdk::instruction::AccountMeta;
+
+    fn crank_ix(program_id: &Pubkey, num_accounts: usize, data_len: usize) -> Instruction {
+        Instruction {
+            program_id: *program_id,
+            accounts: (0..num_accounts)
+                .map(|_| AccountMeta::new(Pubkey::new_unique(), false))
+                .collect(),
+            data: vec![0; data_len],
+        }
+    }
+
+    /// resizes the data of the last instruction so a transaction of the instructions is size bytes
+    fn fill(payer: &Pubkey, ixs: &mut [Instruction], size: usize) {
+        let last = ixs.len() - 1;
+        ixs[last].data = vec![0; 200];
+        let (base, _) = transaction_size(ixs, payer);
+        ixs[last].data = vec![0; 200 + size - base];
+        assert_eq!(transaction_size(ixs, payer).0, size);
+    }
+
+    fn ix_set(instructions: Vec<Instruction>, market: Pubkey) -> MarketInstructions {
+        MarketInstructions {
+            market,
+            instructions,
+            open_orders: vec![],
+        }
+    }
+
+    fn markets(packed: &[PackedTransaction]) -> Vec<Vec<Pubkey>> {
+        packed.iter().map(|tx| tx.markets.clone()).collect()
+    }
+
+    #[test]
+    fn large_market_gets_its_own_transaction() {
+        let (payer, program_id) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (large, small_a, small_b) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut large_ixs = vec![crank_ix(&program_id, 4, 200)];
+        fill(&payer, &mut large_ixs, PACKET_DATA_SIZE - 20);
+        let packer = TransactionPacker::new(payer, 64, None, vec![]);
+        let packed = packer.pack(vec![
+            ix_set(vec![crank_ix(&program_id, 2, 8)], small_a),
+            ix_set(large_ixs, large),
+            ix_set(vec![crank_ix(&program_id, 2, 8)], small_b),
+        ]);
+        assert_eq!(markets(&packed), vec![vec![small_a, small_b], vec![large]]);
+    }
+
+    #[test]
+    fn splits_at_account_limit() {
+        let (payer, program_id) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let market_keys: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        // the payer and program along with 8 accounts per market, so only 2 markets fit in 20 accounts
+        let packer = TransactionPacker::new(payer, 20, None, vec![]);
+        let packed = packer.pack(
+            market_keys
+                .iter()
+                .map(|market| ix_set(vec![crank_ix(&program_id, 8, 8)], *market))
+                .collect(),
+        );
+        assert_eq!(
+            markets(&packed),
+            vec![vec![market_keys[0], market_keys[1]], vec![market_keys[2]]]
+        );
+        for tx in packed.iter() {
+            assert!(transaction_size(&tx.instructions, &payer).1 <= 20);
+        }
+    }
+
+    #[test]
+    fn splits_at_size_limit_counting_extra_instructions() {
+        let (payer, program_id) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (market_a, market_b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        // both markets exactly fill a transaction without the compute budget instructions
+        let mut ixs = vec![crank_ix(&program_id, 2, 200), crank_ix(&program_id, 2, 200)];
+        fill(&payer, &mut ixs, PACKET_DATA_SIZE);
+        let ix_sets = vec![
+            ix_set(vec![ixs[0].clone()], market_a),
+            ix_set(vec![ixs[1].clone()], market_b),
+        ];
+        let packer = TransactionPacker::new(payer, 64, None, vec![]);
+        assert_eq!(
+            markets(&packer.pack(ix_sets.clone())),
+            vec![vec![market_a, market_b]]
+        );
+        let placeholders = vec![set_compute_unit_limit(0), set_compute_unit_price(0)];
+        let packer = TransactionPacker::new(payer, 64, None, placeholders);
+        assert_eq!(
+            markets(&packer.pack(ix_sets)),
+            vec![vec![market_a], vec![market_b]]
+        );
+    }
+
+    #[test]
+    fn splits_at_market_limit() {
+        let (payer, program_id) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let market_keys: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        let packer = TransactionPacker::new(payer, 64, Some(2), vec![]);
+        let packed = packer.pack(
+            market_keys
+                .iter()
+                .map(|market| ix_set(vec![crank_ix(&program_id, 2, 8)], *market))
+                .collect(),
+        );
+        assert_eq!(packed.len(), 2);
+        assert_eq!(packed[0].markets.len(), 2);
+    }
+
+    #[test]
+    fn packs_every_market_once() {
+        let (payer, program_id) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let market_keys: Vec<Pubkey> = (0..12).map(|_| Pubkey::new_unique()).collect();
+        let packer = TransactionPacker::new(payer, 32, None, vec![set_compute_unit_price(0)]);
+        let packed = packer.pack(
+            market_keys
+                .iter()
+                .enumerate()
+                .map(|(idx, market)| ix_set(vec![crank_ix(&program_id, 2 + idx % 4, 8)], *market))
+                .collect(),
+        );
+        assert!(!packed.is_empty());
+        let mut packed_markets = vec![];
+        for tx in packed.iter() {
+            assert!(!tx.instructions.is_empty());
+            assert!(!tx.markets.is_empty());
+            packed_markets.extend_from_slice(&tx.markets);
+        }
+        packed_markets.sort();
+        let mut expected = market_keys;
+        expected.sort();
+        assert_eq!(packed_markets, expected);
+    }
+
+    #[test]
+    fn trims_open_orders_of_market_too_large_for_a_transaction() {
+        let (payer, program_id) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let market = Pubkey::new_unique();
+        // 32 open orders accounts followed by the market, event queue and wallets
+        let open_orders: Vec<Pubkey> = (0..32).map(|_| Pubkey::new_unique()).collect();
+        let mut ix = crank_ix(&program_id, 4, 3);
+        let mut sorted = open_orders.clone();
+        sorted.sort();
+        let mut accounts: Vec<AccountMeta> = sorted
+            .iter()
+            .map(|pubkey| AccountMeta::new(*pubkey, false))
+            .collect();
+        accounts.append(&mut ix.accounts);
+        ix.accounts = accounts;
+        assert!(transaction_size(&[ix.clone()], &payer).0 > PACKET_DATA_SIZE);
+        let packer = TransactionPacker::new(payer, 64, None, vec![]);
+        let packed = packer.pack(vec![MarketInstructions {
+            market,
+            instructions: vec![ix.clone()],
+            open_orders: open_orders.clone(),
+        }]);
+        assert_eq!(markets(&packed), vec![vec![market]]);
+        assert!(transaction_size(&packed[0].instructions, &payer).0 <= PACKET_DATA_SIZE);
+        let kept = &packed[0].instructions[0].accounts;
+        let num_kept = kept.len() - 4;
+        assert!(num_kept > 1 && num_kept < open_orders.len());
+        // the accounts of the earliest events are kept, still sorted, ahead of the market accounts
+        let mut expected: Vec<Pubkey> = open_orders[..num_kept].to_vec();
+        expected.sort();
+        let kept_open_orders: Vec<Pubkey> =
+            kept[..num_kept].iter().map(|meta| meta.pubkey).collect();
+        assert_eq!(kept_open_orders, expected);
+        assert_eq!(kept[num_kept..], ix.accounts[open_orders.len()..]);
+        // the trimmed accounts are reported in the order of their first event
+        assert_eq!(
+            packed[0].open_orders,
+            vec![open_orders[..num_kept].to_vec()]
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use crate::balance::BalanceMonitor;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signer};
+use std::sync::Arc;
+
+/// rotates the fee payer of crank transactions round robin, spreading
+/// fees across the payers and skipping payers whose balance is critical
+pub struct PayerRotation {
+    payers: Vec<Arc<Keypair>>,
+    /// index of the payer to try first for the next transaction
+    next: usize,
+}
+
+impl PayerRotation {
+    /// payers must not be empty
+    pub fn new(payers: Vec<Keypair>) -> Self {
+        assert!(!payers.is_empty(), "at least one payer is required");
+        Self {
+            payers: payers.into_iter().map(Arc::new).collect(),
+            next: 0,
+        }
+    }
+    /// the first configured payer, which is the default wallet of discovered
+    /// markets and is used when a single payer is needed
+    pub fn primary(&self) -> &Arc<Keypair> {
+        &self.payers[0]
+    }
+    pub fn pubkeys(&self) -> Vec<Pubkey> {
+        self.payers.iter().map(|payer| payer.pubkey()).collect()
+    }
+    /// returns the payer of the next transaction, or none when the
+    /// balance of every payer is critical
+    pub fn next(&mut self, balance_monitor: &BalanceMonitor) -> Option<Arc<Keypair>> {
+        for offset in 0..self.payers.len() {
+            let idx = (self.next + offset) % self.payers.len();
+            let payer = &self.payers[idx];
+            if balance_monitor.is_paused(&payer.pubkey()) {
+                continue;
+            }
+            self.next = (idx + 1) % self.payers.len();
+            return Some(Arc::clone(payer));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PayerBalance;
+    use crate::metrics::Metrics;
+
+    fn monitor() -> BalanceMonitor {
+        BalanceMonitor::new(
+            PayerBalance {
+                warning_balance: 1_000,
+                critical_balance: 100,
+            },
+            Arc::new(Metrics::new().unwrap()),
+        )
+    }
+
+    fn next(rotation: &mut PayerRotation, balance_monitor: &BalanceMonitor) -> Option<Pubkey> {
+        rotation.next(balance_monitor).map(|payer| payer.pubkey())
+    }
+
+    #[test]
+    fn payers_are_rotated_round_robin() {
+        let mut rotation = PayerRotation::new(vec![Keypair::new(), Keypair::new(), Keypair::new()]);
+        let pubkeys = rotation.pubkeys();
+        assert_eq!(rotation.primary().pubkey(), pubkeys[0]);
+        let monitor = monitor();
+        for pubkey in pubkeys.iter().chain(pubkeys.iter()) {
+            assert_eq!(next(&mut rotation, &monitor), Some(*pubkey));
+        }
+    }
+
+    #[test]
+    fn critical_payers_are_skipped() {
+        let mut rotation = PayerRotation::new(vec![Keypair::new(), Keypair::new(), Keypair::new()]);
+        let pubkeys = rotation.pubkeys();
+        let mut monitor = monitor();
+        monitor.update(&pubkeys[1], 0);
+        assert_eq!(next(&mut rotation, &monitor), Some(pubkeys[0]));
+        assert_eq!(next(&mut rotation, &monitor), Some(pubkeys[2]));
+        assert_eq!(next(&mut rotation, &monitor), Some(pubkeys[0]));
+        // the payer is used again once its balance recovers
+        monitor.update(&pubkeys[1], 1_000);
+        assert_eq!(next(&mut rotation, &monitor), Some(pubkeys[1]));
+    }
+
+    #[test]
+    fn no_payer_when_every_payer_is_critical() {
+        let mut rotation = PayerRotation::new(vec![Keypair::new(), Keypair::new()]);
+        let mut monitor = monitor();
+        for pubkey in rotation.pubkeys().iter() {
+            monitor.update(pubkey, 0);
+        }
+        assert_eq!(next(&mut rotation, &monitor), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn payers_are_required() {
+        PayerRotation::new(vec![]);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::config::Configuration;
+use crate::overrides::Overrides;
+use anyhow::Result;
+use crossbeam::select;
+use crossbeam_channel::{Receiver, Sender};
+use log::{error, info};
+use std::fs;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// watches the configuration file for changes by polling its modification time,
+/// reloading it whenever it changes or a reload is requested through `trigger`,
+/// which is signalled on SIGHUP. the overrides are applied to every reloaded
+/// configuration. configurations which fail to load or validate are rejected,
+/// valid configurations are sent to the crank on `reload_chan`
+pub fn watch_config(
+    path: String,
+    overrides: Overrides,
+    poll_interval: Duration,
+    trigger: Receiver<()>,
+    reload_chan: Sender<Configuration>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut last_modified = modified(&path).ok();
+        loop {
+            let mut reload = false;
+            select! {
+                recv(trigger) -> msg => {
+                    if msg.is_err() {
+                        return;
+                    }
+                    info!("reload requested for configuration file {}", path);
+                    reload = true;
+                },
+                default(poll_interval) => {},
+            }
+            match modified(&path) {
+                Ok(modified) => {
+                    if last_modified != Some(modified) {
+                        info!("detected change to configuration file {}", path);
+                        last_modified = Some(modified);
+                        reload = true;
+                    }
+                }
+                Err(err) => {
+                    error!("failed to stat configuration file {}: {:#?}", path, err);
+                }
+            }
+            if !reload {
+                continue;
+            }
+            let config = overrides
+                .load(path.as_str())
+                .and_then(|config| config.validate().map(|_| config));
+            match config {
+                Ok(config) => {
+                    if reload_chan.send(config).is_err() {
+                        // the crank is no longer running
+                        return;
+                    }
+                }
+                Err(err) => {
+                    error!(
+                        "rejected invalid configuration file {}, keeping the current configuration: {:#?}",
+                        path, err
+                    );
+                }
+            }
+        }
+    })
+}
+
+/// returns the configuration to run with once the new configuration is reloaded, which
+/// is the current configuration with only the hot reloadable settings taken from the
+/// new configuration, along with whether any setting requiring a restart was changed
+pub fn hot_reload(current: &Configuration, new: Configuration) -> (Configuration, bool) {
+    let mut reloaded = current.clone();
+    reloaded.ws_rpc_url = new.ws_rpc_url.clone();
+    reloaded.payer_balance = new.payer_balance.clone();
+    reloaded.cost_accounting.apportion = new.cost_accounting.apportion;
+    reloaded.alerts = new.alerts.clone();
+    reloaded.crank = new.crank.clone();
+    let restart_required = reloaded != new;
+    (reloaded, restart_required)
+}
+
+fn modified(path: &str) -> Result<SystemTime> {
+    Ok(fs::metadata(path)?.modified()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Apportion;
+
+    #[test]
+    fn hot_settings_are_reloaded() {
+        let current = Configuration::default();
+        let mut new = current.clone();
+        new.ws_rpc_url = "ws://127.0.0.1:8900".to_string();
+        new.crank.num_accounts = 10;
+        new.crank.markets[0].name = "SOL-USDC".to_string();
+        new.cost_accounting.apportion = Apportion::Instructions;
+        new.alerts.max_queue_len = 100;
+        let (reloaded, restart_required) = hot_reload(&current, new.clone());
+        assert_eq!(reloaded, new);
+        assert!(!restart_required);
+    }
+
+    #[test]
+    fn settings_requiring_a_restart_are_kept() {
+        let current = Configuration::default();
+        let mut new = current.clone();
+        new.http_rpc_url = "http://127.0.0.1:8899".to_string();
+        new.log_file = "other.log".to_string();
+        new.debug_log = !current.debug_log;
+        new.cost_accounting.ledger_file = "crank_costs.json".to_string();
+        new.history.database = "crank_history.db".to_string();
+        new.http_listen_addr = Some("127.0.0.1:8080".to_string());
+        new.health_check.min_payer_balance = 1;
+        new.crank.num_accounts = 10;
+        let (reloaded, restart_required) = hot_reload(&current, new);
+        assert!(restart_required);
+        assert_eq!(reloaded.crank.num_accounts, 10);
+        let mut expected = current;
+        expected.crank.num_accounts = 10;
+        assert_eq!(reloaded, expected);
+    }
+}
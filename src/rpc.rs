@@ -0,0 +1,404 @@
+use crate::config::{RpcEndpoint, RpcFailover, RpcRole};
+use crate::metrics::Metrics;
+use log::{info, warn};
+use solana_client::client_error::{ClientError, ClientErrorKind, Result as ClientResult};
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::RpcSendTransactionConfig;
+use solana_client::rpc_request::RpcError;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::signature::Signature;
+use solana_sdk::transaction::Transaction;
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// number of recent request outcomes used to compute the error rate of an endpoint
+const ERROR_WINDOW: usize = 20;
+/// min number of recent outcomes before the error rate of an endpoint is considered
+const MIN_ERROR_SAMPLES: usize = 5;
+/// json-rpc error codes reserved for server errors
+const SERVER_ERROR_CODES: RangeInclusive<i64> = -32099..=-32000;
+
+/// a pool of rpc endpoints, requests go to the first healthy endpoint with the
+/// required role and fail over to the next endpoint when a request errors.
+/// endpoints are unhealthy when their recent error rate is too high, or when
+/// their slot lags behind the highest slot reported by the other endpoints
+pub struct RpcPool {
+    endpoints: Vec<Endpoint>,
+    config: RpcFailover,
+    metrics: Arc<Metrics>,
+}
+
+struct Endpoint {
+    label: String,
+    role: RpcRole,
+    client: RpcClient,
+    state: Mutex<EndpointState>,
+}
+
+struct EndpointState {
+    /// outcome of recent requests, true when the request succeeded
+    outcomes: VecDeque<bool>,
+    lagging: bool,
+    healthy: bool,
+}
+
+impl RpcPool {
+    pub fn new(endpoints: Vec<RpcEndpoint>, config: RpcFailover, metrics: Arc<Metrics>) -> Self {
+        let endpoints: Vec<Endpoint> = endpoints
+            .into_iter()
+            .map(|endpoint| Endpoint {
+                label: endpoint.label(),
+                role: endpoint.role,
+                client: RpcClient::new(endpoint.url),
+                state: Mutex::new(EndpointState {
+                    outcomes: VecDeque::with_capacity(ERROR_WINDOW),
+                    lagging: false,
+                    healthy: true,
+                }),
+            })
+            .collect();
+        for endpoint in endpoints.iter() {
+            metrics
+                .rpc_endpoint_healthy
+                .with_label_values(&[&endpoint.label])
+                .set(1);
+        }
+        Self {
+            endpoints,
+            config,
+            metrics,
+        }
+    }
+    /// starts a thread which checks the slot of every endpoint each health_check_interval,
+    /// the thread stops once the pool is dropped
+    pub fn start_health_checks(pool: &Arc<Self>) -> JoinHandle<()> {
+        let interval = Duration::from_secs(pool.config.health_check_interval);
+        let pool: Weak<Self> = Arc::downgrade(pool);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            match pool.upgrade() {
+                Some(pool) => pool.check_health(),
+                None => return,
+            }
+        })
+    }
+    /// runs the request against the endpoints serving reads, returning
+    /// the first successful response or the error of the last endpoint
+    pub fn read<T>(&self, request: impl Fn(&RpcClient) -> ClientResult<T>) -> ClientResult<T> {
+        let candidates = self.candidates(RpcRole::Read);
+        let mut last_err = None;
+        for endpoint in candidates {
+            match self.request(endpoint, &request) {
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    warn!("rpc request to {} failed: {:#?}", endpoint.label, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+    /// runs a request made up of several rpc calls, such as resolving the keys of
+    /// markets, against the endpoints serving reads like `read`. only rpc errors
+    /// fail over to the next endpoint, any other error is returned right away.
+    /// only transport and server errors count against the health of the endpoint
+    pub fn read_with<T>(
+        &self,
+        request: impl Fn(&RpcClient) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let candidates = self.candidates(RpcRole::Read);
+        let mut last_err = None;
+        for endpoint in candidates {
+            let res = request(&endpoint.client);
+            let rpc_err = match &res {
+                Err(err) => err.downcast_ref::<ClientError>(),
+                Ok(_) => None,
+            };
+            let rpc_failed = rpc_err.is_some();
+            self.record_outcome(endpoint, !rpc_err.map_or(false, is_endpoint_failure));
+            match res {
+                Err(err) if rpc_failed => {
+                    warn!("rpc request to {} failed: {:#?}", endpoint.label, err);
+                    last_err = Some(err);
+                }
+                res => return res,
+            }
+        }
+        Err(last_err.unwrap())
+    }
+    /// sends the transaction through the endpoints serving sends. when fan_out_sends
+    /// is enabled the transaction is sent to every healthy endpoint, otherwise it is
+    /// sent to the first endpoint which accepts it
+    pub fn send_transaction(
+        &self,
+        transaction: &Transaction,
+        config: RpcSendTransactionConfig,
+    ) -> ClientResult<Signature> {
+        let candidates = self.candidates(RpcRole::Send);
+        let mut signature = None;
+        let mut last_err = None;
+        for endpoint in candidates {
+            let res = self.request(endpoint, |client| {
+                client.send_transaction_with_config(transaction, config)
+            });
+            match res {
+                Ok(sig) => {
+                    signature = Some(sig);
+                    if !self.config.fan_out_sends {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to send transaction to {}: {:#?}",
+                        endpoint.label, err
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+        match signature {
+            Some(signature) => Ok(signature),
+            None => Err(last_err.unwrap()),
+        }
+    }
+    /// refreshes the slot lag of every endpoint, marking endpoints whose slot is more
+    /// than max_slot_lag behind the highest slot, or which failed to respond, as lagging
+    pub fn check_health(&self) {
+        let slots: Vec<Option<u64>> = self
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                self.request(endpoint, |client| {
+                    client.get_slot_with_commitment(CommitmentConfig::processed())
+                })
+                .ok()
+            })
+            .collect();
+        let max_slot = slots.iter().filter_map(|slot| *slot).max().unwrap_or(0);
+        for (endpoint, slot) in self.endpoints.iter().zip(slots.into_iter()) {
+            let mut state = endpoint.state.lock().unwrap();
+            state.lagging = match slot {
+                Some(slot) => max_slot.saturating_sub(slot) > self.config.max_slot_lag,
+                None => true,
+            };
+            self.update_health(endpoint, &mut state);
+        }
+    }
+    /// returns the endpoints with the role, healthy endpoints first
+    /// so unhealthy endpoints are only used as a last resort
+    fn candidates(&self, role: RpcRole) -> Vec<&Endpoint> {
+        let (mut healthy, unhealthy): (Vec<&Endpoint>, Vec<&Endpoint>) = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.role == role || endpoint.role == RpcRole::Both)
+            .partition(|endpoint| endpoint.state.lock().unwrap().healthy);
+        healthy.extend(unhealthy);
+        healthy
+    }
+    /// runs the request against the endpoint, recording its outcome
+    fn request<T>(
+        &self,
+        endpoint: &Endpoint,
+        request: impl Fn(&RpcClient) -> ClientResult<T>,
+    ) -> ClientResult<T> {
+        let res = request(&endpoint.client);
+        self.record_outcome(
+            endpoint,
+            !res.as_ref().err().map_or(false, is_endpoint_failure),
+        );
+        res
+    }
+    fn record_outcome(&self, endpoint: &Endpoint, ok: bool) {
+        let mut state = endpoint.state.lock().unwrap();
+        if state.outcomes.len() >= ERROR_WINDOW {
+            state.outcomes.pop_front();
+        }
+        state.outcomes.push_back(ok);
+        if !ok {
+            self.metrics
+                .rpc_endpoint_errors
+                .with_label_values(&[&endpoint.label])
+                .inc();
+        }
+        self.update_health(endpoint, &mut state);
+    }
+    fn update_health(&self, endpoint: &Endpoint, state: &mut EndpointState) {
+        let num_errors = state.outcomes.iter().filter(|ok| !**ok).count();
+        let error_rate = num_errors as f64 / state.outcomes.len().max(1) as f64;
+        let erroring =
+            state.outcomes.len() >= MIN_ERROR_SAMPLES && error_rate > self.config.max_error_rate;
+        let healthy = !erroring && !state.lagging;
+        if healthy != state.healthy {
+            if healthy {
+                info!("rpc endpoint {} is healthy again", endpoint.label);
+            } else {
+                warn!(
+                    "rpc endpoint {} is unhealthy, error rate {:.2}, lagging {}",
+                    endpoint.label, error_rate, state.lagging
+                );
+            }
+            state.healthy = healthy;
+            self.metrics
+                .rpc_endpoint_healthy
+                .with_label_values(&[&endpoint.label])
+                .set(healthy as i64);
+        }
+    }
+}
+
+/// whether the error is caused by the endpoint, failing to respond or answering
+/// with a server error, rather than by the request itself, such as invalid
+/// params or a market account which doesn't exist
+fn is_endpoint_failure(err: &ClientError) -> bool {
+    match err.kind() {
+        ClientErrorKind::Io(_) | ClientErrorKind::Reqwest(_) => true,
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => {
+            SERVER_ERROR_CODES.contains(code)
+        }
+        _ => false,
+    }
+}
+
+/// a mock rpc endpoint shared by the tests of the modules sending rpc requests
+#[cfg(test)]
+pub mod mock {
+    use serde_json::{json, Value};
+    use std::io::Read;
+    use tiny_http::{Response, Server};
+
+    /// starts an rpc endpoint answering every request with the result of `handler`
+    /// for its method and params, returning its url
+    pub fn mock_endpoint<F>(handler: F) -> String
+    where
+        F: Fn(&str, &Value) -> Value + Send + 'static,
+    {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr());
+        std::thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let request_json: Value = serde_json::from_str(&body).unwrap();
+                let result = match request_json["method"].as_str().unwrap() {
+                    // the client checks the node version before sending a commitment
+                    "getVersion" => json!({"solana-core": "1.7.10", "feature-set": 0}),
+                    method => handler(method, &request_json["params"]),
+                };
+                let response =
+                    json!({"jsonrpc": "2.0", "result": result, "id": request_json["id"]});
+                let _ = request.respond(Response::from_string(response.to_string()));
+            }
+        });
+        url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::mock_endpoint;
+    use anyhow::anyhow;
+    use serde_json::json;
+    use solana_client::rpc_request::RpcResponseErrorData;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// nothing listens on this port, so requests fail right away
+    const UNREACHABLE_URL: &str = "http://127.0.0.1:1";
+
+    /// starts an rpc endpoint answering every request with the slot, returning its url
+    fn slot_endpoint(slot: u64) -> String {
+        mock_endpoint(move |_, _| json!(slot))
+    }
+
+    fn pool(urls: &[&str]) -> RpcPool {
+        let endpoints = urls
+            .iter()
+            .enumerate()
+            .map(|(idx, url)| RpcEndpoint {
+                url: url.to_string(),
+                name: Some(format!("endpoint-{}", idx)),
+                role: RpcRole::Both,
+            })
+            .collect();
+        RpcPool::new(
+            endpoints,
+            RpcFailover::default(),
+            Arc::new(Metrics::new().unwrap()),
+        )
+    }
+
+    #[test]
+    fn failing_primary_falls_over_to_secondary() {
+        let secondary = slot_endpoint(42);
+        let pool = pool(&[UNREACHABLE_URL, &secondary]);
+        assert_eq!(pool.read(|client| client.get_slot()).unwrap(), 42);
+        assert_eq!(pool.read_with(|client| Ok(client.get_slot()?)).unwrap(), 42);
+    }
+
+    #[test]
+    fn failing_primary_becomes_unhealthy() {
+        let secondary = slot_endpoint(42);
+        let pool = pool(&[UNREACHABLE_URL, &secondary]);
+        assert_eq!(pool.candidates(RpcRole::Read)[0].label, "endpoint-0");
+        for _ in 0..MIN_ERROR_SAMPLES {
+            pool.read(|client| client.get_slot()).unwrap();
+        }
+        // reads now go to the secondary first
+        assert_eq!(pool.candidates(RpcRole::Read)[0].label, "endpoint-1");
+        assert!(!pool.endpoints[0].state.lock().unwrap().healthy);
+    }
+
+    #[test]
+    fn other_errors_do_not_fail_over() {
+        let pool = pool(&[&slot_endpoint(42), &slot_endpoint(43)]);
+        let requests = AtomicUsize::new(0);
+        let res: anyhow::Result<()> = pool.read_with(|_client| {
+            requests.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow!("market own address mismatch"))
+        });
+        assert!(res.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn only_endpoint_failures_count_against_its_health() {
+        let pool = pool(&[&slot_endpoint(42)]);
+        let response_error = |code| {
+            move |_client: &RpcClient| -> anyhow::Result<()> {
+                Err(ClientError::from(RpcError::RpcResponseError {
+                    code,
+                    message: "error".to_string(),
+                    data: RpcResponseErrorData::Empty,
+                })
+                .into())
+            }
+        };
+        // invalid params are caused by the request
+        assert!(pool.read_with(response_error(-32602)).is_err());
+        // a node which is behind is not
+        assert!(pool.read_with(response_error(-32005)).is_err());
+        let outcomes: Vec<bool> = pool.endpoints[0]
+            .state
+            .lock()
+            .unwrap()
+            .outcomes
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(outcomes, vec![true, false]);
+        assert!(!is_endpoint_failure(&ClientError::from(
+            ClientErrorKind::Custom("market not found".to_string())
+        )));
+    }
+
+    #[test]
+    fn every_endpoint_failing_returns_the_last_error() {
+        let pool = pool(&[UNREACHABLE_URL, UNREACHABLE_URL]);
+        assert!(pool.read(|client| client.get_slot()).is_err());
+        assert!(pool.read_with(|client| Ok(client.get_slot()?)).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use crate::config::{Crank, ParsedMarketKeys};
+use crate::subscriber::Woken;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime};
+
+/// decides which markets are checked on each crank run, so that every
+/// market is polled at its own interval and isn't cranked more often
+/// than its minimum crank interval allows
+#[derive(Default)]
+pub struct Schedule {
+    /// when each market's queues were last fetched
+    last_checked: HashMap<Pubkey, Instant>,
+    /// when each market was last included in a confirmed crank transaction
+    last_cranked: HashMap<Pubkey, Instant>,
+    /// markets that were due but skipped because of their minimum crank interval
+    deferred: HashSet<Pubkey>,
+}
+
+impl Schedule {
+    /// returns the markets due for a check, which are markets whose polling
+    /// interval has elapsed or whose event queue was reported to have events
+    pub fn due_markets<'a>(
+        &mut self,
+        crank: &Crank,
+        market_keys: &'a [ParsedMarketKeys],
+        woken: &Woken,
+    ) -> Vec<&'a ParsedMarketKeys> {
+        let now = Instant::now();
+        let mut due = Vec::with_capacity(market_keys.len());
+        for market_key in market_keys.iter() {
+            let market = market_key.keys.market;
+            let requested = woken.contains(&market)
+                || self.deferred.contains(&market)
+                || match self.last_checked.get(&market) {
+                    Some(last_checked) => {
+                        now.duration_since(*last_checked) >= market_key.poll_interval(crank)
+                    }
+                    None => true,
+                };
+            if !requested {
+                continue;
+            }
+            if let Some(last_cranked) = self.last_cranked.get(&market) {
+                if now.duration_since(*last_cranked) < market_key.min_crank_interval(crank) {
+                    self.deferred.insert(market);
+                    continue;
+                }
+            }
+            self.deferred.remove(&market);
+            self.last_checked.insert(market, now);
+            due.push(market_key);
+        }
+        due
+    }
+    /// records that the market was included in a confirmed crank transaction
+    pub fn record_crank(&mut self, market: Pubkey) {
+        self.last_cranked.insert(market, Instant::now());
+    }
+    /// records a crank confirmed at the given time, before the crank was restarted
+    pub fn restore_crank(&mut self, market: Pubkey, cranked_at: SystemTime) {
+        let elapsed = cranked_at.elapsed().unwrap_or_default();
+        if let Some(cranked_at) = Instant::now().checked_sub(elapsed) {
+            self.last_cranked.insert(market, cranked_at);
+        }
+    }
+    /// returns how long until the next market is due a check, bounded
+    /// by max_wait_for_events_delay so the crank loop keeps iterating
+    pub fn next_delay(&self, crank: &Crank, market_keys: &[ParsedMarketKeys]) -> Duration {
+        let now = Instant::now();
+        let mut next = now + Duration::from_secs(crank.max_wait_for_events_delay);
+        for market_key in market_keys.iter() {
+            let market = market_key.keys.market;
+            let next_check = match self.last_checked.get(&market) {
+                Some(last_checked) => *last_checked + market_key.poll_interval(crank),
+                None => now,
+            };
+            next = next.min(next_check);
+            if self.deferred.contains(&market) {
+                if let Some(last_cranked) = self.last_cranked.get(&market) {
+                    next = next.min(*last_cranked + market_key.min_crank_interval(crank));
+                }
+            }
+        }
+        next.saturating_duration_since(now)
+    }
+    /// forgets markets which are no longer cranked
+    pub fn retain(&mut self, market_keys: &[ParsedMarketKeys]) {
+        let markets: HashSet<Pubkey> = market_keys
+            .iter()
+            .map(|market_key| market_key.keys.market)
+            .collect();
+        self.last_checked
+            .retain(|market, _| markets.contains(market));
+        self.last_cranked
+            .retain(|market, _| markets.contains(market));
+        self.deferred.retain(|market| markets.contains(market));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crank::MarketPubkeys;
+    use crate::subscriber::Wake;
+
+    fn market_keys(max_wait_for_events_delay: Option<u64>) -> ParsedMarketKeys {
+        ParsedMarketKeys {
+            keys: MarketPubkeys {
+                market: Pubkey::new_unique(),
+                ..MarketPubkeys::default()
+            },
+            max_wait_for_events_delay,
+            ..ParsedMarketKeys::default()
+        }
+    }
+
+    fn crank() -> Crank {
+        Crank {
+            max_wait_for_events_delay: 60,
+            min_crank_interval: 0,
+            ..Crank::default()
+        }
+    }
+
+    fn markets(due: &[&ParsedMarketKeys]) -> Vec<Pubkey> {
+        due.iter()
+            .map(|market_key| market_key.keys.market)
+            .collect()
+    }
+
+    #[test]
+    fn markets_are_due_at_their_poll_interval() {
+        let crank = crank();
+        let market_keys = vec![market_keys(None), market_keys(Some(0))];
+        let mut schedule = Schedule::default();
+        let due = schedule.due_markets(&crank, &market_keys, &Woken::default());
+        assert_eq!(due.len(), 2);
+        // only the market polled on every run is due again
+        let due = schedule.due_markets(&crank, &market_keys, &Woken::default());
+        assert_eq!(markets(&due), vec![market_keys[1].keys.market]);
+        assert_eq!(
+            schedule.next_delay(&crank, &market_keys),
+            Duration::from_secs(0)
+        );
+        assert!(schedule.next_delay(&crank, &market_keys[..1]) <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn woken_markets_are_due() {
+        let crank = crank();
+        let market_keys = vec![market_keys(None), market_keys(None)];
+        let mut schedule = Schedule::default();
+        schedule.due_markets(&crank, &market_keys, &Woken::default());
+        let mut woken = Woken::default();
+        woken.insert(Wake::Market(market_keys[0].keys.market));
+        let due = schedule.due_markets(&crank, &market_keys, &woken);
+        assert_eq!(markets(&due), vec![market_keys[0].keys.market]);
+        let mut woken = Woken::default();
+        woken.insert(Wake::All);
+        assert_eq!(schedule.due_markets(&crank, &market_keys, &woken).len(), 2);
+    }
+
+    #[test]
+    fn recently_cranked_markets_are_deferred() {
+        let crank = Crank {
+            min_crank_interval: 30,
+            ..crank()
+        };
+        let market_keys = vec![market_keys(None)];
+        let market = market_keys[0].keys.market;
+        let mut schedule = Schedule::default();
+        assert_eq!(
+            schedule
+                .due_markets(&crank, &market_keys, &Woken::default())
+                .len(),
+            1
+        );
+        schedule.record_crank(market);
+        let mut woken = Woken::default();
+        woken.insert(Wake::Market(market));
+        assert!(schedule
+            .due_markets(&crank, &market_keys, &woken)
+            .is_empty());
+        assert!(schedule.deferred.contains(&market));
+        let delay = schedule.next_delay(&crank, &market_keys);
+        assert!(delay > Duration::from_secs(0) && delay <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn restored_cranks_defer_markets() {
+        let crank = Crank {
+            min_crank_interval: 30,
+            ..crank()
+        };
+        let market_keys = vec![market_keys(None), market_keys(None)];
+        let mut schedule = Schedule::default();
+        schedule.restore_crank(market_keys[0].keys.market, SystemTime::now());
+        schedule.restore_crank(
+            market_keys[1].keys.market,
+            SystemTime::now() - Duration::from_secs(60),
+        );
+        let due = schedule.due_markets(&crank, &market_keys, &Woken::default());
+        assert_eq!(markets(&due), vec![market_keys[1].keys.market]);
+        assert!(schedule.deferred.contains(&market_keys[0].keys.market));
+    }
+
+    #[test]
+    fn removed_markets_are_forgotten() {
+        let crank = crank();
+        let market_keys = vec![market_keys(None), market_keys(None)];
+        let mut schedule = Schedule::default();
+        schedule.due_markets(&crank, &market_keys, &Woken::default());
+        schedule.record_crank(market_keys[0].keys.market);
+        schedule.retain(&market_keys[1..]);
+        assert!(!schedule
+            .last_checked
+            .contains_key(&market_keys[0].keys.market));
+        assert!(!schedule
+            .last_cranked
+            .contains_key(&market_keys[0].keys.market));
+        assert!(schedule
+            .last_checked
+            .contains_key(&market_keys[1].keys.market));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::health::Health;
+use crate::metrics::Metrics;
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use std::sync::Arc;
+use tiny_http::{Header, Response, Server};
+
+/// starts the embedded http server in a background thread, serving prometheus
+/// metrics at /metrics along with the /healthz and /readyz probes
+pub fn start(listen_addr: &str, metrics: Arc<Metrics>, health: Arc<Health>) -> Result<()> {
+    let server = Server::http(listen_addr)
+        .map_err(|err| anyhow!("failed to start http server on {}: {:#?}", listen_addr, err))?;
+    info!("http server listening on {}", listen_addr);
+    serve(server, metrics, health);
+    Ok(())
+}
+
+/// answers the requests to the server in a background thread
+fn serve(server: Server, metrics: Arc<Metrics>, health: Arc<Health>) {
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            // routes are matched on the path, ignoring any query string
+            let path = request.url().split('?').next().unwrap_or_default();
+            let response = match path {
+                "/metrics" => match metrics.encode() {
+                    Ok(body) => Response::from_string(body).with_header(
+                        Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+                            .unwrap(),
+                    ),
+                    Err(err) => {
+                        error!("failed to encode metrics {:#?}", err);
+                        Response::from_string("failed to encode metrics").with_status_code(500)
+                    }
+                },
+                "/healthz" => match health.check() {
+                    Ok(_) => Response::from_string("ok"),
+                    Err(reason) => Response::from_string(reason).with_status_code(503),
+                },
+                "/readyz" => {
+                    if health.is_ready() {
+                        Response::from_string("ok")
+                    } else {
+                        Response::from_string("market keys not resolved").with_status_code(503)
+                    }
+                }
+                _ => Response::from_string("not found").with_status_code(404),
+            };
+            if let Err(err) = request.respond(response) {
+                error!("failed to respond to http request {:#?}", err);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Configuration;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    /// starts a server on a free port, returning its address
+    fn start_server(metrics: Arc<Metrics>, health: Arc<Health>) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_string();
+        serve(server, metrics, health);
+        addr
+    }
+
+    /// returns the status code and body of a GET request to the path
+    fn get(addr: &str, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, addr
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = match response.find("\r\n\r\n") {
+            Some(idx) => response[idx + 4..].to_string(),
+            None => String::new(),
+        };
+        (status, body)
+    }
+
+    #[test]
+    fn metrics_are_served() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        metrics.transactions_sent.inc();
+        let health = Arc::new(Health::new(&Configuration::default()));
+        let addr = start_server(metrics, health);
+        for path in ["/metrics", "/metrics?foo"].iter() {
+            let (status, body) = get(&addr, path);
+            assert_eq!(status, 200);
+            assert!(body.contains("crank_transactions_sent_total 1"), "{}", body);
+        }
+    }
+
+    #[test]
+    fn probes_ignore_query_strings() {
+        let health = Arc::new(Health::new(&Configuration::default()));
+        let addr = start_server(Arc::new(Metrics::new().unwrap()), Arc::clone(&health));
+        assert_eq!(get(&addr, "/healthz?x").0, 200);
+        assert_eq!(get(&addr, "/readyz").0, 503);
+        health.set_ready();
+        assert_eq!(get(&addr, "/readyz?x=1").0, 200);
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let health = Arc::new(Health::new(&Configuration::default()));
+        let addr = start_server(Arc::new(Metrics::new().unwrap()), health);
+        assert_eq!(get(&addr, "/"), (404, "not found".to_string()));
+        assert_eq!(get(&addr, "/metricsz").0, 404);
+    }
+}
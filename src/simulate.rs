@@ -0,0 +1,205 @@
+use crate::metrics::Metrics;
+use crate::rpc::RpcPool;
+use anyhow::Result;
+use log::{error, info};
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::transaction::{Transaction, TransactionError};
+
+/// outcome of simulating the crank instruction of a single market
+#[derive(Clone, Debug)]
+pub struct MarketSimulation {
+    pub market: Pubkey,
+    /// compute units consumed by the market's crank instruction, if it ran
+    pub compute_units: Option<u64>,
+    /// error returned by the dex for the market's crank instruction
+    pub error: Option<InstructionError>,
+}
+
+/// outcome of simulating a crank transaction
+#[derive(Clone, Debug)]
+pub struct SimulationReport {
+    pub err: Option<TransactionError>,
+    pub logs: Vec<String>,
+    pub markets: Vec<MarketSimulation>,
+}
+
+/// simulates the crank transaction instead of sending it, attributing the
+/// compute units consumed and any dex error to the markets it cranks. the
+/// dex instructions of the transaction are expected to be in the same order
+/// as the markets, one instruction per market
+pub fn simulate_crank(
+    rpc: &RpcPool,
+    metrics: &Metrics,
+    dex_program: &Pubkey,
+    transaction: &Transaction,
+    markets: &[Pubkey],
+) -> Result<SimulationReport> {
+    let res = metrics
+        .time_rpc("simulateTransaction", || {
+            rpc.read(|client| client.simulate_transaction(transaction))
+        })?
+        .value;
+    let logs = res.logs.unwrap_or_default();
+    let markets = market_simulations(dex_program, transaction, markets, &logs, res.err.as_ref());
+    Ok(SimulationReport {
+        err: res.err,
+        logs,
+        markets,
+    })
+}
+
+/// attributes the compute units consumed by each dex instruction of the transaction,
+/// and the error of the failed instruction, to the market the instruction cranks
+fn market_simulations(
+    dex_program: &Pubkey,
+    transaction: &Transaction,
+    markets: &[Pubkey],
+    logs: &[String],
+    err: Option<&TransactionError>,
+) -> Vec<MarketSimulation> {
+    let compute_units = compute_units_consumed(logs, dex_program);
+    // map the index of the failed instruction to the index of its market
+    let failed = match err {
+        Some(TransactionError::InstructionError(idx, err)) => {
+            let market_idx = transaction
+                .message
+                .instructions
+                .iter()
+                .take(*idx as usize)
+                .filter(|ix| ix.program_id(&transaction.message.account_keys) == dex_program)
+                .count();
+            Some((market_idx, err.clone()))
+        }
+        _ => None,
+    };
+    markets
+        .iter()
+        .enumerate()
+        .map(|(idx, market)| MarketSimulation {
+            market: *market,
+            compute_units: compute_units.get(idx).copied(),
+            error: match failed.as_ref() {
+                Some((market_idx, err)) if *market_idx == idx => Some(err.clone()),
+                _ => None,
+            },
+        })
+        .collect()
+}
+
+impl SimulationReport {
+    /// logs the outcome of the simulation for each market
+    pub fn log(&self) {
+        for market in self.markets.iter() {
+            match (market.compute_units, market.error.as_ref()) {
+                (_, Some(err)) => error!(
+                    "dry run crank of market {} failed with dex error {:?}",
+                    market.market, err
+                ),
+                (Some(compute_units), None) => info!(
+                    "dry run crank of market {} consumed {} compute units",
+                    market.market, compute_units
+                ),
+                (None, None) => info!("dry run crank of market {} did not run", market.market),
+            }
+        }
+        match self.err.as_ref() {
+            Some(err) => error!(
+                "dry run transaction failed with error {:?}, logs: {:#?}",
+                err, self.logs
+            ),
+            None => info!("dry run transaction succeeded, logs: {:#?}", self.logs),
+        }
+    }
+}
+
+/// returns the compute units consumed by each invocation of the
+/// program, in order, parsed from the transaction logs
+pub fn compute_units_consumed(logs: &[String], program_id: &Pubkey) -> Vec<u64> {
+    let prefix = format!("Program {} consumed ", program_id);
+    logs.iter()
+        .filter_map(|log| log.strip_prefix(&prefix))
+        .filter_map(|units| units.split_whitespace().next())
+        .filter_map(|units| units.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compute_budget::set_compute_unit_limit;
+    use solana_sdk::instruction::Instruction;
+
+    fn logs(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn compute_units_are_parsed_per_invocation() {
+        let (dex_program, other) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let logs = logs(&[
+            &format!("Program {} invoke [1]", dex_program),
+            &format!(
+                "Program {} consumed 1200 of 200000 compute units",
+                dex_program
+            ),
+            &format!("Program {} success", dex_program),
+            &format!("Program {} consumed 500 of 200000 compute units", other),
+            &format!(
+                "Program {} consumed many of 200000 compute units",
+                dex_program
+            ),
+            &format!(
+                "Program {} consumed 3400 of 198800 compute units",
+                dex_program
+            ),
+        ]);
+        assert_eq!(
+            compute_units_consumed(&logs, &dex_program),
+            vec![1200, 3400]
+        );
+        assert!(compute_units_consumed(&[], &dex_program).is_empty());
+    }
+
+    #[test]
+    fn failed_instructions_are_attributed_to_their_market() {
+        let (dex_program, payer) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (market_a, market_b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let crank_ix = Instruction {
+            program_id: dex_program,
+            accounts: vec![],
+            data: vec![],
+        };
+        let transaction = Transaction::new_with_payer(
+            &[set_compute_unit_limit(400_000), crank_ix.clone(), crank_ix],
+            Some(&payer),
+        );
+        let logs = logs(&[&format!(
+            "Program {} consumed 1200 of 400000 compute units",
+            dex_program
+        )]);
+        // the second dex instruction follows the compute budget instruction
+        let err = TransactionError::InstructionError(2, InstructionError::Custom(42));
+        let markets = market_simulations(
+            &dex_program,
+            &transaction,
+            &[market_a, market_b],
+            &logs,
+            Some(&err),
+        );
+        assert_eq!(markets[0].market, market_a);
+        assert_eq!(markets[0].compute_units, Some(1200));
+        assert_eq!(markets[0].error, None);
+        assert_eq!(markets[1].market, market_b);
+        assert_eq!(markets[1].compute_units, None);
+        assert_eq!(markets[1].error, Some(InstructionError::Custom(42)));
+        let markets = market_simulations(
+            &dex_program,
+            &transaction,
+            &[market_a, market_b],
+            &logs,
+            None,
+        );
+        assert!(markets.iter().all(|market| market.error.is_none()));
+    }
+}
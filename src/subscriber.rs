@@ -0,0 +1,519 @@
+use crate::crank::{parse_event_queue, remove_dex_account_padding};
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{select, Receiver, Sender};
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tungstenite::{client::AutoStream, stream::Stream, Message, WebSocket};
+
+/// how long a blocking socket read waits before checking whether
+/// the subscriber has been asked to stop
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// delay before reconnecting after the connection of a successful subscription drops
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// upper bound for the reconnect backoff
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// time to wait before retrying a subscription request which failed
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// subscribes to the event queue of each market over the websocket rpc endpoint
+/// and notifies the crank whenever an event queue holds events that can be consumed
+pub struct EventQueueSubscriber {
+    ws_url: String,
+    /// pairs of (market, event queue)
+    event_queues: Vec<(Pubkey, Pubkey)>,
+    running: Arc<AtomicBool>,
+}
+
+/// wakes up the crank for a market whose event queue holds events, or for every
+/// market once the subscriptions are established, as events may have been missed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wake {
+    Market(Pubkey),
+    All,
+}
+
+/// the markets a crank run was woken up for
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Woken {
+    pub all: bool,
+    pub markets: HashSet<Pubkey>,
+}
+
+/// maps the subscription id of each event queue to its market
+#[derive(Default)]
+struct Subscriptions {
+    /// id of the last subscription request
+    last_request_id: u64,
+    /// maps the request id to the market and event queue so the subscription
+    /// id can be associated with the market once confirmed
+    pending: HashMap<u64, (Pubkey, Pubkey)>,
+    active: HashMap<u64, Pubkey>,
+    /// markets and event queues whose subscription request failed,
+    /// along with the time at which to retry the request
+    retries: Vec<(Instant, (Pubkey, Pubkey))>,
+}
+
+/// stops the subscriber thread when dropped
+pub struct SubscriberHandle {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EventQueueSubscriber {
+    pub fn new(ws_url: String, event_queues: Vec<(Pubkey, Pubkey)>) -> Self {
+        Self {
+            ws_url,
+            event_queues,
+            running: Arc::new(AtomicBool::new(true)),
+        }
+    }
+    /// starts the subscriber in a background thread, waking up the market on
+    /// `wake_chan` every time its event queue count is non-zero. the connection
+    /// is re-established with an exponential backoff whenever it drops
+    pub fn start(self, wake_chan: Sender<Wake>) -> SubscriberHandle {
+        let running = Arc::clone(&self.running);
+        let thread = std::thread::spawn(move || {
+            let mut reconnect_delay = INITIAL_RECONNECT_DELAY;
+            while self.running.load(Ordering::SeqCst) {
+                let mut subscribed = false;
+                match self.run(&wake_chan, &mut subscribed) {
+                    Ok(_) => return,
+                    Err(err) => {
+                        // only back off further while the subscriptions keep failing
+                        if subscribed {
+                            reconnect_delay = INITIAL_RECONNECT_DELAY;
+                        }
+                        error!(
+                            "event queue subscription failed, falling back to polling and reconnecting in {:?}: {:#?}",
+                            reconnect_delay, err
+                        );
+                    }
+                }
+                self.sleep_while_running(reconnect_delay);
+                reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        });
+        SubscriberHandle {
+            running,
+            thread: Some(thread),
+        }
+    }
+    /// connects, subscribes to every event queue and processes notifications until
+    /// the subscriber is stopped (returning Ok) or the connection fails (returning Err).
+    /// `subscribed` is set once the first subscription is confirmed
+    fn run(&self, wake_chan: &Sender<Wake>, subscribed: &mut bool) -> Result<()> {
+        let (mut socket, _response) = tungstenite::connect(self.ws_url.as_str())?;
+        set_read_timeout(&socket, READ_TIMEOUT)?;
+        info!("connected to {}", self.ws_url);
+        let mut subscriptions = Subscriptions::default();
+        for (market, event_q) in self.event_queues.iter() {
+            socket.write_message(Message::Text(subscriptions.subscribe(*market, *event_q)))?;
+        }
+        // events may have arrived while we were disconnected, so
+        // wake the crank once the subscriptions are being established
+        let _ = wake_chan.send(Wake::All);
+        while self.running.load(Ordering::SeqCst) {
+            for request in subscriptions.due_retries(Instant::now()) {
+                socket.write_message(Message::Text(request))?;
+            }
+            let message = match socket.read_message() {
+                Ok(message) => message,
+                Err(tungstenite::Error::Io(err))
+                    if err.kind() == std::io::ErrorKind::WouldBlock
+                        || err.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let text = match message {
+                Message::Text(text) => text,
+                Message::Ping(data) => {
+                    socket.write_message(Message::Pong(data))?;
+                    continue;
+                }
+                Message::Close(frame) => {
+                    return Err(anyhow!("websocket closed by server {:?}", frame));
+                }
+                _ => continue,
+            };
+            let woken = subscriptions.handle(text.as_str())?;
+            *subscribed |= !subscriptions.active.is_empty();
+            if let Some(market) = woken {
+                if wake_chan.send(Wake::Market(market)).is_err() {
+                    // the crank is no longer listening
+                    return Ok(());
+                }
+            }
+        }
+        let _ = socket.close(None);
+        Ok(())
+    }
+    fn sleep_while_running(&self, duration: Duration) {
+        let mut slept = Duration::from_secs(0);
+        while slept < duration && self.running.load(Ordering::SeqCst) {
+            std::thread::sleep(READ_TIMEOUT);
+            slept += READ_TIMEOUT;
+        }
+    }
+}
+
+impl Subscriptions {
+    /// returns the request subscribing to the event queue of the market
+    fn subscribe(&mut self, market: Pubkey, event_q: Pubkey) -> String {
+        self.last_request_id += 1;
+        self.pending.insert(self.last_request_id, (market, event_q));
+        json!({
+            "jsonrpc": "2.0",
+            "id": self.last_request_id,
+            "method": "accountSubscribe",
+            "params": [
+                event_q.to_string(),
+                {"encoding": "base64", "commitment": "processed"},
+            ],
+        })
+        .to_string()
+    }
+    /// returns the requests retrying the failed subscriptions which are due
+    fn due_retries(&mut self, now: Instant) -> Vec<String> {
+        let (due, later): (Vec<_>, Vec<_>) = std::mem::take(&mut self.retries)
+            .into_iter()
+            .partition(|(retry_at, _)| *retry_at <= now);
+        self.retries = later;
+        due.into_iter()
+            .map(|(_, (market, event_q))| {
+                info!("retrying event queue subscription for market {}", market);
+                self.subscribe(market, event_q)
+            })
+            .collect()
+    }
+    /// handles a message received over the websocket, returning the market
+    /// to wake up when it notifies that the market's event queue holds events.
+    /// a failed subscription request is retried after RESUBSCRIBE_DELAY, leaving
+    /// the subscriptions of the other markets in place
+    fn handle(&mut self, text: &str) -> Result<Option<Pubkey>> {
+        let msg: Value = serde_json::from_str(text)?;
+        // subscription confirmation
+        if let (Some(id), Some(sub_id)) = (msg["id"].as_u64(), msg["result"].as_u64()) {
+            if let Some((market, _event_q)) = self.pending.remove(&id) {
+                debug!("subscribed to event queue for market {}", market);
+                self.active.insert(sub_id, market);
+            }
+            return Ok(None);
+        }
+        if let Some(err) = msg.get("error") {
+            match msg["id"].as_u64().and_then(|id| self.pending.remove(&id)) {
+                Some((market, event_q)) => {
+                    error!(
+                        "event queue subscription for market {} failed, retrying in {:?}: {}",
+                        market, RESUBSCRIBE_DELAY, err
+                    );
+                    self.retries
+                        .push((Instant::now() + RESUBSCRIBE_DELAY, (market, event_q)));
+                }
+                None => warn!("websocket request failed {}", err),
+            }
+            return Ok(None);
+        }
+        if msg["method"].as_str() != Some("accountNotification") {
+            return Ok(None);
+        }
+        let market = match msg["params"]["subscription"]
+            .as_u64()
+            .and_then(|sub_id| self.active.get(&sub_id))
+        {
+            Some(market) => *market,
+            None => return Ok(None),
+        };
+        match event_queue_count(&msg["params"]["result"]["value"]["data"]) {
+            Ok(count) if count > 0 => {
+                debug!("event queue for market {} has {} events", market, count);
+                Ok(Some(market))
+            }
+            Ok(_) => Ok(None),
+            Err(err) => {
+                warn!(
+                    "failed to decode event queue notification for market {}: {:#?}",
+                    market, err
+                );
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Woken {
+    pub fn insert(&mut self, wake: Wake) {
+        match wake {
+            Wake::Market(market) => {
+                self.markets.insert(market);
+            }
+            Wake::All => self.all = true,
+        }
+    }
+    pub fn contains(&self, market: &Pubkey) -> bool {
+        self.all || self.markets.contains(market)
+    }
+}
+
+impl SubscriberHandle {
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for SubscriberHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// collects the wake ups received within the debounce window, so a burst of
+/// notifications results in a single crank run. returns None on exit
+pub fn debounce(
+    mut woken: Woken,
+    wake_chan: &Receiver<Wake>,
+    exit_chan: &Receiver<bool>,
+    window: Duration,
+) -> Option<Woken> {
+    let deadline = Instant::now() + window;
+    loop {
+        select! {
+            recv(exit_chan) -> _msg => return None,
+            recv(wake_chan) -> msg => match msg {
+                Ok(wake) => woken.insert(wake),
+                Err(_) => return Some(woken),
+            },
+            default(deadline.saturating_duration_since(Instant::now())) => return Some(woken),
+        }
+    }
+}
+
+/// decodes the base64 encoded account data of an account notification,
+/// returning the number of events held in the event queue
+fn event_queue_count(data: &Value) -> Result<u64> {
+    let encoded = match data[0].as_str() {
+        Some(encoded) => encoded,
+        None => return Err(anyhow!("notification is missing account data")),
+    };
+    let data = base64::decode(encoded)?;
+    let inner: Cow<[u64]> = remove_dex_account_padding(&data)?;
+    let (_header, seg0, seg1) = parse_event_queue(&inner)?;
+    Ok((seg0.len() + seg1.len()) as u64)
+}
+
+fn set_read_timeout(socket: &WebSocket<AutoStream>, timeout: Duration) -> Result<()> {
+    let stream: &TcpStream = match socket.get_ref() {
+        Stream::Plain(stream) => stream,
+        Stream::Tls(stream) => stream.get_ref(),
+    };
+    stream.set_read_timeout(Some(timeout))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serum_dex::state::{Event, ACCOUNT_HEAD_PADDING, ACCOUNT_TAIL_PADDING};
+    use std::mem::size_of;
+    use std::net::TcpListener;
+
+    /// base64 encodes an event queue account holding count events
+    fn event_queue_data(count: u64) -> String {
+        let mut data = ACCOUNT_HEAD_PADDING.to_vec();
+        // account flags, head, count and seq_num
+        for word in [0, 0, count, count].iter() {
+            data.extend_from_slice(&u64::to_le_bytes(*word));
+        }
+        data.extend(vec![0; 4 * size_of::<Event>()]);
+        data.extend_from_slice(ACCOUNT_TAIL_PADDING);
+        base64::encode(data)
+    }
+
+    fn notification(sub_id: u64, count: u64) -> String {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "accountNotification",
+            "params": {
+                "subscription": sub_id,
+                "result": {
+                    "context": {"slot": 1},
+                    "value": {"data": [event_queue_data(count), "base64"]},
+                },
+            },
+        })
+        .to_string()
+    }
+
+    fn confirmation(request_id: u64, sub_id: u64) -> String {
+        json!({"jsonrpc": "2.0", "id": request_id, "result": sub_id}).to_string()
+    }
+
+    /// subscribes to the event queues of the markets, whose requests have ids 1, 2, ...
+    fn subscriptions(markets: &[Pubkey]) -> Subscriptions {
+        let mut subscriptions = Subscriptions::default();
+        for market in markets.iter() {
+            subscriptions.subscribe(*market, Pubkey::new_unique());
+        }
+        subscriptions
+    }
+
+    #[test]
+    fn notifications_wake_markets_with_events() {
+        let (market_a, market_b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut subscriptions = subscriptions(&[market_a, market_b]);
+        assert_eq!(subscriptions.handle(&confirmation(1, 10)).unwrap(), None);
+        assert_eq!(subscriptions.handle(&confirmation(2, 20)).unwrap(), None);
+        assert_eq!(
+            subscriptions.handle(&notification(10, 3)).unwrap(),
+            Some(market_a)
+        );
+        assert_eq!(
+            subscriptions.handle(&notification(20, 1)).unwrap(),
+            Some(market_b)
+        );
+        // empty event queues and unknown subscriptions don't wake anything
+        assert_eq!(subscriptions.handle(&notification(10, 0)).unwrap(), None);
+        assert_eq!(subscriptions.handle(&notification(30, 3)).unwrap(), None);
+    }
+
+    #[test]
+    fn failed_subscriptions_are_retried() {
+        let (market_a, market_b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut subscriptions = subscriptions(&[market_a, market_b]);
+        let event_q_b = subscriptions.pending[&2].1;
+        subscriptions.handle(&confirmation(1, 10)).unwrap();
+        let err =
+            json!({"jsonrpc": "2.0", "id": 2, "error": {"code": -32602, "message": "invalid"}});
+        assert_eq!(subscriptions.handle(&err.to_string()).unwrap(), None);
+        // the other subscriptions keep waking their markets
+        assert_eq!(
+            subscriptions.handle(&notification(10, 1)).unwrap(),
+            Some(market_a)
+        );
+        assert!(subscriptions.due_retries(Instant::now()).is_empty());
+        let retries = subscriptions.due_retries(Instant::now() + RESUBSCRIBE_DELAY);
+        assert_eq!(retries.len(), 1);
+        let request: Value = serde_json::from_str(&retries[0]).unwrap();
+        assert_eq!(request["id"], 3);
+        assert_eq!(request["params"][0], event_q_b.to_string());
+        assert!(subscriptions
+            .due_retries(Instant::now() + RESUBSCRIBE_DELAY)
+            .is_empty());
+        subscriptions.handle(&confirmation(3, 20)).unwrap();
+        assert_eq!(
+            subscriptions.handle(&notification(20, 1)).unwrap(),
+            Some(market_b)
+        );
+    }
+
+    #[test]
+    fn notifications_are_debounced() {
+        let (market_a, market_b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut subscriptions = subscriptions(&[market_a, market_b]);
+        subscriptions.handle(&confirmation(1, 10)).unwrap();
+        subscriptions.handle(&confirmation(2, 20)).unwrap();
+        let (wake_s, wake_r) = crossbeam_channel::unbounded();
+        let (_exit_s, exit_r) = crossbeam_channel::unbounded();
+        for (sub_id, count) in [(10, 1), (10, 2), (20, 0), (10, 3)].iter() {
+            if let Some(market) = subscriptions
+                .handle(&notification(*sub_id, *count))
+                .unwrap()
+            {
+                wake_s.send(Wake::Market(market)).unwrap();
+            }
+        }
+        let woken = debounce(
+            Woken::default(),
+            &wake_r,
+            &exit_r,
+            Duration::from_millis(10),
+        )
+        .unwrap();
+        assert!(!woken.all);
+        assert_eq!(woken.markets.len(), 1);
+        assert!(woken.contains(&market_a));
+        assert!(!woken.contains(&market_b));
+        // a wake up for every market after subscribing
+        wake_s.send(Wake::All).unwrap();
+        let woken = debounce(
+            Woken::default(),
+            &wake_r,
+            &exit_r,
+            Duration::from_millis(10),
+        )
+        .unwrap();
+        assert!(woken.contains(&market_a) && woken.contains(&market_b));
+    }
+
+    #[test]
+    fn debounce_stops_on_exit() {
+        let (_wake_s, wake_r) = crossbeam_channel::unbounded::<Wake>();
+        let (exit_s, exit_r) = crossbeam_channel::unbounded();
+        exit_s.send(true).unwrap();
+        assert_eq!(
+            debounce(Woken::default(), &wake_r, &exit_r, Duration::from_secs(5)),
+            None
+        );
+    }
+
+    #[test]
+    fn subscriber_wakes_markets_and_reconnects() {
+        let (market, event_q) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ws_url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = vec![];
+            // the server closes the first connection after a notification
+            for sub_id in [10_u64, 20].iter() {
+                let (stream, _addr) = listener.accept().unwrap();
+                let mut socket = tungstenite::accept(stream).unwrap();
+                let request: Value = match socket.read_message().unwrap() {
+                    Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                    message => panic!("unexpected message {:?}", message),
+                };
+                let request_id = request["id"].as_u64().unwrap();
+                requests.push(request);
+                socket
+                    .write_message(Message::Text(confirmation(request_id, *sub_id)))
+                    .unwrap();
+                socket
+                    .write_message(Message::Text(notification(*sub_id, 2)))
+                    .unwrap();
+                socket.close(None).unwrap();
+            }
+            requests
+        });
+        let (wake_s, wake_r) = crossbeam_channel::unbounded();
+        let mut handle = EventQueueSubscriber::new(ws_url, vec![(market, event_q)]).start(wake_s);
+        let timeout = Duration::from_secs(10);
+        let wakes: Vec<Wake> = (0..4)
+            .map(|_| wake_r.recv_timeout(timeout).unwrap())
+            .collect();
+        handle.stop();
+        assert_eq!(
+            wakes,
+            vec![
+                Wake::All,
+                Wake::Market(market),
+                Wake::All,
+                Wake::Market(market)
+            ]
+        );
+        for request in server.join().unwrap() {
+            assert_eq!(request["method"], "accountSubscribe");
+            assert_eq!(request["params"][0], event_q.to_string());
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::{Configuration, Market};
+use crate::crank::{market_keys_from_account_data, MAX_MULTIPLE_ACCOUNTS};
+use anyhow::Result;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::account::Account;
+use solana_sdk::program_pack::Pack;
+use solana_sdk::pubkey::Pubkey;
+use std::str::FromStr;
+
+/// checks the accounts referenced by the configured markets on chain, returning
+/// every problem found. markets must exist and belong to dex_program, and their
+/// wallets must be token accounts of the market's coin and pc mints. markets
+/// with invalid pubkeys are skipped, as Configuration::problems reports them
+pub fn check_accounts(config: &Configuration, rpc: &RpcClient) -> Vec<String> {
+    let mut problems = vec![];
+    let dex_program = match Pubkey::from_str(&config.crank.dex_program) {
+        Ok(dex_program) => dex_program,
+        Err(_) => return problems,
+    };
+    match get_accounts(rpc, &[dex_program]) {
+        Ok(accounts) => match accounts[0].as_ref() {
+            Some(account) if account.executable => {}
+            Some(_) => problems.push(format!("dex_program {} is not a program", dex_program)),
+            None => problems.push(format!("dex_program {} does not exist", dex_program)),
+        },
+        Err(err) => {
+            problems.push(format!(
+                "failed to fetch dex_program {}: {}",
+                dex_program, err
+            ));
+            return problems;
+        }
+    }
+    let markets: Vec<(&Market, Pubkey)> = config
+        .crank
+        .markets
+        .iter()
+        .filter_map(|market| {
+            Pubkey::from_str(&market.market_account)
+                .ok()
+                .map(|market_account| (market, market_account))
+        })
+        .collect();
+    let market_keys: Vec<Pubkey> = markets.iter().map(|(_, key)| *key).collect();
+    let market_accounts = match get_accounts(rpc, &market_keys) {
+        Ok(accounts) => accounts,
+        Err(err) => {
+            problems.push(format!("failed to fetch market accounts: {}", err));
+            return problems;
+        }
+    };
+    // the wallets of each market along with the mint they must hold
+    let mut wallets: Vec<(&Market, &'static str, Pubkey, Pubkey)> = vec![];
+    for ((market, market_account), account) in markets.iter().zip(market_accounts.iter()) {
+        let account = match account {
+            Some(account) => account,
+            None => {
+                problems.push(format!(
+                    "market {} account {} does not exist",
+                    market.name, market_account
+                ));
+                continue;
+            }
+        };
+        if account.owner != dex_program {
+            problems.push(format!(
+                "market {} account {} is owned by {}, not dex_program {}",
+                market.name, market_account, account.owner, dex_program
+            ));
+            continue;
+        }
+        let keys = market_keys_from_account_data(&dex_program, market_account, &account.data);
+        let keys = match keys {
+            Ok(keys) => keys,
+            Err(err) => {
+                problems.push(format!(
+                    "market {} account {} is not a valid market: {}",
+                    market.name, market_account, err
+                ));
+                continue;
+            }
+        };
+        if let Ok(coin_wallet) = Pubkey::from_str(&market.coin_wallet) {
+            wallets.push((*market, "coin_wallet", coin_wallet, keys.coin_mint));
+        }
+        if let Ok(pc_wallet) = Pubkey::from_str(&market.pc_wallet) {
+            wallets.push((*market, "pc_wallet", pc_wallet, keys.pc_mint));
+        }
+    }
+    let wallet_keys: Vec<Pubkey> = wallets.iter().map(|(_, _, wallet, _)| *wallet).collect();
+    let wallet_accounts = match get_accounts(rpc, &wallet_keys) {
+        Ok(accounts) => accounts,
+        Err(err) => {
+            problems.push(format!("failed to fetch wallet accounts: {}", err));
+            return problems;
+        }
+    };
+    for ((market, field, wallet, mint), account) in wallets.iter().zip(wallet_accounts.iter()) {
+        if let Err(problem) = check_wallet(account.as_ref(), mint) {
+            problems.push(format!(
+                "market {} {} {} {}",
+                market.name, field, wallet, problem
+            ));
+        }
+    }
+    problems
+}
+
+/// checks that the wallet is a token account of the mint
+fn check_wallet(account: Option<&Account>, mint: &Pubkey) -> Result<(), String> {
+    let account = account.ok_or_else(|| "does not exist".to_string())?;
+    if account.owner != spl_token::id() {
+        return Err(format!(
+            "is owned by {}, not the token program",
+            account.owner
+        ));
+    }
+    let token_account = spl_token::state::Account::unpack(&account.data)
+        .map_err(|err| format!("is not a token account: {}", err))?;
+    if token_account.mint != *mint {
+        return Err(format!(
+            "holds mint {}, not the market's mint {}",
+            token_account.mint, mint
+        ));
+    }
+    Ok(())
+}
+
+/// fetches the accounts, MAX_MULTIPLE_ACCOUNTS per request
+fn get_accounts(rpc: &RpcClient, keys: &[Pubkey]) -> Result<Vec<Option<Account>>> {
+    let mut accounts = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        accounts.extend(rpc.get_multiple_accounts(chunk)?);
+    }
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spl_token::state::AccountState;
+
+    fn token_account(mint: &Pubkey) -> Account {
+        let token_account = spl_token::state::Account {
+            mint: *mint,
+            owner: Pubkey::new_unique(),
+            state: AccountState::Initialized,
+            ..spl_token::state::Account::default()
+        };
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        spl_token::state::Account::pack(token_account, &mut data).unwrap();
+        Account {
+            lamports: 1,
+            data,
+            owner: spl_token::id(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    #[test]
+    fn wallets_must_be_token_accounts_of_the_mint() {
+        let mint = Pubkey::new_unique();
+        let account = token_account(&mint);
+        assert_eq!(check_wallet(Some(&account), &mint), Ok(()));
+        assert_eq!(check_wallet(None, &mint), Err("does not exist".to_string()));
+        let other_mint = Pubkey::new_unique();
+        assert_eq!(
+            check_wallet(Some(&account), &other_mint),
+            Err(format!(
+                "holds mint {}, not the market's mint {}",
+                mint, other_mint
+            ))
+        );
+        let mut not_token = account.clone();
+        not_token.owner = Pubkey::new_unique();
+        assert!(check_wallet(Some(&not_token), &mint)
+            .unwrap_err()
+            .contains("not the token program"));
+        let mut uninitialized = account;
+        uninitialized.data = vec![0; spl_token::state::Account::LEN];
+        assert!(check_wallet(Some(&uninitialized), &mint)
+            .unwrap_err()
+            .contains("is not a token account"));
+    }
+}
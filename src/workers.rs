@@ -0,0 +1,137 @@
+use crossbeam_channel::{Receiver, Sender};
+use log::error;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+/// a fixed size pool of long lived worker threads, bounding the number
+/// of markets processed, and rpc requests made, concurrently
+pub struct WorkerPool {
+    size: usize,
+    tasks: Option<Sender<Task>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        let (tasks, r): (Sender<Task>, Receiver<Task>) = crossbeam_channel::unbounded();
+        let workers = (0..size)
+            .map(|idx| {
+                let r = r.clone();
+                std::thread::Builder::new()
+                    .name(format!("crank-worker-{}", idx))
+                    .spawn(move || {
+                        for task in r.iter() {
+                            // keep the worker alive when a task panics
+                            if catch_unwind(AssertUnwindSafe(task)).is_err() {
+                                error!("crank worker task panicked");
+                            }
+                        }
+                    })
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+        Self {
+            size,
+            tasks: Some(tasks),
+            workers,
+        }
+    }
+    /// returns the number of worker threads
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    /// runs the function for each item on the pool, blocking until every item has
+    /// been processed. results are returned in the same order as the items, items
+    /// whose task panicked have no result
+    pub fn map<T, R, F>(&self, items: Vec<T>, f: F) -> Vec<R>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+        F: Fn(T) -> R + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let num_items = items.len();
+        let (s, r) = crossbeam_channel::unbounded();
+        for (idx, item) in items.into_iter().enumerate() {
+            let f = Arc::clone(&f);
+            let s = s.clone();
+            self.execute(move || {
+                let _ = s.send((idx, f(item)));
+            });
+        }
+        // the receiver disconnects once every task has finished
+        drop(s);
+        let mut results: Vec<Option<R>> = (0..num_items).map(|_| None).collect();
+        for (idx, res) in r.iter() {
+            results[idx] = Some(res);
+        }
+        results.into_iter().flatten().collect()
+    }
+    fn execute(&self, task: impl FnOnce() + Send + 'static) {
+        if let Some(tasks) = self.tasks.as_ref() {
+            // the workers only exit once the sender is dropped
+            let _ = tasks.send(Box::new(task));
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // disconnect the task channel so the workers exit once the queued tasks are done
+        drop(self.tasks.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn map_keeps_the_order_of_items() {
+        let pool = WorkerPool::new(3);
+        let results = pool.map((0..50).collect(), |item: u64| {
+            // finish out of order
+            std::thread::sleep(Duration::from_millis(50 - item));
+            item * 2
+        });
+        assert_eq!(results, (0..50).map(|item| item * 2).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn map_is_bounded_by_the_pool_size() {
+        let pool = WorkerPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let (task_running, task_max_running) = (Arc::clone(&running), Arc::clone(&max_running));
+        pool.map((0..10).collect(), move |_item: u64| {
+            let now_running = task_running.fetch_add(1, Ordering::SeqCst) + 1;
+            task_max_running.fetch_max(now_running, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10));
+            task_running.fetch_sub(1, Ordering::SeqCst);
+        });
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn panicking_tasks_have_no_result() {
+        let pool = WorkerPool::new(1);
+        let results = pool.map(vec![1, 2, 3], |item: u64| {
+            if item == 2 {
+                panic!("failed to process item {}", item);
+            }
+            item
+        });
+        assert_eq!(results, vec![1, 3]);
+        // the worker survived the panic
+        assert_eq!(pool.map(vec![4], |item: u64| item), vec![4]);
+    }
+}